@@ -0,0 +1,24 @@
+use std::fs;
+use std::path::Path;
+use chipper::vm::asm::assemble;
+use crate::cli::{positional, value, Args};
+
+/// Assembles Octo source into a ROM.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let mut source = None;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(arg, &mut args)?.to_string()),
+            _ => positional(arg, &mut source)?,
+        }
+    }
+
+    let source = source.ok_or("no source file given")?;
+    let output = output.unwrap_or_else(|| Path::new(&source).with_extension("ch8").to_string_lossy().into_owned());
+    let text = fs::read_to_string(&source).map_err(|e| format!("{}: {}", source, e))?;
+    let assembly = assemble(&text).map_err(|e| format!("{}:{}: {}", source, e.line, e.message))?;
+
+    fs::write(&output, &assembly.rom).map_err(|e| format!("{}: {}", output, e))
+}
@@ -0,0 +1,13 @@
+use std::io::{self, BufReader};
+use chipper::vm::dap::DapServer;
+use crate::cli::Args;
+
+/// Serves the Debug Adapter Protocol over stdin and stdout. The ROM and
+/// its options come from the client's launch request.
+pub fn main(mut args: Args) -> Result<(), String> {
+    if let Some(arg) = args.next() {
+        return Err(format!("unexpected argument '{}'", arg));
+    }
+
+    DapServer::new(io::stdout()).serve(BufReader::new(io::stdin())).map_err(|e| format!("dap: {}", e))
+}
@@ -0,0 +1,543 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use chipper::vm::debug::{Access, Action, Condition, Debugger, Stop};
+use chipper::vm::disasm::classic;
+use chipper::vm::error::ChipperError;
+use chipper::vm::io::Frame;
+use crate::cli::keymap::Keymap;
+use crate::cli::term::{self, RawMode};
+use crate::cli::{positional, Args, Common, HOLD};
+
+/// How many slices of execution a written byte stays highlighted for.
+const HEAT: u8 = 30;
+
+/// Bytes per row of the memory pane.
+const ROW: usize = 8;
+
+/// Width of the right hand column with the registers and stack.
+const SIDE: usize = 16;
+
+const HELP: &str = "s [n] step, n next, o out, c continue, u ADDR until, b ADDR [if COND], \
+                    w ADDR[+LEN] [r|w|rw], d ADDR|all, m ADDR|i, k KEY, reset, Tab keypad, q quit";
+
+/// The state of the debugger UI.
+struct Tui {
+    debugger: Debugger,
+
+    /// Host keys for the pad in keypad mode.
+    keymap: Keymap,
+
+    /// Terminal size in columns and rows.
+    cols: usize,
+    rows: usize,
+
+    /// The command being typed.
+    command: String,
+
+    /// The last command run, repeated by an empty Enter.
+    last: String,
+
+    /// Shown above the command line.
+    status: String,
+
+    /// True while the program runs.
+    running: bool,
+
+    /// True while typed keys go to the pad instead of the command line.
+    keypad: bool,
+
+    /// Frames left that each pad key is held down for.
+    held: [usize; 16],
+
+    /// The address at the top of the memory pane, or None to follow I.
+    memory_addr: Option<usize>,
+
+    /// Memory as of the last check for writes.
+    last_memory: Vec<u8>,
+
+    /// How much longer each byte of memory stays highlighted.
+    heat: Vec<u8>,
+
+    /// Bytes left of an escape sequence being skipped.
+    escape: Escape,
+
+    /// Set when the screen needs drawing.
+    dirty: bool,
+
+    /// Set by the quit command.
+    quit: bool,
+}
+
+/// Where input is within an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Started,
+    Sequence,
+}
+
+/// Debugs a ROM in a full-screen terminal UI.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let mut common = Common::default();
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        if !common.parse(arg, &mut args)? {
+            positional(arg, &mut rom)?;
+        }
+    }
+
+    let vm = common.load(&rom.ok_or("no ROM given")?)?;
+    let memory = vm.memory().to_vec();
+    let (cols, rows) = term::size();
+
+    let mut tui = Tui {
+        debugger: Debugger::new(vm),
+        keymap: common.keymap,
+        cols,
+        rows,
+        command: String::new(),
+        last: String::new(),
+        status: HELP.to_string(),
+        running: false,
+        keypad: false,
+        held: [0; 16],
+        memory_addr: None,
+        heat: vec![0; memory.len()],
+        last_memory: memory,
+        escape: Escape::None,
+        dirty: true,
+        quit: false,
+    };
+
+    let raw = RawMode::enter()?;
+    let result = tui.run(term::spawn_stdin());
+
+    drop(raw);
+    result
+}
+
+impl Tui {
+    /// Handles input, runs the program and redraws at 60 Hz until quit.
+    fn run(&mut self, input: Receiver<u8>) -> Result<(), String> {
+        let frame = Duration::from_nanos(1_000_000_000 / 60);
+        let mut next = Instant::now();
+
+        while !self.quit {
+            loop {
+                match input.try_recv() {
+                    Ok(b) => self.input(b),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            let mut keys = [false; 16];
+
+            for (key, held) in keys.iter_mut().zip(self.held.iter_mut()) {
+                *key = *held > 0;
+                *held = held.saturating_sub(1);
+            }
+
+            self.debugger.vm_mut().set_keys(keys);
+
+            if self.running {
+                let limit = self.limit();
+                let result = self.debugger.resume(limit);
+
+                self.stopped(result);
+            }
+
+            if self.dirty || self.running {
+                self.draw().map_err(|e| format!("{}", e))?;
+                self.dirty = false;
+            }
+
+            next += frame;
+
+            match next.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                None => next = Instant::now(),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Instructions run per 60 Hz frame.
+    fn limit(&self) -> u64 {
+        self.debugger.vm().cycles_per_frame() as u64
+    }
+
+    /// Handles a byte typed at the terminal.
+    fn input(&mut self, b: u8) {
+        self.dirty = true;
+
+        // Arrow and function keys send escape sequences, which are skipped.
+        match (self.escape, b) {
+            (Escape::None, 0x1B) => {
+                self.escape = Escape::Started;
+                return;
+            },
+            (Escape::Started, b'[') | (Escape::Started, b'O') => {
+                self.escape = Escape::Sequence;
+                return;
+            },
+            (Escape::Started, _) => {
+                // A lone Esc leaves keypad mode.
+                self.escape = Escape::None;
+                self.keypad = false;
+            },
+            (Escape::Sequence, 0x40..=0x7E) => {
+                self.escape = Escape::None;
+                return;
+            },
+            (Escape::Sequence, _) => return,
+            _ => (),
+        }
+
+        match b {
+            0x03 => self.quit = true,
+            b'\t' => self.keypad = !self.keypad,
+            _ if self.keypad => {
+                if let Some(key) = self.keymap.key(b as char) {
+                    self.held[key as usize] = HOLD;
+                }
+            },
+            b'\r' | b'\n' => {
+                let command = std::mem::take(&mut self.command);
+
+                if command.trim().is_empty() && self.running {
+                    self.running = false;
+                    self.status = format!("paused at {:03X}", self.debugger.vm().pc());
+                } else if command.trim().is_empty() {
+                    let last = self.last.clone();
+
+                    self.execute(&last);
+                } else {
+                    self.execute(&command);
+                    self.last = command;
+                }
+            },
+            0x7F | 0x08 => {
+                self.command.pop();
+            },
+            0x15 => self.command.clear(),
+            0x20..=0x7E => self.command.push(b as char),
+            _ => (),
+        }
+    }
+
+    /// Runs a command typed on the command line.
+    fn execute(&mut self, command: &str) {
+        let words: Vec<&str> = command.split_whitespace().collect();
+
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["s"] | ["step"] => self.step(1),
+            ["s", n] | ["step", n] => match n.parse() {
+                Ok(n) => self.step(n),
+                Err(_) => Err(format!("'{}' is not a number", n)),
+            },
+            ["n"] | ["next"] => self.start(Action::StepOver),
+            ["o"] | ["out"] => self.start(Action::StepOut),
+            ["c"] | ["continue"] => self.start(Action::Continue),
+            ["p"] | ["pause"] => {
+                self.running = false;
+                Ok(())
+            },
+            ["u", addr] | ["until", addr] => parse_addr(addr).and_then(|addr| self.start(Action::RunTo(addr))),
+            ["b", addr] | ["break", addr] => parse_addr(addr).map(|addr| {
+                self.debugger.set_breakpoint(addr, None);
+                self.status = format!("breakpoint at {:03X}", addr);
+            }),
+            ["b", addr, "if", cond @ ..] | ["break", addr, "if", cond @ ..] => {
+                let condition = cond.join(" ").parse::<Condition>().map_err(|e| format!("{}", e));
+
+                parse_addr(addr).and_then(|addr| {
+                    let condition = condition?;
+
+                    self.debugger.set_breakpoint(addr, Some(condition));
+                    self.status = format!("breakpoint at {:03X} if {}", addr, condition);
+                    Ok(())
+                })
+            },
+            ["w", range] | ["watch", range] => self.watch(range, Access::Write),
+            ["w", range, access] | ["watch", range, access] => match *access {
+                "r" => self.watch(range, Access::Read),
+                "w" => self.watch(range, Access::Write),
+                "rw" => self.watch(range, Access::Any),
+                _ => Err(format!("'{}' is not r, w or rw", access)),
+            },
+            ["d", "all"] | ["delete", "all"] => {
+                self.debugger.clear_breakpoints();
+                self.debugger.clear_watchpoints();
+                self.status = "deleted all breakpoints and watchpoints".to_string();
+                Ok(())
+            },
+            ["d", addr] | ["delete", addr] => parse_addr(addr).and_then(|addr| {
+                let bp = self.debugger.remove_breakpoint(addr);
+                let wp = self.debugger.remove_watchpoint(addr);
+
+                if bp || wp {
+                    self.status = format!("deleted {:03X}", addr);
+                    Ok(())
+                } else {
+                    Err(format!("nothing set at {:03X}", addr))
+                }
+            }),
+            ["m", "i"] | ["memory", "i"] => {
+                self.memory_addr = None;
+                Ok(())
+            },
+            ["m", addr] | ["memory", addr] => parse_addr(addr).map(|addr| self.memory_addr = Some(addr)),
+            ["k", key] | ["key", key] => match u8::from_str_radix(key, 16) {
+                Ok(key) if key < 16 => {
+                    self.held[key as usize] = HOLD;
+                    Ok(())
+                },
+                _ => Err(format!("'{}' is not a key (0-F)", key)),
+            },
+            ["reset"] => {
+                self.debugger.vm_mut().reset();
+                self.running = false;
+                self.status = "reset".to_string();
+                Ok(())
+            },
+            ["q"] | ["quit"] => {
+                self.quit = true;
+                Ok(())
+            },
+            ["h"] | ["help"] => {
+                self.status = HELP.to_string();
+                Ok(())
+            },
+            _ => Err(format!("unknown command '{}', type help", command.trim())),
+        };
+
+        if let Err(err) = result {
+            self.status = err;
+        }
+    }
+
+    /// Executes n instructions, stopping early at a breakpoint.
+    fn step(&mut self, n: usize) -> Result<(), String> {
+        for _ in 0..n {
+            let result = self.debugger.step();
+
+            if result != Ok(Stop::Done) {
+                self.stopped(result);
+                return Ok(());
+            }
+        }
+
+        self.stopped(Ok(Stop::Done));
+        Ok(())
+    }
+
+    /// Starts an action, which carries on in the background if it takes
+    /// longer than a frame.
+    fn start(&mut self, action: Action) -> Result<(), String> {
+        let limit = self.limit();
+        let result = self.debugger.run(action, limit);
+
+        self.stopped(result);
+        Ok(())
+    }
+
+    /// Watches memory given as `ADDR` or `ADDR+LEN`.
+    fn watch(&mut self, range: &str, access: Access) -> Result<(), String> {
+        let (start, len) = match range.split_once('+') {
+            Some((addr, len)) => (parse_addr(addr)?, len.parse().map_err(|_| format!("'{}' is not a length", len))?),
+            None => (parse_addr(range)?, 1),
+        };
+
+        self.debugger.add_watchpoint(start..start + len, access);
+        self.status = format!("watching {:03X}-{:03X} for {}", start, start + len - 1, access);
+
+        Ok(())
+    }
+
+    /// Shows why execution stopped, and notes what memory was written.
+    fn stopped(&mut self, result: Result<Stop, ChipperError>) {
+        let pc = self.debugger.vm().pc();
+
+        self.running = result == Ok(Stop::Limit);
+        self.dirty = true;
+
+        self.status = match result {
+            Ok(Stop::Limit) => "running, press Enter to pause".to_string(),
+            Ok(Stop::Done) => format!("stopped at {:03X}", pc),
+            Ok(Stop::Breakpoint(addr)) => format!("breakpoint at {:03X}", addr),
+            Ok(Stop::Watchpoint { pc, addr, access }) => format!("{} of {:03X} at {:03X}", access, addr, pc),
+            Ok(Stop::Exited) => "program exited".to_string(),
+            Err(err) => err.to_string(),
+        };
+
+        let memory = self.debugger.vm().memory();
+
+        for (n, heat) in self.heat.iter_mut().enumerate() {
+            if memory[n] != self.last_memory[n] {
+                *heat = HEAT;
+            } else {
+                *heat = heat.saturating_sub(1);
+            }
+        }
+
+        self.last_memory.copy_from_slice(memory);
+    }
+
+    /// Draws the whole screen.
+    fn draw(&self) -> io::Result<()> {
+        let mut out = String::new();
+        let vm = self.debugger.vm();
+        let frame = vm.frame();
+
+        // Hires video is halved unless the terminal is wide enough.
+        let scale = if frame.width + SIDE + 2 > self.cols { 2 } else { 1 };
+        let video_cols = frame.width / scale;
+        let video_rows = frame.height / scale / 2;
+
+        for row in 0..video_rows {
+            let _ = write!(out, "\x1b[{};1H{}", row + 1, video_row(&frame, row, scale));
+        }
+
+        self.draw_side(&mut out, video_cols + 2, video_rows);
+
+        let top = video_rows + 2;
+        let height = self.rows.saturating_sub(top + 2);
+
+        let _ = write!(out, "\x1b[{};1H\x1b[2m{}\x1b[0m", top, "-".repeat(self.cols.min(video_cols + SIDE + 2)));
+
+        self.draw_code(&mut out, top + 1, height);
+        self.draw_memory(&mut out, top + 1, height);
+
+        let mode = if self.keypad { "[keypad] " } else { "" };
+        let status = truncate(&format!("{}{}", mode, self.status), self.cols);
+
+        let _ = write!(out, "\x1b[{};1H\x1b[7m{:<width$}\x1b[0m", self.rows - 1, status, width = self.cols);
+        let _ = write!(out, "\x1b[{};1H> {}\x1b[K", self.rows, truncate(&self.command, self.cols - 2));
+
+        let mut stdout = io::stdout();
+
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+
+    /// Draws the registers and the stack to the right of the video.
+    fn draw_side(&self, out: &mut String, col: usize, height: usize) {
+        let vm = self.debugger.vm();
+        let regs = vm.registers();
+        let mut lines = Vec::new();
+
+        for n in 0..8 {
+            lines.push(format!("V{:X} {:02X}  V{:X} {:02X}", n, regs.v[n], n + 8, regs.v[n + 8]));
+        }
+
+        lines.push(format!("I  {:03X}", regs.i));
+        lines.push(format!("PC {:03X}  SP {}", vm.pc(), vm.stack().len()));
+        lines.push(format!("DT {:02X}   ST {:02X}", regs.dt, regs.st));
+        lines.push("stack".to_string());
+
+        for ret in vm.stack().iter().rev() {
+            lines.push(format!("  {:03X}", ret));
+        }
+
+        for row in 0..height.max(lines.len().min(self.rows.saturating_sub(2))) {
+            let line = lines.get(row).map_or("", String::as_str);
+
+            let _ = write!(out, "\x1b[{};{}H{:<width$}", row + 1, col + 1, line, width = SIDE);
+        }
+    }
+
+    /// Draws the disassembly around the program counter.
+    fn draw_code(&self, out: &mut String, top: usize, height: usize) {
+        let vm = self.debugger.vm();
+        let pc = vm.pc();
+        let mut addr = pc.saturating_sub(height / 3 * 2);
+
+        for row in 0..height {
+            let (bytes, text, size) = match vm.instruction(addr) {
+                Ok(inst) => {
+                    let bytes: String = vm.memory().iter().skip(addr).take(inst.size()).map(|b| format!("{:02X}", b)).collect();
+
+                    (bytes, classic(inst), inst.size())
+                },
+                Err(_) => (format!("{:04X}", vm.memory().get(addr..addr + 2).map_or(0, |w| (w[0] as u16) << 8 | w[1] as u16)), "?".to_string(), 2),
+            };
+
+            let mark = if self.debugger.breakpoints().contains_key(&addr) { '*' } else { ' ' };
+            let line = truncate(&format!("{}{:03X}  {:<8} {}", mark, addr, bytes, text), 38);
+
+            let _ = if addr == pc {
+                write!(out, "\x1b[{};1H\x1b[7m{:<38}\x1b[0m", top + row, line)
+            } else {
+                write!(out, "\x1b[{};1H{:<38}", top + row, line)
+            };
+
+            addr += size;
+        }
+    }
+
+    /// Draws a hex view of memory, highlighting recent writes and I.
+    fn draw_memory(&self, out: &mut String, top: usize, height: usize) {
+        let vm = self.debugger.vm();
+        let memory = vm.memory();
+        let i = vm.registers().i;
+        let start = self.memory_addr.unwrap_or(i.saturating_sub(ROW)) / ROW * ROW;
+
+        for row in 0..height {
+            let base = start + row * ROW;
+            let _ = write!(out, "\x1b[{};40H", top + row);
+
+            if base >= memory.len() {
+                let _ = write!(out, "\x1b[K");
+                continue;
+            }
+
+            let _ = write!(out, "{:04X} ", base);
+
+            for (addr, byte) in memory.iter().enumerate().skip(base).take(ROW) {
+                let _ = match (addr == i, self.heat[addr] > 0) {
+                    (true, _) => write!(out, " \x1b[7m{:02X}\x1b[0m", byte),
+                    (false, true) => write!(out, " \x1b[1;33m{:02X}\x1b[0m", byte),
+                    (false, false) => write!(out, " {:02X}", byte),
+                };
+            }
+
+            let _ = write!(out, "\x1b[K");
+        }
+    }
+}
+
+/// Draws a row of video as half-block characters, two pixel rows per
+/// character, taking every pixel of a `scale` sized square into account.
+fn video_row(frame: &Frame, row: usize, scale: usize) -> String {
+    let lit = |x: usize, y: usize| {
+        (0..scale).any(|dy| (0..scale).any(|dx| frame.pixel(x * scale + dx, y * scale + dy) != 0))
+    };
+
+    (0..frame.width / scale).map(|x| match (lit(x, row * 2), lit(x, row * 2 + 1)) {
+        (true, true) => '█',
+        (true, false) => '▀',
+        (false, true) => '▄',
+        (false, false) => ' ',
+    }).collect()
+}
+
+/// Cuts text down to a number of characters.
+fn truncate(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
+
+/// Parses an address, which is always hex, with or without `0x`, `#` or
+/// `$` in front.
+fn parse_addr(s: &str) -> Result<usize, String> {
+    let upper = s.to_ascii_uppercase();
+    let hex = upper.strip_prefix("0X").or_else(|| upper.strip_prefix('#')).or_else(|| upper.strip_prefix('$')).unwrap_or(&upper);
+
+    usize::from_str_radix(hex, 16).map_err(|_| format!("'{}' is not an address", s))
+}
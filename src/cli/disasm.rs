@@ -0,0 +1,33 @@
+use chipper::vm::disasm::{Disassembly, Syntax};
+use crate::cli::{positional, value, Args, Common};
+
+/// Prints a listing of a ROM, separating code from data by following
+/// the paths execution can take.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let mut common = Common::default();
+    let mut rom = None;
+    let mut syntax = Syntax::default();
+
+    while let Some(arg) = args.next() {
+        if common.parse(arg, &mut args)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--syntax" => {
+                syntax = match value(arg, &mut args)? {
+                    "classic" => Syntax::Classic,
+                    "octo" => Syntax::Octo,
+                    s => return Err(format!("unknown syntax '{}'", s)),
+                }
+            },
+            _ => positional(arg, &mut rom)?,
+        }
+    }
+
+    let vm = common.load(&rom.ok_or("no ROM given")?)?;
+
+    print!("{}", Disassembly::new(vm.program(), vm.platform()).listing(syntax));
+
+    Ok(())
+}
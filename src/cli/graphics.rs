@@ -0,0 +1,235 @@
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use chipper::vm::io::{Display, Frame, Palette};
+
+/// Characters used by base64, in order of value.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Largest piece of base64 the Kitty protocol takes in one escape code.
+const CHUNK: usize = 4096;
+
+/// Width in pixels that frames are scaled up to when no scale is given,
+/// so hires games come out the same size as lores ones.
+///
+/// Kitty is sent every pixel uncompressed, so at this width each changed
+/// frame is 393 KB of RGB, or 524 KB once base64 encoded. That's fine for
+/// a local terminal, but over a slow link a smaller `--scale` is better.
+/// Sixel is run-length encoded and much smaller.
+const WIDTH: usize = 512;
+
+/// A terminal graphics protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// DEC Sixel, supported by xterm -ti vt340, foot, WezTerm and others.
+    Sixel,
+
+    /// The Kitty graphics protocol, supported by kitty and WezTerm.
+    Kitty,
+}
+
+/// Draws frames in the terminal as images, with every pixel of the
+/// frame drawn as a square of pixels on screen.
+pub struct GraphicsDisplay {
+    /// How images are sent to the terminal.
+    protocol: Protocol,
+
+    /// The colors pixels are drawn in.
+    palette: Palette,
+
+    /// Pixels on screen per pixel of the frame, or None to scale frames
+    /// up to `WIDTH`.
+    scale: Option<usize>,
+
+    /// The image last drawn, which isn't sent again if nothing changed.
+    last: Vec<u8>,
+}
+
+impl GraphicsDisplay {
+    /// Creates a display drawing with a protocol. The terminal should be
+    /// in raw mode, so typed keys aren't echoed over the image.
+    pub fn new(protocol: Protocol, palette: Palette, scale: Option<usize>) -> GraphicsDisplay {
+        GraphicsDisplay { protocol, palette, scale, last: Vec::new() }
+    }
+}
+
+impl Display for GraphicsDisplay {
+    fn present(&mut self, frame: &Frame) {
+        let scale = self.scale.unwrap_or(WIDTH / frame.width).max(1);
+        let rgb = frame.to_rgb(&self.palette, scale);
+
+        if rgb == self.last {
+            return;
+        }
+
+        let (width, height) = (frame.width * scale, frame.height * scale);
+
+        let image = match self.protocol {
+            Protocol::Sixel => sixel(frame, &self.palette, scale),
+            Protocol::Kitty => kitty(&rgb, width, height),
+        };
+
+        let mut stdout = io::stdout();
+
+        let _ = write!(stdout, "\x1b[H{}", image);
+        let _ = stdout.flush();
+
+        self.last = rgb;
+    }
+}
+
+impl Drop for GraphicsDisplay {
+    /// Kitty keeps images on screen until they're deleted.
+    fn drop(&mut self) {
+        if self.protocol == Protocol::Kitty {
+            print!("\x1b_Ga=d,d=I,i=1,q=2\x1b\\");
+            let _ = io::stdout().flush();
+        }
+    }
+}
+
+/// Encodes a frame as Sixel. Sixels are columns of 6 pixels, drawn a
+/// band of 6 rows at a time with one pass over the band for each color.
+fn sixel(frame: &Frame, palette: &Palette, scale: usize) -> String {
+    let (width, height) = (frame.width * scale, frame.height * scale);
+    let mut out = format!("\x1bPq\"1;1;{};{}", width, height);
+
+    // Color registers take each channel as a percentage.
+    for n in 0..4 {
+        let [r, g, b] = palette.color(n);
+        let _ = write!(out, "#{};2;{};{};{}", n, r as usize * 100 / 255, g as usize * 100 / 255, b as usize * 100 / 255);
+    }
+
+    for top in (0..height).step_by(6) {
+        let mut bands = [vec![0u8; width], vec![0u8; width], vec![0u8; width], vec![0u8; width]];
+
+        for (bit, y) in (top..height.min(top + 6)).enumerate() {
+            for x in 0..width {
+                bands[frame.pixel(x / scale, y / scale) as usize][x] |= 1 << bit;
+            }
+        }
+
+        let mut first = true;
+
+        for (color, band) in bands.iter().enumerate() {
+            if band.iter().all(|&bits| bits == 0) {
+                continue;
+            }
+
+            if !first {
+                out.push('$');
+            }
+
+            let _ = write!(out, "#{}", color);
+            first = false;
+
+            // Runs of the same sixel are written once with a count.
+            let mut x = 0;
+
+            while x < width {
+                let bits = band[x];
+                let run = band[x..].iter().take_while(|&&b| b == bits).count();
+                let c = (63 + bits) as char;
+
+                if run > 3 {
+                    let _ = write!(out, "!{}{}", run, c);
+                } else {
+                    out.extend(std::iter::repeat_n(c, run));
+                }
+
+                x += run;
+            }
+        }
+
+        out.push('-');
+    }
+
+    out.push_str("\x1b\\");
+    out
+}
+
+/// Encodes an RGB image for the Kitty graphics protocol, replacing the
+/// image drawn before. The data is base64 sent in chunks.
+fn kitty(rgb: &[u8], width: usize, height: usize) -> String {
+    let data = base64(rgb);
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(CHUNK).collect();
+    let mut out = String::new();
+
+    for (n, chunk) in chunks.iter().enumerate() {
+        let more = if n + 1 < chunks.len() { 1 } else { 0 };
+
+        // Only the first chunk has the keys describing the image.
+        if n == 0 {
+            let _ = write!(out, "\x1b_Ga=T,f=24,s={},v={},i=1,p=1,q=2,C=1,m={};", width, height, more);
+        } else {
+            let _ = write!(out, "\x1b_Gm={};", more);
+        }
+
+        out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+        out.push_str("\x1b\\");
+    }
+
+    out
+}
+
+/// Encodes bytes as base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Many"), "TWFueQ==");
+    }
+
+    #[test]
+    fn kitty_splits_data_into_chunks() {
+        // 3072 bytes is exactly one chunk of base64.
+        let one = kitty(&[0; 3072], 32, 32);
+
+        assert!(one.starts_with("\x1b_Ga=T,f=24,s=32,v=32,i=1,p=1,q=2,C=1,m=0;"));
+        assert_eq!(one.matches("\x1b_G").count(), 1);
+
+        let two = kitty(&[0; 3075], 41, 25);
+        let chunks: Vec<&str> = two.split("\x1b\\").filter(|c| !c.is_empty()).collect();
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].starts_with("\x1b_Ga=T,f=24,s=41,v=25,i=1,p=1,q=2,C=1,m=1;"));
+        assert_eq!(chunks[0].split(';').nth(1).unwrap().len(), CHUNK);
+        assert_eq!(chunks[1], "\x1b_Gm=0;AAAA");
+    }
+
+    #[test]
+    fn sixel_run_length_encodes_each_color() {
+        let planes = [0xF0, 0x0F];
+        let frame = Frame { planes: [&planes, &[0; 2]], pitch: 1, width: 8, height: 2 };
+
+        assert_eq!(sixel(&frame, &Palette::DEFAULT, 2), concat!(
+            "\x1bPq\"1;1;16;4",
+            "#0;2;0;0;0#1;2;100;100;100#2;2;100;100;33#3;2;100;33;33",
+            "#0!8K!8B$#1!8B!8K-",
+            "\x1b\\",
+        ));
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+use chipper::vm::disasm::{classic, Disassembly};
+use chipper::vm::platform::Platform;
+use chipper::vm::state::crc32;
+use crate::cli::{positional, read, Args, Common};
+
+/// Prints the size, hash and detected platform of a ROM, along with how
+/// often each instruction appears in it.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let mut common = Common::default();
+    let mut rom = None;
+
+    while let Some(arg) = args.next() {
+        if !common.parse(arg, &mut args)? {
+            positional(arg, &mut rom)?;
+        }
+    }
+
+    let path = rom.ok_or("no ROM given")?;
+    let program = read(&path)?;
+    let detected = Platform::detect(&program);
+    let vm = common.load(&path)?;
+    let disasm = Disassembly::new(vm.program(), vm.platform());
+    let mut opcodes = BTreeMap::new();
+    let mut code = 0;
+
+    for (_, inst) in disasm.instructions() {
+        let text = classic(inst);
+        let mnemonic = text.split(' ').next().unwrap_or("").to_string();
+
+        *opcodes.entry(mnemonic).or_insert(0) += 1;
+        code += inst.size();
+    }
+
+    println!("file      {}", path);
+    println!("size      {} bytes", program.len());
+    println!("crc32     {:08X}", crc32(&program));
+    println!("detected  {}", detected);
+    println!("platform  {}", vm.platform());
+    println!("fits in   {}", Platform::ALL.iter()
+        .filter(|p| program.len() <= p.max_program_size())
+        .map(|p| p.name())
+        .collect::<Vec<_>>()
+        .join(", "));
+    println!("code      {} bytes", code);
+    println!("data      {} bytes", program.len() - code);
+    println!("opcodes");
+
+    for (mnemonic, count) in opcodes.iter() {
+        println!("    {:<6} {}", mnemonic, count);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Which key on the host keyboard stands for each key of the 16-key pad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keymap {
+    /// The host key for each pad key, 0-F.
+    keys: [char; 16],
+}
+
+/// Returned when a keymap can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidKeymap(pub String);
+
+impl Keymap {
+    /// The usual layout on a QWERTY keyboard, where the left four columns
+    /// of keys 1-4 through Z-V form the pad:
+    ///
+    /// ```text
+    /// 1 2 3 C        1 2 3 4
+    /// 4 5 6 D   =>   Q W E R
+    /// 7 8 9 E        A S D F
+    /// A 0 B F        Z X C V
+    /// ```
+    pub const QWERTY: Keymap = Keymap { keys: ['x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'z', 'c', '4', 'r', 'f', 'v'] };
+
+    /// The same layout on an AZERTY keyboard.
+    pub const AZERTY: Keymap = Keymap { keys: ['x', '1', '2', '3', 'a', 'z', 'e', 'q', 's', 'd', 'w', 'c', '4', 'r', 'f', 'v'] };
+
+    /// Returns the pad key for a host key, if it's mapped.
+    pub fn key(&self, c: char) -> Option<u8> {
+        let c = c.to_ascii_lowercase();
+
+        self.keys.iter().position(|&k| k == c).map(|n| n as u8)
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::QWERTY
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = InvalidKeymap;
+
+    /// Parses "qwerty", "azerty", or 16 different characters giving the
+    /// host key for pad keys 0 through F in order.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "qwerty" => return Ok(Keymap::QWERTY),
+            "azerty" => return Ok(Keymap::AZERTY),
+            _ => (),
+        }
+
+        let chars: Vec<char> = s.chars().map(|c| c.to_ascii_lowercase()).collect();
+        let mut keys = [' '; 16];
+
+        if chars.len() != 16 || chars.iter().enumerate().any(|(n, c)| chars[..n].contains(c)) {
+            return Err(InvalidKeymap(s.to_string()));
+        }
+
+        keys.copy_from_slice(&chars);
+        Ok(Keymap { keys })
+    }
+}
+
+impl fmt::Display for Keymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.keys.iter().try_for_each(|c| write!(f, "{}", c))
+    }
+}
+
+impl fmt::Display for InvalidKeymap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid keymap '{}', expected qwerty, azerty or 16 keys for 0-F", self.0)
+    }
+}
+
+impl std::error::Error for InvalidKeymap {}
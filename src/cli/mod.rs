@@ -0,0 +1,217 @@
+use std::fs;
+use std::path::Path;
+use std::slice::Iter;
+use chipper::vm::asm::assemble;
+use chipper::vm::platform::Platform;
+use chipper::vm::quirks::Quirks;
+use chipper::vm::rng::VipRng;
+use chipper::vm::vm::{load_rom, VM};
+use crate::cli::keymap::Keymap;
+
+pub mod asm;
+pub mod dap;
+pub mod debug;
+pub mod disasm;
+pub mod graphics;
+pub mod info;
+pub mod keymap;
+pub mod run;
+pub mod term;
+pub mod trace;
+
+/// The remaining command line arguments.
+pub type Args<'a> = Iter<'a, String>;
+
+/// How many frames a key typed in the terminal is held down for, since
+/// terminals don't report keys being released.
+pub const HOLD: usize = 6;
+
+pub const USAGE: &str = "\
+usage: chipper <command> [options] <file>
+
+commands:
+    run       run a ROM in the terminal, or headless with --headless
+    disasm    print a listing of a ROM
+    asm       assemble Octo source into a ROM
+    info      print the size, hash, platform and opcodes of a ROM
+    trace     run a ROM, logging every instruction executed
+    debug     debug a ROM in a full-screen terminal UI with video,
+              disassembly, registers, stack and memory panes; type help
+              at its command line for the commands
+    dap       serve the Debug Adapter Protocol on stdin and stdout, for
+              debugging in an editor; the launch request takes program,
+              stopOnEntry, platform, quirks and speed
+
+options for run, debug, disasm, info and trace (files ending in .8o are
+assembled first):
+    --platform <name>         chip-8, hires-chip-8, chip-48, schip, xo-chip
+                              or eti-660 (default: detected from the ROM)
+    --quirks <list>           comma-separated changes to the platform's
+                              quirks: a preset (vip, chip-48, schip-1.0,
+                              schip-1.1, xo-chip), a quirk (shift-vy,
+                              jump-vx, vf-reset, clip, display-wait) to
+                              enable, -quirk to disable, or index=unchanged,
+                              index=x or index=x+1
+    --speed <n>               instructions per second
+    --keymap <map>            qwerty, azerty or the 16 keys for 0-F
+    --seed <n>                seed for CXNN random numbers (default 1, so
+                              runs are the same every time)
+    --rng <xorshift|vip>      the CXNN generator: xorshift (the default) or
+                              the COSMAC VIP interpreter's routine
+
+run options:
+    --headless                run without a display, printing the final
+                              frame and hashes of video and memory on exit
+    --frames <n>              number of frames to run (default: until quit,
+                              or 600 with --headless)
+    --press <frame:key[:n]>   hold a key down for n frames (default 1),
+                              may be given more than once
+    --format <ascii|pbm>      how to print the final frame (default ascii);
+                              with pbm the hashes are printed to stderr
+    --display <mode>          how to show frames: blocks (the default) or
+                              braille in a terminal, sixel or kitty for
+                              terminals with graphics, or text, which reads
+                              keys a line at a time
+    --palette <colors>        up to 4 comma-separated hex colors for sixel
+                              and kitty, replacing black (off), white
+                              (plane 0), yellow (plane 1) and red (both)
+    --scale <n>               screen pixels per pixel for sixel and kitty
+                              (default: 512 pixels wide, which kitty sends
+                              as about 520 KB per changed frame)
+    --gdb <host:port>         wait for gdb to connect and debug the ROM with
+                              it over the remote serial protocol
+
+disasm options:
+    --syntax <classic|octo>   the assembly language to write (default classic)
+
+asm options:
+    -o, --output <file>       where to write the ROM (default: the source
+                              file with a .ch8 extension)
+
+trace options:
+    --cycles <n>              number of instructions to run (default 1000)";
+
+/// Options shared by every command that loads a ROM.
+#[derive(Debug, Default)]
+pub struct Common {
+    /// The platform to emulate, or None to detect it.
+    pub platform: Option<Platform>,
+
+    /// Changes to the quirks of the platform.
+    pub quirks: Option<String>,
+
+    /// Instructions per second, or None for the platform default.
+    pub speed: Option<i64>,
+
+    /// How the host keyboard maps to the 16-key pad.
+    pub keymap: Keymap,
+
+    /// The seed for CXNN, or None for the default.
+    pub seed: Option<u32>,
+
+    /// Use the COSMAC VIP interpreter's random routine for CXNN instead
+    /// of xorshift.
+    pub vip_rng: bool,
+}
+
+impl Common {
+    /// Parses one of the common options, returning false if the argument
+    /// isn't one of them.
+    pub fn parse(&mut self, arg: &str, args: &mut Args) -> Result<bool, String> {
+        match arg {
+            "--platform" => self.platform = Some(value(arg, args)?.parse().map_err(|e| format!("{}", e))?),
+            "--quirks" => {
+                let changes = value(arg, args)?;
+
+                // Check the changes now, they're applied once the platform is known.
+                Quirks::default().apply(changes).map_err(|e| format!("{}", e))?;
+                self.quirks = Some(changes.to_string());
+            },
+            "--speed" => self.speed = Some(number(value(arg, args)?)? as i64),
+            "--keymap" => self.keymap = value(arg, args)?.parse().map_err(|e| format!("{}", e))?,
+            "--seed" => {
+                let seed = value(arg, args)?;
+
+                self.seed = Some(seed.parse().map_err(|_| format!("'{}' is not a seed (0-4294967295)", seed))?);
+            },
+            "--rng" => {
+                self.vip_rng = match value(arg, args)? {
+                    "xorshift" => false,
+                    "vip" => true,
+                    r => return Err(format!("unknown generator '{}'", r)),
+                }
+            },
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+
+    /// Reads a ROM and loads it into a VM set up with the options.
+    pub fn load(&self, path: &str) -> Result<VM, String> {
+        self.load_program(read(path)?, path)
+    }
+
+    /// Loads a program into a VM set up with the options. Errors are
+    /// reported against `name`.
+    pub fn load_program(&self, program: Vec<u8>, name: &str) -> Result<VM, String> {
+        let platform = self.platform.unwrap_or_else(|| Platform::detect(&program));
+        let mut vm = load_rom(program, platform).map_err(|e| format!("{}: {}", name, e))?;
+
+        if let Some(changes) = &self.quirks {
+            let mut quirks = *vm.quirks();
+
+            quirks.apply(changes).map_err(|e| format!("{}", e))?;
+            vm.set_quirks(quirks);
+        }
+
+        if self.vip_rng {
+            vm.set_rng(Box::new(VipRng::default()));
+        }
+
+        if let Some(seed) = self.seed {
+            vm.set_seed(seed);
+        }
+
+        if let Some(speed) = self.speed {
+            vm.set_speed(speed);
+            vm.set_cycles_per_frame(speed / 60);
+        }
+
+        Ok(vm)
+    }
+}
+
+/// Returns the value following an option.
+pub fn value<'a>(name: &str, args: &mut Args<'a>) -> Result<&'a str, String> {
+    args.next().map(String::as_str).ok_or(format!("{} needs a value", name))
+}
+
+/// Parses a decimal number.
+pub fn number(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("'{}' is not a number", s))
+}
+
+/// Takes a positional argument, failing if one was already given or if
+/// it looks like an option.
+pub fn positional(arg: &str, file: &mut Option<String>) -> Result<(), String> {
+    if arg.starts_with('-') {
+        Err(format!("unknown option '{}'", arg))
+    } else if file.is_some() {
+        Err(format!("unexpected argument '{}'", arg))
+    } else {
+        *file = Some(arg.to_string());
+        Ok(())
+    }
+}
+
+/// Reads a ROM. Octo source, with an `.8o` extension, is assembled.
+pub fn read(path: &str) -> Result<Vec<u8>, String> {
+    if Path::new(path).extension().is_some_and(|ext| ext == "8o") {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        return assemble(&source).map(|a| a.rom).map_err(|e| format!("{}:{}: {}", path, e.line, e.message));
+    }
+
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
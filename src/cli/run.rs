@@ -0,0 +1,431 @@
+use std::io::{self, BufRead, Stdout, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+use chipper::vm::debug::Debugger;
+use chipper::vm::gdb::GdbStub;
+use chipper::vm::io::{AudioSink, Display, Frame, InputSource, NullAudio, NullDisplay, Palette, Press, ScriptedInput};
+use chipper::vm::machine::Machine;
+use chipper::vm::vm::VM;
+use crate::cli::graphics::{GraphicsDisplay, Protocol};
+use crate::cli::keymap::Keymap;
+use crate::cli::term::{Cells, RawMode, RawInput, TermDisplay};
+use crate::cli::{number, positional, value, Args, Common, HOLD};
+
+/// How many frames a headless run lasts when `--frames` isn't given.
+const HEADLESS_FRAMES: usize = 600;
+
+/// How the final frame of a headless run is printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    Pbm,
+}
+
+/// How frames are shown when running interactively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Screen {
+    /// Frames printed as text, with keys read a line at a time, which
+    /// works without a terminal.
+    Text,
+
+    /// Frames drawn with characters in a terminal in raw mode.
+    Cells(Cells),
+
+    /// Frames drawn as images in a terminal in raw mode.
+    Graphics(Protocol),
+}
+
+/// Options for the run command.
+#[derive(Debug)]
+struct Options {
+    /// Path to the ROM.
+    rom: String,
+
+    /// Options shared with the other commands.
+    common: Common,
+
+    /// Run without a display, printing the final frame on exit.
+    headless: bool,
+
+    /// The number of frames to run, or None to run until quit, or for
+    /// `HEADLESS_FRAMES` when headless.
+    frames: Option<usize>,
+
+    /// Keys to press, and when.
+    presses: Vec<Press>,
+
+    /// How the final frame is printed.
+    format: Format,
+
+    /// How frames are shown when running interactively.
+    screen: Screen,
+
+    /// The colors frames are drawn in as images.
+    palette: Palette,
+
+    /// Pixels on screen per pixel when drawing images, or None to fit
+    /// a default size.
+    scale: Option<usize>,
+
+    /// Address to wait for a gdb connection on, to debug the ROM instead
+    /// of running it.
+    gdb: Option<String>,
+}
+
+/// Draws frames as text at the top of the terminal.
+struct Terminal {
+    out: Stdout,
+}
+
+/// Rings the terminal bell when the sound timer starts.
+struct Bell;
+
+/// Reads keys typed into the terminal. Without raw mode the terminal
+/// only hands over a line once Enter is pressed, so every key on the
+/// line is held down for a few frames.
+struct LineInput {
+    /// Characters read from stdin by a background thread, or None once
+    /// stdin is closed.
+    rx: Receiver<Option<char>>,
+
+    /// How the host keyboard maps to the pad.
+    keymap: Keymap,
+
+    /// Frames left that each key is held down for.
+    held: [usize; 16],
+
+    /// Set once stdin is closed.
+    quit: bool,
+}
+
+/// Runs a ROM interactively in the terminal, or headless.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let opts = parse(&mut args)?;
+
+    if let Some(addr) = &opts.gdb {
+        gdb(&opts, addr)
+    } else if opts.headless {
+        headless(&opts)
+    } else {
+        interactive(&opts)
+    }
+}
+
+/// Parses the arguments of the run command.
+fn parse(args: &mut Args) -> Result<Options, String> {
+    let mut rom = None;
+    let mut opts = Options {
+        rom: String::new(),
+        common: Common::default(),
+        headless: false,
+        frames: None,
+        presses: Vec::new(),
+        format: Format::Ascii,
+        screen: Screen::Cells(Cells::Blocks),
+        palette: Palette::default(),
+        scale: None,
+        gdb: None,
+    };
+
+    while let Some(arg) = args.next() {
+        if opts.common.parse(arg, args)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--headless" => opts.headless = true,
+            "--frames" => opts.frames = Some(number(value(arg, args)?)?),
+            "--gdb" => opts.gdb = Some(value(arg, args)?.to_string()),
+            "--press" => opts.presses.push(parse_press(value(arg, args)?)?),
+            "--format" => {
+                opts.format = match value(arg, args)? {
+                    "ascii" => Format::Ascii,
+                    "pbm" => Format::Pbm,
+                    f => return Err(format!("unknown format '{}'", f)),
+                }
+            },
+            "--display" => {
+                opts.screen = match value(arg, args)? {
+                    "text" => Screen::Text,
+                    "blocks" => Screen::Cells(Cells::Blocks),
+                    "braille" => Screen::Cells(Cells::Braille),
+                    "sixel" => Screen::Graphics(Protocol::Sixel),
+                    "kitty" => Screen::Graphics(Protocol::Kitty),
+                    d => return Err(format!("unknown display '{}'", d)),
+                }
+            },
+            "--palette" => opts.palette = value(arg, args)?.parse().map_err(|e| format!("{}", e))?,
+            "--scale" => {
+                opts.scale = match number(value(arg, args)?)? {
+                    0 => return Err("--scale must be at least 1".to_string()),
+                    n => Some(n),
+                }
+            },
+            _ => positional(arg, &mut rom)?,
+        }
+    }
+
+    opts.rom = rom.ok_or("no ROM given")?;
+
+    Ok(opts)
+}
+
+/// Parses a scripted key press of the form `frame:key[:frames]`, where
+/// the key is a hex digit.
+fn parse_press(s: &str) -> Result<Press, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+
+    if parts.len() < 2 || parts.len() > 3 {
+        return Err(format!("'{}' is not frame:key[:frames]", s));
+    }
+
+    let key = match u8::from_str_radix(parts[1], 16) {
+        Ok(key) if key < 16 && parts[1].len() == 1 => key,
+        _ => return Err(format!("'{}' is not a key (0-F)", parts[1])),
+    };
+
+    Ok(Press {
+        frame: number(parts[0])?,
+        key,
+        frames: match parts.get(2) {
+            Some(n) => number(n)?,
+            None => 1,
+        },
+    })
+}
+
+/// Runs a ROM for a number of frames, then prints the final frame and
+/// hashes of video and memory.
+fn headless(opts: &Options) -> Result<(), String> {
+    let (machine, frames) = run_headless(opts.common.load(&opts.rom)?, opts)?;
+    let frame = machine.vm().frame();
+    let summary = summary(machine.vm(), frames);
+
+    // Keep the bitmap alone on stdout so it can be redirected to a file.
+    match opts.format {
+        Format::Ascii => println!("{}{}", frame.to_ascii(), summary),
+        Format::Pbm => {
+            print!("{}", frame.to_pbm());
+            eprintln!("{}", summary);
+        },
+    }
+
+    Ok(())
+}
+
+/// Runs a VM with the scripted presses until it exits or the frame
+/// limit is reached, returning the machine and the frames run.
+fn run_headless(vm: VM, opts: &Options) -> Result<(Machine, usize), String> {
+    let input = ScriptedInput::new(opts.presses.clone());
+    let mut machine = Machine::new(vm, Box::new(NullDisplay), Box::new(NullAudio), Box::new(input));
+    let limit = opts.frames.unwrap_or(HEADLESS_FRAMES);
+    let mut frames = 0;
+
+    while frames < limit && !machine.vm().exited() {
+        machine.run_frame().map_err(|e| format!("frame {}: {}", frames, e))?;
+        frames += 1;
+    }
+
+    Ok((machine, frames))
+}
+
+/// Returns the summary printed after a headless run.
+fn summary(vm: &VM, frames: usize) -> String {
+    format!(
+        "frames {}\ncycles {}\nvideo  {:08X}\nmemory {:08X}",
+        frames,
+        vm.cycles(),
+        vm.video_hash(),
+        vm.memory_hash(),
+    )
+}
+
+/// Waits for gdb to connect, then lets it debug the ROM until it
+/// detaches or disconnects.
+fn gdb(opts: &Options, addr: &str) -> Result<(), String> {
+    let vm = opts.common.load(&opts.rom)?;
+    let listener = TcpListener::bind(addr).map_err(|e| format!("{}: {}", addr, e))?;
+
+    eprintln!("waiting for gdb on {}", addr);
+
+    let (stream, peer) = listener.accept().map_err(|e| format!("{}: {}", addr, e))?;
+
+    eprintln!("gdb connected from {}", peer);
+
+    GdbStub::new(Debugger::new(vm), stream).serve().map_err(|e| format!("gdb: {}", e))
+}
+
+/// Runs a ROM at 60 frames per second, drawing it in the terminal until
+/// stdin is closed or the program exits.
+fn interactive(opts: &Options) -> Result<(), String> {
+    let vm = opts.common.load(&opts.rom)?;
+
+    let (raw, display, input): (_, Box<dyn Display>, Box<dyn InputSource>) = match opts.screen {
+        Screen::Text => (None, Box::new(Terminal { out: io::stdout() }), Box::new(LineInput::new(opts.common.keymap))),
+        Screen::Cells(cells) => (
+            Some(RawMode::enter()?),
+            Box::new(TermDisplay::new(cells, "Ctrl-C to quit")),
+            Box::new(RawInput::new(opts.common.keymap)),
+        ),
+        Screen::Graphics(protocol) => (
+            Some(RawMode::enter()?),
+            Box::new(GraphicsDisplay::new(protocol, opts.palette, opts.scale)),
+            Box::new(RawInput::new(opts.common.keymap)),
+        ),
+    };
+
+    let mut machine = Machine::new(vm, display, Box::new(Bell), input);
+    let frame_time = Duration::from_nanos(1_000_000_000 / 60);
+    let mut next = Instant::now();
+    let mut frames = 0;
+
+    print!("\x1b[2J\x1b[?25l");
+    machine.refresh();
+
+    let result = loop {
+        if machine.quit() || machine.vm().exited() || opts.frames.is_some_and(|n| frames >= n) {
+            break Ok(());
+        }
+
+        if let Err(err) = machine.run_frame() {
+            break Err(format!("frame {}: {}", frames, err));
+        }
+
+        frames += 1;
+        next += frame_time;
+
+        match next.checked_duration_since(Instant::now()) {
+            Some(wait) => thread::sleep(wait),
+            None => next = Instant::now(),
+        }
+    };
+
+    print!("\x1b[?25h");
+    let _ = io::stdout().flush();
+    drop(raw);
+
+    result
+}
+
+impl Display for Terminal {
+    fn present(&mut self, frame: &Frame) {
+        let _ = writeln!(self.out, "\x1b[H{}type keys and press Enter, Ctrl-D to quit\x1b[K", frame.to_ascii());
+        let _ = self.out.flush();
+    }
+}
+
+impl AudioSink for Bell {
+    fn beep(&mut self, on: bool) {
+        if on {
+            print!("\x07");
+        }
+    }
+}
+
+impl LineInput {
+    fn new(keymap: Keymap) -> LineInput {
+        let (tx, rx) = mpsc::channel();
+
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+
+                for c in line.chars() {
+                    if tx.send(Some(c)).is_err() {
+                        return;
+                    }
+                }
+            }
+
+            let _ = tx.send(None);
+        });
+
+        LineInput { rx, keymap, held: [0; 16], quit: false }
+    }
+}
+
+impl InputSource for LineInput {
+    fn poll(&mut self) -> [bool; 16] {
+        for held in self.held.iter_mut() {
+            *held = held.saturating_sub(1);
+        }
+
+        while let Ok(c) = self.rx.try_recv() {
+            match c.map(|c| self.keymap.key(c)) {
+                Some(Some(key)) => self.held[key as usize] = HOLD,
+                Some(None) => (),
+                None => self.quit = true,
+            }
+        }
+
+        let mut keys = [false; 16];
+
+        for (key, &held) in keys.iter_mut().zip(self.held.iter()) {
+            *key = held > 0;
+        }
+
+        keys
+    }
+
+    fn quit(&self) -> bool {
+        self.quit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Draws the font zero at random positions, forever.
+    const ROM: [u8; 8] = [0xC0, 0x3F, 0xC1, 0x1F, 0xD0, 0x15, 0x12, 0x00];
+
+    fn options(args: &[&str]) -> Options {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+
+        parse(&mut args.iter()).unwrap()
+    }
+
+    fn headless_summary(args: &[&str]) -> String {
+        let opts = options(args);
+        let vm = opts.common.load_program(ROM.to_vec(), "rom").unwrap();
+        let (machine, frames) = run_headless(vm, &opts).unwrap();
+
+        summary(machine.vm(), frames)
+    }
+
+    #[test]
+    fn presses_are_frame_key_and_length() {
+        assert_eq!(parse_press("10:a").unwrap(), Press { frame: 10, key: 0xA, frames: 1 });
+        assert_eq!(parse_press("0:F:30").unwrap(), Press { frame: 0, key: 0xF, frames: 30 });
+    }
+
+    #[test]
+    fn bad_presses_are_rejected() {
+        assert_eq!(parse_press("10").unwrap_err(), "'10' is not frame:key[:frames]");
+        assert_eq!(parse_press("1:2:3:4").unwrap_err(), "'1:2:3:4' is not frame:key[:frames]");
+        assert_eq!(parse_press("10:g").unwrap_err(), "'g' is not a key (0-F)");
+        assert_eq!(parse_press("10:10").unwrap_err(), "'10' is not a key (0-F)");
+        assert_eq!(parse_press("x:1").unwrap_err(), "'x' is not a number");
+        assert_eq!(parse_press("1:1:-5").unwrap_err(), "'-5' is not a number");
+    }
+
+    #[test]
+    fn headless_summary_is_reproducible() {
+        let args = ["--headless", "--frames", "20", "--seed", "7", "--press", "2:5", "rom.ch8"];
+        let summary = headless_summary(&args);
+
+        assert_eq!(summary, headless_summary(&args));
+        assert_eq!(summary, "frames 20\ncycles 220\nvideo  48DC3273\nmemory 80DDB1D9");
+        assert_ne!(summary, headless_summary(&["--headless", "--frames", "20", "--seed", "8", "rom.ch8"]));
+    }
+
+    #[test]
+    fn headless_runs_600_frames_by_default() {
+        assert!(headless_summary(&["--headless", "rom.ch8"]).starts_with("frames 600\n"));
+    }
+}
@@ -0,0 +1,361 @@
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use chipper::vm::io::{Display, Frame, InputSource};
+use crate::cli::keymap::Keymap;
+use crate::cli::HOLD;
+
+/// SGR foreground color codes for each color index, adding 10 gives the
+/// background. Index 0 is the terminal's own colors.
+const COLORS: [u8; 4] = [39, 97, 93, 91];
+
+/// Braille dot for each pixel of a 2x4 cell, by row then column.
+const BRAILLE: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+/// Puts the terminal into raw mode on the alternate screen, so keys are
+/// read as they are pressed without being echoed. The terminal is put
+/// back the way it was when this is dropped.
+///
+/// Raw mode is set with stty, which every Linux terminal and SSH session
+/// has, rather than binding termios.
+pub struct RawMode {
+    /// The terminal settings to restore, as printed by `stty -g`.
+    saved: String,
+}
+
+impl RawMode {
+    /// Enters raw mode and switches to the alternate screen.
+    pub fn enter() -> Result<RawMode, String> {
+        let saved = stty(&["-g"]).map_err(|e| format!("can't read terminal settings: {}", e))?;
+
+        stty(&["raw", "-echo"]).map_err(|e| format!("can't enter raw mode: {}", e))?;
+        print!("\x1b[?1049h\x1b[?25l\x1b[2J");
+        let _ = io::stdout().flush();
+
+        Ok(RawMode { saved: saved.trim().to_string() })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        print!("\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+/// Runs stty on the terminal, returning what it prints.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+    }
+}
+
+/// Returns the size of the terminal as columns and rows, or 80x24 if it
+/// can't be found.
+pub fn size() -> (usize, usize) {
+    let size = stty(&["size"]).ok().and_then(|s| {
+        let mut parts = s.split_whitespace().map(|n| n.parse::<usize>().ok());
+
+        match (parts.next()?, parts.next()?) {
+            (Some(rows), Some(cols)) if rows > 0 && cols > 0 => Some((cols, rows)),
+            _ => None,
+        }
+    });
+
+    size.unwrap_or((80, 24))
+}
+
+/// Reads bytes from stdin on a background thread. The channel closes
+/// when stdin does.
+pub fn spawn_stdin() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = [0; 64];
+
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+
+            for &b in &buf[..n] {
+                if tx.send(b).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+/// How frames are drawn with characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cells {
+    /// Half-blocks, one column and two rows of pixels per character, so
+    /// 64x32 takes 64x16 characters.
+    Blocks,
+
+    /// Braille, two columns and four rows of pixels per character, so
+    /// 128x64 takes 64x16 characters. Each character has one color.
+    Braille,
+}
+
+/// A character on screen with its foreground and background colors,
+/// as indexes into `COLORS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    c: char,
+    fg: u8,
+    bg: u8,
+}
+
+/// Draws frames in the terminal with Unicode characters. Only the
+/// characters that changed since the last frame are written.
+pub struct TermDisplay {
+    /// How pixels are turned into characters.
+    cells: Cells,
+
+    /// The characters on screen, by row.
+    screen: Vec<Cell>,
+
+    /// Size of the screen in characters, which changes with the
+    /// resolution.
+    size: (usize, usize),
+
+    /// Printed below the frame.
+    footer: String,
+}
+
+impl TermDisplay {
+    /// Creates a display drawing with the given characters, printing a
+    /// footer below each frame. The terminal should be in raw mode.
+    pub fn new(cells: Cells, footer: &str) -> TermDisplay {
+        TermDisplay { cells, screen: Vec::new(), size: (0, 0), footer: footer.to_string() }
+    }
+
+    /// Returns a frame as characters and its size in characters.
+    fn render(&self, frame: &Frame) -> (Vec<Cell>, (usize, usize)) {
+        let mut cells = Vec::new();
+
+        let size = match self.cells {
+            Cells::Blocks => {
+                let size = (frame.width, frame.height / 2);
+
+                for y in 0..size.1 {
+                    for x in 0..size.0 {
+                        cells.push(block(frame.pixel(x, y * 2), frame.pixel(x, y * 2 + 1)));
+                    }
+                }
+
+                size
+            },
+            Cells::Braille => {
+                let size = (frame.width / 2, frame.height / 4);
+
+                for y in 0..size.1 {
+                    for x in 0..size.0 {
+                        let mut dots = 0;
+                        let mut fg = 0;
+
+                        for (dy, row) in BRAILLE.iter().enumerate() {
+                            for (dx, dot) in row.iter().enumerate() {
+                                let color = frame.pixel(x * 2 + dx, y * 4 + dy);
+
+                                if color != 0 {
+                                    dots |= dot;
+                                    fg = fg.max(color);
+                                }
+                            }
+                        }
+
+                        // A blank braille character is drawn as a space, like
+                        // the cleared screen.
+                        let c = match dots {
+                            0 => ' ',
+                            _ => std::char::from_u32(0x2800 + dots).unwrap_or(' '),
+                        };
+
+                        cells.push(Cell { c, fg, bg: 0 });
+                    }
+                }
+
+                size
+            },
+        };
+
+        (cells, size)
+    }
+}
+
+/// Returns the half-block for a pair of pixels above each other.
+fn block(top: u8, bottom: u8) -> Cell {
+    match (top, bottom) {
+        (0, 0) => Cell { c: ' ', fg: 0, bg: 0 },
+        (t, b) if t == b => Cell { c: '█', fg: t, bg: 0 },
+        (t, 0) => Cell { c: '▀', fg: t, bg: 0 },
+        (0, b) => Cell { c: '▄', fg: b, bg: 0 },
+        (t, b) => Cell { c: '▀', fg: t, bg: b },
+    }
+}
+
+impl Display for TermDisplay {
+    fn present(&mut self, frame: &Frame) {
+        let (cells, size) = self.render(frame);
+        let mut out = String::new();
+
+        // Start over when the resolution changes.
+        if size != self.size {
+            self.screen = vec![Cell { c: ' ', fg: 0, bg: 0 }; cells.len()];
+            self.size = size;
+
+            let _ = write!(out, "\x1b[0m\x1b[2J\x1b[{};1H{}", size.1 + 1, self.footer);
+        }
+
+        // Where the cursor is after the last write, and the colors set.
+        let mut cursor = None;
+        let mut colors = (0, 0);
+
+        for (n, (&cell, old)) in cells.iter().zip(self.screen.iter_mut()).enumerate() {
+            if cell == *old {
+                continue;
+            }
+
+            let (x, y) = (n % size.0, n / size.0);
+
+            if cursor != Some(n) {
+                let _ = write!(out, "\x1b[{};{}H", y + 1, x + 1);
+            }
+
+            if (cell.fg, cell.bg) != colors {
+                let _ = write!(out, "\x1b[{};{}m", COLORS[cell.fg as usize], COLORS[cell.bg as usize] + 10);
+                colors = (cell.fg, cell.bg);
+            }
+
+            out.push(cell.c);
+            *old = cell;
+
+            // The cursor doesn't move on past the last column.
+            cursor = if x + 1 < size.0 { Some(n + 1) } else { None };
+        }
+
+        if !out.is_empty() {
+            let mut stdout = io::stdout();
+
+            let _ = write!(stdout, "{}\x1b[0m", out);
+            let _ = stdout.flush();
+        }
+    }
+}
+
+/// Reads keys from a terminal in raw mode. Terminals only report keys
+/// being typed, not released, so each key is held down for a few frames,
+/// which key repeat keeps topped up while it's held.
+pub struct RawInput {
+    /// Bytes read from stdin by a background thread.
+    rx: Receiver<u8>,
+
+    /// How the host keyboard maps to the pad.
+    keymap: Keymap,
+
+    /// Frames left that each key is held down for.
+    held: [usize; 16],
+
+    /// True while skipping the rest of an escape sequence, sent for arrow
+    /// and function keys.
+    escape: bool,
+
+    /// Set by Ctrl-C, Ctrl-D or stdin being closed.
+    quit: bool,
+}
+
+impl RawInput {
+    /// Starts reading keys. The terminal should be in raw mode.
+    pub fn new(keymap: Keymap) -> RawInput {
+        RawInput { rx: spawn_stdin(), keymap, held: [0; 16], escape: false, quit: false }
+    }
+}
+
+impl InputSource for RawInput {
+    fn poll(&mut self) -> [bool; 16] {
+        for held in self.held.iter_mut() {
+            *held = held.saturating_sub(1);
+        }
+
+        loop {
+            let b = match self.rx.try_recv() {
+                Ok(b) => b,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.quit = true;
+                    break;
+                },
+            };
+
+            match b {
+                0x03 | 0x04 => self.quit = true,
+                0x1B => self.escape = true,
+                b'[' | b'O' if self.escape => (),
+                _ if self.escape => self.escape = !(0x40..=0x7E).contains(&b),
+                _ => {
+                    if let Some(key) = self.keymap.key(b as char) {
+                        self.held[key as usize] = HOLD;
+                    }
+                },
+            }
+        }
+
+        let mut keys = [false; 16];
+
+        for (key, &held) in keys.iter_mut().zip(self.held.iter()) {
+            *key = held > 0;
+        }
+
+        keys
+    }
+
+    fn quit(&self) -> bool {
+        self.quit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4x4 frame with every kind of half-block in its top two rows.
+    const PLANES: [[u8; 4]; 2] = [[0xD0, 0x80, 0x00, 0x00], [0x00, 0x30, 0x00, 0x00]];
+
+    fn frame() -> Frame<'static> {
+        Frame { planes: [&PLANES[0], &PLANES[1]], pitch: 1, width: 4, height: 4 }
+    }
+
+    fn cell(c: char, fg: u8, bg: u8) -> Cell {
+        Cell { c, fg, bg }
+    }
+
+    #[test]
+    fn blocks_render_two_rows_per_character() {
+        let (cells, size) = TermDisplay::new(Cells::Blocks, "").render(&frame());
+
+        assert_eq!(size, (4, 2));
+        assert_eq!(cells[..4], [cell('█', 1, 0), cell('▀', 1, 0), cell('▄', 2, 0), cell('▀', 1, 2)]);
+        assert!(cells[4..].iter().all(|&c| c == cell(' ', 0, 0)));
+    }
+
+    #[test]
+    fn braille_renders_two_by_four_pixels_per_character() {
+        let (cells, size) = TermDisplay::new(Cells::Braille, "").render(&frame());
+
+        assert_eq!(size, (2, 1));
+        assert_eq!(cells, [cell('\u{280B}', 1, 0), cell('\u{281A}', 2, 0)]);
+    }
+}
@@ -0,0 +1,59 @@
+use chipper::vm::disasm::classic;
+use chipper::vm::instruction::Instruction;
+use crate::cli::{number, positional, value, Args, Common};
+
+/// Runs a ROM, printing every instruction before it executes along with
+/// the registers.
+pub fn main(mut args: Args) -> Result<(), String> {
+    let mut common = Common::default();
+    let mut rom = None;
+    let mut cycles = 1000;
+
+    while let Some(arg) = args.next() {
+        if common.parse(arg, &mut args)? {
+            continue;
+        }
+
+        match arg.as_str() {
+            "--cycles" => cycles = number(value(arg, &mut args)?)?,
+            _ => positional(arg, &mut rom)?,
+        }
+    }
+
+    let mut vm = common.load(&rom.ok_or("no ROM given")?)?;
+
+    for _ in 0..cycles {
+        if vm.exited() {
+            println!("exited");
+            break;
+        }
+
+        let pc = vm.pc();
+        let memory = vm.memory();
+        let word = |addr: usize| u16::from_be_bytes([memory[addr % memory.len()], memory[(addr + 1) % memory.len()]]);
+        let op = word(pc);
+        let inst = match Instruction::decode_long(op, word(pc + 2)) {
+            Ok(inst) => classic(inst),
+            Err(_) => "???".to_string(),
+        };
+        let regs = vm.registers();
+        let v: Vec<String> = regs.v.iter().map(|v| format!("{:02X}", v)).collect();
+
+        println!(
+            "{:>8}  {:03X}  {:04X}  {:<20}  V={} I={:03X} SP={:X} DT={:02X} ST={:02X}",
+            vm.cycles(),
+            pc,
+            op,
+            inst,
+            v.join(" "),
+            regs.i,
+            vm.stack().len(),
+            regs.dt,
+            regs.st,
+        );
+
+        vm.step().map_err(|e| format!("{}", e))?;
+    }
+
+    Ok(())
+}
@@ -1,5 +1,30 @@
-mod vm;
+use std::env;
+use std::process;
+
+mod cli;
 
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let rest = args.get(1..).unwrap_or(&[]).iter();
+
+    let result = match args.first().map(String::as_str) {
+        Some("run") => cli::run::main(rest),
+        Some("disasm") => cli::disasm::main(rest),
+        Some("asm") => cli::asm::main(rest),
+        Some("info") => cli::info::main(rest),
+        Some("trace") => cli::trace::main(rest),
+        Some("debug") => cli::debug::main(rest),
+        Some("dap") => cli::dap::main(rest),
+        Some("help") | Some("-h") | Some("--help") => {
+            println!("{}", cli::USAGE);
+            return;
+        },
+        Some(cmd) => Err(format!("unknown command '{}'\n\n{}", cmd, cli::USAGE)),
+        None => Err(format!("no command given\n\n{}", cli::USAGE)),
+    };
+
+    if let Err(err) = result {
+        eprintln!("chipper: {}", err);
+        process::exit(1);
+    }
 }
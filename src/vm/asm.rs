@@ -0,0 +1,1272 @@
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+
+/// Address Octo programs are assembled at.
+const BASE: usize = 0x200;
+
+/// How many macro expansions a program may make before it's assumed a
+/// macro is expanding itself forever.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// A program assembled from Octo source.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Assembly {
+    /// The assembled bytes, starting at 0x200, ready for `load_rom`.
+    pub rom: Vec<u8>,
+
+    /// The address of every label.
+    pub labels: BTreeMap<String, u16>,
+
+    /// The source line (counting from 1) of the statement that emitted
+    /// the instruction or data at each address.
+    pub lines: BTreeMap<u16, usize>,
+
+    /// Breakpoints set with `:breakpoint`, by name.
+    pub breakpoints: Vec<(String, u16)>,
+}
+
+/// An error in Octo source, with the line (counting from 1) it is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// The line the error is on.
+    pub line: usize,
+
+    /// What went wrong.
+    pub message: String,
+}
+
+/// A word of source and the line it is on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+/// An operand that might not be known until the whole program has been
+/// assembled, because it names a label further on.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Known(i64),
+    Forward(String),
+}
+
+/// How a forward reference is patched in once its label is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Patch {
+    /// The low 12 bits of the word at the address.
+    Addr,
+
+    /// The 16-bit word at the address.
+    Long,
+
+    /// The byte at the address.
+    Byte,
+
+    /// The byte at the address, with a nibble above the top 4 bits of
+    /// the 12-bit address, as `:unpack` loads into V0.
+    Unpack(u8),
+
+    /// The byte at the address, with the low 8 bits of the address, as
+    /// `:unpack` loads into V1.
+    Low,
+}
+
+/// A forward reference waiting for a label to be defined.
+#[derive(Debug, Clone)]
+struct Fixup {
+    addr: usize,
+    name: String,
+    patch: Patch,
+    line: usize,
+}
+
+/// A condition of `if` or `while`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cond {
+    Eq(u8, Rhs),
+    Ne(u8, Rhs),
+    Lt(u8, Rhs),
+    Gt(u8, Rhs),
+    Le(u8, Rhs),
+    Ge(u8, Rhs),
+    Key(u8),
+    NotKey(u8),
+}
+
+/// The right hand side of a condition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rhs {
+    Reg(u8),
+    Imm(u8),
+}
+
+/// An open control structure, waiting for its end.
+#[derive(Debug, Clone)]
+enum Flow {
+    /// `if ... begin`, with the address of the jump to `else` or `end`.
+    Begin(usize),
+
+    /// `else`, with the address of the jump to `end`.
+    Else(usize),
+
+    /// `loop`, with its address and the jumps out of it made by `while`.
+    Loop(usize, Vec<usize>),
+}
+
+/// A macro defined with `:macro`.
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// The state of a single pass over the source.
+struct Assembler {
+    /// The source, split into words. Macros are expanded in place.
+    tokens: Vec<Token>,
+
+    /// The next token to read.
+    pos: usize,
+
+    /// The line of the last token read.
+    line: usize,
+
+    /// The bytes emitted so far, starting at 0x200.
+    rom: Vec<u8>,
+
+    /// Where the next byte is emitted.
+    here: usize,
+
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    flow: Vec<(Flow, usize)>,
+    lines: BTreeMap<u16, usize>,
+    breakpoints: Vec<(String, u16)>,
+
+    /// Set by `:next` to label the second byte of the next instruction.
+    next: Option<String>,
+
+    /// Number of macro expansions so far.
+    expansions: usize,
+}
+
+/// Assembles Octo source into a ROM.
+///
+/// Programs start at the `main` label. As in Octo, a jump to `main` is
+/// placed at 0x200 unless `main` comes right after it, in which case the
+/// jump is left out.
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let assembly = Assembler::new(source, true).run()?;
+
+    // The jump to main is patched like any other, so a program without
+    // one has already failed.
+    match assembly.labels.get("main") {
+        Some(&0x202) => Assembler::new(source, false).run(),
+        _ => Ok(assembly),
+    }
+}
+
+impl Assembly {
+    /// Returns the source line that emitted the byte at an address.
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.lines.range(..=addr).next_back().map(|(_, &line)| line)
+    }
+
+    /// Returns the first address emitted by a source line, or by the
+    /// closest line after it that emitted anything.
+    pub fn addr(&self, line: usize) -> Option<u16> {
+        self.lines.iter()
+            .filter(|&(_, &l)| l >= line)
+            .min_by_key(|&(&addr, &l)| (l, addr))
+            .map(|(&addr, _)| addr)
+    }
+}
+
+impl Assembler {
+    fn new(source: &str, reserve_main: bool) -> Assembler {
+        let mut tokens = Vec::new();
+
+        for (n, line) in source.lines().enumerate() {
+            let code = line.split('#').next().unwrap_or("");
+
+            for word in code.split_whitespace() {
+                tokens.push(Token { text: word.to_string(), line: n + 1 });
+            }
+        }
+
+        Assembler {
+            tokens,
+            pos: 0,
+            line: 1,
+            rom: if reserve_main { vec![0x12, 0x00] } else { Vec::new() },
+            here: if reserve_main { BASE + 2 } else { BASE },
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: if reserve_main {
+                vec![Fixup { addr: BASE, name: "main".to_string(), patch: Patch::Addr, line: 1 }]
+            } else {
+                Vec::new()
+            },
+            flow: Vec::new(),
+            lines: BTreeMap::new(),
+            breakpoints: Vec::new(),
+            next: None,
+            expansions: 0,
+        }
+    }
+
+    /// Assembles every statement, then patches in forward references.
+    fn run(mut self) -> Result<Assembly, AsmError> {
+        while self.pos < self.tokens.len() {
+            self.statement()?;
+        }
+
+        if let Some((_, line)) = self.flow.last() {
+            return Err(AsmError { line: *line, message: "This control structure is never closed".to_string() });
+        }
+
+        if let Some(name) = self.next.take() {
+            return Err(self.error(format!(":next {} has no instruction after it", name)));
+        }
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            let value = match (self.labels.get(&fixup.name), self.constants.get(&fixup.name)) {
+                (Some(&addr), _) => addr as i64,
+                (None, Some(&value)) => value as i64,
+                _ => {
+                    return Err(AsmError { line: fixup.line, message: format!("Undefined name '{}'", fixup.name) });
+                },
+            };
+
+            self.line = fixup.line;
+            self.patch(fixup.addr, value, fixup.patch)?;
+        }
+
+        Ok(Assembly {
+            rom: self.rom,
+            labels: self.labels.into_iter().collect(),
+            lines: self.lines,
+            breakpoints: self.breakpoints,
+        })
+    }
+
+    fn error(&self, message: String) -> AsmError {
+        AsmError { line: self.line, message }
+    }
+
+    /// Returns the next token.
+    fn next(&mut self) -> Result<String, AsmError> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                self.line = token.line;
+                Ok(token.text.clone())
+            },
+            None => Err(self.error("Unexpected end of file".to_string())),
+        }
+    }
+
+    /// Returns the next token without consuming it.
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.text.as_str())
+    }
+
+    /// Consumes the next token, which must be `expected`.
+    fn expect(&mut self, expected: &str) -> Result<(), AsmError> {
+        let token = self.next()?;
+
+        if token == expected {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected '{}', found '{}'", expected, token)))
+        }
+    }
+
+    /// Assembles a single statement.
+    fn statement(&mut self) -> Result<(), AsmError> {
+        let token = self.next()?;
+        let line = self.line;
+
+        match token.as_str() {
+            ":" => {
+                let name = self.name()?;
+
+                self.define_label(name)?;
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.known()?;
+
+                self.constants.insert(name, value as f64);
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let reg = if self.peek() == Some("{") {
+                    let value = self.calc()?;
+
+                    if !(0.0..16.0).contains(&value) {
+                        return Err(self.error(format!("Register {} is out of range", value)));
+                    }
+
+                    value as u8
+                } else {
+                    self.register()?
+                };
+
+                self.aliases.insert(name, reg);
+            },
+            ":macro" => self.define_macro()?,
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+
+                self.constants.insert(name, value);
+            },
+            ":org" => {
+                let addr = self.known()?;
+
+                if !(BASE as i64..0x10000).contains(&addr) {
+                    return Err(self.error(format!(":org address {:#X} is out of range", addr)));
+                }
+
+                self.here = addr as usize;
+            },
+            ":next" => self.next = Some(self.name()?),
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    Value::Known(self.calc()? as i64)
+                } else {
+                    self.value()?
+                };
+
+                self.emit_value(&[0], value, Patch::Byte, 0, line)?;
+            },
+            ":pointer" => {
+                let value = self.value()?;
+
+                self.emit_value(&[0, 0], value, Patch::Long, 0, line)?;
+            },
+            ":call" => {
+                let value = self.value()?;
+
+                self.emit_value(&[0x20, 0x00], value, Patch::Addr, 0, line)?;
+            },
+            ":unpack" => {
+                let nibble = self.known()?;
+                let value = self.value()?;
+
+                if !(0..16).contains(&nibble) {
+                    return Err(self.error(format!("Nibble {} is out of range", nibble)));
+                }
+
+                self.emit_value(&[0x60, 0x00], value.clone(), Patch::Unpack(nibble as u8), 1, line)?;
+                self.emit_value(&[0x61, 0x00], value, Patch::Low, 1, line)?;
+            },
+            ":breakpoint" => {
+                let name = self.name()?;
+
+                self.breakpoints.push((name, self.here as u16));
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+            ":assert" => {
+                let message = if self.peek() == Some("{") { "Assertion failed".to_string() } else { self.next()? };
+
+                if self.calc()? == 0.0 {
+                    return Err(self.error(message));
+                }
+            },
+            ";" | "return" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "hires" => self.emit(0x00FF)?,
+            "lores" => self.emit(0x00FE)?,
+            "exit" => self.emit(0x00FD)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+
+                self.emit(0x00C0 | n as u16)?;
+            },
+            "scroll-up" => {
+                let n = self.nibble()?;
+
+                self.emit(0x00D0 | n as u16)?;
+            },
+            "audio" => self.emit(0xF002)?,
+            "plane" => {
+                let n = self.nibble()?;
+
+                self.emit(0xF001 | (n as u16) << 8)?;
+            },
+            "bcd" => self.reg_op(0xF033)?,
+            "saveflags" => self.reg_op(0xF075)?,
+            "loadflags" => self.reg_op(0xF085)?,
+            "save" | "load" => {
+                let x = self.register()?;
+
+                if self.peek() == Some("-") {
+                    self.next()?;
+
+                    let y = self.register()?;
+                    let op = if token == "save" { 0x5002 } else { 0x5003 };
+
+                    self.emit(op | (x as u16) << 8 | (y as u16) << 4)?;
+                } else {
+                    let op = if token == "save" { 0xF055 } else { 0xF065 };
+
+                    self.emit(op | (x as u16) << 8)?;
+                }
+            },
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+
+                self.emit(0xD000 | (x as u16) << 8 | (y as u16) << 4 | n as u16)?;
+            },
+            "jump" => self.addr_op(0x1000)?,
+            "jump0" => self.addr_op(0xB000)?,
+            "native" => self.addr_op(0x0000)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+
+                let op = match token.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+
+                self.reg_op(op)?;
+            },
+            "i" => self.index()?,
+            "if" => self.conditional()?,
+            "else" => match self.flow.pop() {
+                Some((Flow::Begin(jump), _)) => {
+                    let addr = self.here;
+
+                    self.emit(0x1000)?;
+                    self.patch(jump, self.here as i64, Patch::Addr)?;
+                    self.flow.push((Flow::Else(addr), line));
+                },
+                _ => return Err(self.error("'else' without 'if ... begin'".to_string())),
+            },
+            "end" => match self.flow.pop() {
+                Some((Flow::Begin(jump), _)) | Some((Flow::Else(jump), _)) => {
+                    self.patch(jump, self.here as i64, Patch::Addr)?;
+                },
+                _ => return Err(self.error("'end' without 'begin'".to_string())),
+            },
+            "loop" => self.flow.push((Flow::Loop(self.here, Vec::new()), line)),
+            "while" => {
+                let cond = self.condition()?;
+
+                self.skip_unless(negate(cond))?;
+
+                let jump = self.here;
+
+                self.emit(0x1000)?;
+
+                match self.flow.iter_mut().rev().find(|(f, _)| matches!(f, Flow::Loop(..))) {
+                    Some((Flow::Loop(_, exits), _)) => exits.push(jump),
+                    _ => return Err(self.error("'while' outside of 'loop'".to_string())),
+                }
+            },
+            "again" => match self.flow.pop() {
+                Some((Flow::Loop(start, exits), _)) => {
+                    let jump = self.here;
+
+                    self.emit(0x1000)?;
+                    self.patch(jump, start as i64, Patch::Addr)?;
+
+                    for exit in exits {
+                        self.patch(exit, self.here as i64, Patch::Addr)?;
+                    }
+                },
+                _ => return Err(self.error("'again' without 'loop'".to_string())),
+            },
+            _ if parse_number(&token).is_some() => {
+                // A bare number is a byte of data.
+                let n = self.byte(parse_number(&token).unwrap())?;
+
+                self.emit_bytes(&[n], line)?;
+            },
+            _ if self.macros.contains_key(&token) => self.expand(&token)?,
+            _ if self.reg(&token).is_some() => self.assignment(self.reg(&token).unwrap())?,
+            _ if is_name(&token) => {
+                // Any other name calls the label.
+                self.pos -= 1;
+
+                let value = self.value()?;
+
+                self.emit_value(&[0x20, 0x00], value, Patch::Addr, 0, line)?;
+            },
+            _ => return Err(self.error(format!("Unexpected '{}'", token))),
+        }
+
+        Ok(())
+    }
+
+    /// Assembles the rest of a statement beginning with a register.
+    fn assignment(&mut self, x: u8) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let line = self.line;
+        let xy = |base: u16, y: u8| base | (x as u16) << 8 | (y as u16) << 4;
+
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+
+                    let value = self.value()?;
+
+                    self.emit_value(&[0xC0 | x, 0], value, Patch::Byte, 1, line)?;
+                },
+                Some("key") => {
+                    self.next()?;
+                    self.emit(0xF00A | (x as u16) << 8)?;
+                },
+                Some("delay") => {
+                    self.next()?;
+                    self.emit(0xF007 | (x as u16) << 8)?;
+                },
+                _ => match self.rhs_token()? {
+                    Ok(y) => self.emit(xy(0x8000, y))?,
+                    Err(value) => self.emit_value(&[0x60 | x, 0], value, Patch::Byte, 1, line)?,
+                },
+            },
+            "+=" => match self.rhs_token()? {
+                Ok(y) => self.emit(xy(0x8004, y))?,
+                Err(value) => self.emit_value(&[0x70 | x, 0], value, Patch::Byte, 1, line)?,
+            },
+            "-=" => match self.rhs_token()? {
+                Ok(y) => self.emit(xy(0x8005, y))?,
+                Err(Value::Known(n)) => {
+                    self.byte(n)?;
+                    self.emit(0x7000 | (x as u16) << 8 | (n.wrapping_neg() & 0xFF) as u16)?;
+                },
+                Err(Value::Forward(name)) => {
+                    return Err(self.error(format!("Can't subtract '{}' before it is defined", name)));
+                },
+            },
+            "=-" => {
+                let y = self.register()?;
+
+                self.emit(xy(0x8007, y))?;
+            },
+            "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.register()?;
+                let base = match op.as_str() {
+                    "|=" => 0x8001,
+                    "&=" => 0x8002,
+                    "^=" => 0x8003,
+                    ">>=" => 0x8006,
+                    _ => 0x800E,
+                };
+
+                self.emit(xy(base, y))?;
+            },
+            _ => return Err(self.error(format!("Unknown operator '{}'", op))),
+        }
+
+        Ok(())
+    }
+
+    /// Assembles the rest of a statement beginning with `i`.
+    fn index(&mut self) -> Result<(), AsmError> {
+        let op = self.next()?;
+        let line = self.line;
+
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.reg_op(0xF029)?;
+                },
+                Some("bighex") => {
+                    self.next()?;
+                    self.reg_op(0xF030)?;
+                },
+                Some("long") => {
+                    self.next()?;
+
+                    let value = self.value()?;
+
+                    self.emit(0xF000)?;
+                    self.emit_value(&[0, 0], value, Patch::Long, 0, line)?;
+                },
+                _ => {
+                    let value = self.value()?;
+
+                    self.emit_value(&[0xA0, 0], value, Patch::Addr, 0, line)?;
+                },
+            },
+            "+=" => self.reg_op(0xF01E)?,
+            _ => return Err(self.error(format!("Unknown operator '{}' for i", op))),
+        }
+
+        Ok(())
+    }
+
+    /// Assembles `if cond then` or `if cond begin`.
+    fn conditional(&mut self) -> Result<(), AsmError> {
+        let line = self.line;
+        let cond = self.condition()?;
+
+        match self.next()?.as_str() {
+            "then" => self.skip_unless(cond),
+            "begin" => {
+                self.skip_unless(negate(cond))?;
+                self.flow.push((Flow::Begin(self.here), line));
+                self.emit(0x1000)
+            },
+            token => Err(self.error(format!("Expected 'then' or 'begin', found '{}'", token))),
+        }
+    }
+
+    /// Parses a condition: a register followed by a comparison, or by
+    /// `key` or `-key`.
+    fn condition(&mut self) -> Result<Cond, AsmError> {
+        let x = self.register()?;
+        let op = self.next()?;
+
+        if op == "key" {
+            return Ok(Cond::Key(x));
+        } else if op == "-key" {
+            return Ok(Cond::NotKey(x));
+        }
+
+        let rhs = match self.rhs_token()? {
+            Ok(y) => Rhs::Reg(y),
+            Err(Value::Known(n)) => Rhs::Imm(self.byte(n)?),
+            Err(Value::Forward(name)) => {
+                return Err(self.error(format!("Can't compare with '{}' before it is defined", name)));
+            },
+        };
+
+        match op.as_str() {
+            "==" => Ok(Cond::Eq(x, rhs)),
+            "!=" => Ok(Cond::Ne(x, rhs)),
+            "<" => Ok(Cond::Lt(x, rhs)),
+            ">" => Ok(Cond::Gt(x, rhs)),
+            "<=" => Ok(Cond::Le(x, rhs)),
+            ">=" => Ok(Cond::Ge(x, rhs)),
+            _ => Err(self.error(format!("Unknown comparison '{}'", op))),
+        }
+    }
+
+    /// Emits the instructions that skip the next one unless a condition
+    /// holds. Comparisons other than == and != use VF.
+    fn skip_unless(&mut self, cond: Cond) -> Result<(), AsmError> {
+        let x = |x: u8| (x as u16) << 8;
+
+        match cond {
+            Cond::Eq(r, Rhs::Imm(n)) => self.emit(0x4000 | x(r) | n as u16),
+            Cond::Eq(r, Rhs::Reg(y)) => self.emit(0x9000 | x(r) | (y as u16) << 4),
+            Cond::Ne(r, Rhs::Imm(n)) => self.emit(0x3000 | x(r) | n as u16),
+            Cond::Ne(r, Rhs::Reg(y)) => self.emit(0x5000 | x(r) | (y as u16) << 4),
+            Cond::Key(r) => self.emit(0xE0A1 | x(r)),
+            Cond::NotKey(r) => self.emit(0xE09E | x(r)),
+            Cond::Ge(r, rhs) => {
+                self.greater_or_equal(r, rhs, false)?;
+                self.emit(0x3F00)
+            },
+            Cond::Lt(r, rhs) => {
+                self.greater_or_equal(r, rhs, false)?;
+                self.emit(0x4F00)
+            },
+            Cond::Le(r, rhs) => {
+                self.greater_or_equal(r, rhs, true)?;
+                self.emit(0x3F00)
+            },
+            Cond::Gt(r, rhs) => {
+                self.greater_or_equal(r, rhs, true)?;
+                self.emit(0x4F00)
+            },
+        }
+    }
+
+    /// Sets VF to 1 if VX >= rhs, or if `swap` is set, rhs >= VX.
+    fn greater_or_equal(&mut self, x: u8, rhs: Rhs, swap: bool) -> Result<(), AsmError> {
+        let x = x as u16;
+
+        match (rhs, swap) {
+            // vf := x ; vf -= y
+            (Rhs::Reg(y), false) => {
+                self.emit(0x8F00 | x << 4)?;
+                self.emit(0x8F05 | (y as u16) << 4)
+            },
+            // vf := n ; vf =- x
+            (Rhs::Imm(n), false) => {
+                self.emit(0x6F00 | n as u16)?;
+                self.emit(0x8F07 | x << 4)
+            },
+            // vf := y ; vf -= x
+            (Rhs::Reg(y), true) => {
+                self.emit(0x8F00 | (y as u16) << 4)?;
+                self.emit(0x8F05 | x << 4)
+            },
+            // vf := n ; vf -= x
+            (Rhs::Imm(n), true) => {
+                self.emit(0x6F00 | n as u16)?;
+                self.emit(0x8F05 | x << 4)
+            },
+        }
+    }
+
+    /// Reads `:macro name params { body }`.
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+
+        loop {
+            let token = self.next()?;
+
+            if token == "{" {
+                break;
+            }
+
+            params.push(token);
+        }
+
+        let body = self.braced()?;
+
+        self.macros.insert(name, Macro { params, body });
+        Ok(())
+    }
+
+    /// Reads tokens up to the `}` matching a `{` just read.
+    fn braced(&mut self) -> Result<Vec<Token>, AsmError> {
+        let mut depth = 1;
+        let mut body = Vec::new();
+
+        loop {
+            let text = self.next()?;
+
+            match text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => (),
+            }
+
+            if depth == 0 {
+                return Ok(body);
+            }
+
+            body.push(Token { text, line: self.line });
+        }
+    }
+
+    /// Replaces a macro invocation with the body of the macro, with its
+    /// parameters substituted. The expansion takes the line of the
+    /// invocation so errors point at it.
+    fn expand(&mut self, name: &str) -> Result<(), AsmError> {
+        let line = self.line;
+        let mac = self.macros[name].clone();
+        let mut args = HashMap::new();
+
+        for param in mac.params.iter() {
+            args.insert(param.clone(), self.next()?);
+        }
+
+        self.expansions += 1;
+
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.error(format!("Macro '{}' expands forever", name)));
+        }
+
+        let body = mac.body.iter().map(|t| Token {
+            text: args.get(&t.text).cloned().unwrap_or_else(|| t.text.clone()),
+            line,
+        });
+
+        self.tokens.splice(self.pos..self.pos, body);
+        Ok(())
+    }
+
+    /// Evaluates `{ expression }` for `:calc` and friends.
+    ///
+    /// As in Octo, operators have no precedence and are evaluated right
+    /// to left, so `2 * 3 + 1` is 8. Parentheses group.
+    fn calc(&mut self) -> Result<f64, AsmError> {
+        self.expect("{")?;
+
+        let body = self.braced()?;
+        let mut words = Vec::new();
+
+        // Parentheses may be written against their contents.
+        for token in body.iter() {
+            let mut word = String::new();
+
+            for c in token.text.chars() {
+                if c == '(' || c == ')' {
+                    if !word.is_empty() {
+                        words.push(std::mem::take(&mut word));
+                    }
+
+                    words.push(c.to_string());
+                } else {
+                    word.push(c);
+                }
+            }
+
+            if !word.is_empty() {
+                words.push(word);
+            }
+        }
+
+        let mut pos = 0;
+        let value = self.expression(&words, &mut pos)?;
+
+        if pos != words.len() {
+            return Err(self.error(format!("Unexpected '{}' in expression", words[pos])));
+        }
+
+        Ok(value)
+    }
+
+    fn expression(&self, words: &[String], pos: &mut usize) -> Result<f64, AsmError> {
+        let lhs = self.term(words, pos)?;
+
+        let op = match words.get(*pos) {
+            Some(op) if op != ")" => op.clone(),
+            _ => return Ok(lhs),
+        };
+
+        *pos += 1;
+
+        let rhs = self.expression(words, pos)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+
+        let value = match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << (b & 63)) as f64,
+            ">>" => (a >> (b & 63)) as f64,
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return Err(self.error(format!("Unknown operator '{}' in expression", op))),
+        };
+
+        Ok(value)
+    }
+
+    fn term(&self, words: &[String], pos: &mut usize) -> Result<f64, AsmError> {
+        let word = words.get(*pos).ok_or_else(|| self.error("Incomplete expression".to_string()))?.clone();
+
+        *pos += 1;
+
+        let unary = |f: fn(f64) -> f64, pos: &mut usize| self.term(words, pos).map(f);
+
+        match word.as_str() {
+            "(" => {
+                let value = self.expression(words, pos)?;
+
+                match words.get(*pos) {
+                    Some(close) if close == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    },
+                    _ => Err(self.error("Missing ')' in expression".to_string())),
+                }
+            },
+            "-" => unary(|v| -v, pos),
+            "~" => unary(|v| !(v as i64) as f64, pos),
+            "!" => unary(|v| (v == 0.0) as i64 as f64, pos),
+            "sin" => unary(f64::sin, pos),
+            "cos" => unary(f64::cos, pos),
+            "tan" => unary(f64::tan, pos),
+            "exp" => unary(f64::exp, pos),
+            "log" => unary(f64::ln, pos),
+            "abs" => unary(f64::abs, pos),
+            "sqrt" => unary(f64::sqrt, pos),
+            "sign" => unary(f64::signum, pos),
+            "ceil" => unary(f64::ceil, pos),
+            "floor" => unary(f64::floor, pos),
+            "@" => {
+                let addr = self.term(words, pos)? as usize;
+
+                Ok(addr.checked_sub(BASE).and_then(|n| self.rom.get(n)).copied().unwrap_or(0) as f64)
+            },
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => {
+                if let Some(n) = parse_number(&word) {
+                    Ok(n as f64)
+                } else if let Some(&value) = self.constants.get(&word) {
+                    Ok(value)
+                } else if let Some(&addr) = self.labels.get(&word) {
+                    Ok(addr as f64)
+                } else if let Ok(n) = word.parse::<f64>() {
+                    Ok(n)
+                } else {
+                    Err(self.error(format!("Undefined name '{}' in expression", word)))
+                }
+            },
+        }
+    }
+
+    /// Defines a label at the current address.
+    fn define_label(&mut self, name: String) -> Result<(), AsmError> {
+        if self.labels.contains_key(&name) {
+            return Err(self.error(format!("Label '{}' is already defined", name)));
+        }
+
+        self.labels.insert(name, self.here as u16);
+        Ok(())
+    }
+
+    /// Reads a name for a label, constant, alias or macro.
+    fn name(&mut self) -> Result<String, AsmError> {
+        let token = self.next()?;
+
+        if is_name(&token) && self.reg(&token).is_none() {
+            Ok(token)
+        } else {
+            Err(self.error(format!("'{}' can't be used as a name", token)))
+        }
+    }
+
+    /// Returns the register a token names, `v0`-`vf` or an alias.
+    fn reg(&self, token: &str) -> Option<u8> {
+        if let Some(&reg) = self.aliases.get(token) {
+            return Some(reg);
+        }
+
+        let mut chars = token.chars();
+
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(c), None) | (Some('V'), Some(c), None) => c.to_digit(16).map(|n| n as u8),
+            _ => None,
+        }
+    }
+
+    /// Reads a register.
+    fn register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+
+        self.reg(&token).ok_or_else(|| self.error(format!("Expected a register, found '{}'", token)))
+    }
+
+    /// Reads either a register, or a value.
+    fn rhs_token(&mut self) -> Result<Result<u8, Value>, AsmError> {
+        match self.peek().and_then(|t| self.reg(t)) {
+            Some(reg) => {
+                self.next()?;
+                Ok(Ok(reg))
+            },
+            None => Ok(Err(self.value()?)),
+        }
+    }
+
+    /// Reads a number, constant or label, which may not be defined yet.
+    fn value(&mut self) -> Result<Value, AsmError> {
+        let token = self.next()?;
+
+        if let Some(n) = parse_number(&token) {
+            Ok(Value::Known(n))
+        } else if let Some(&value) = self.constants.get(&token) {
+            Ok(Value::Known(value as i64))
+        } else if let Some(&addr) = self.labels.get(&token) {
+            Ok(Value::Known(addr as i64))
+        } else if is_name(&token) && self.reg(&token).is_none() {
+            Ok(Value::Forward(token))
+        } else {
+            Err(self.error(format!("Expected a value, found '{}'", token)))
+        }
+    }
+
+    /// Reads a value that must already be known.
+    fn known(&mut self) -> Result<i64, AsmError> {
+        match self.value()? {
+            Value::Known(n) => Ok(n),
+            Value::Forward(name) => Err(self.error(format!("Undefined name '{}'", name))),
+        }
+    }
+
+    /// Reads a number from 0 to 15.
+    fn nibble(&mut self) -> Result<u8, AsmError> {
+        let n = self.known()?;
+
+        if (0..16).contains(&n) {
+            Ok(n as u8)
+        } else {
+            Err(self.error(format!("{} doesn't fit in 4 bits", n)))
+        }
+    }
+
+    /// Checks that a number fits in a byte, allowing negative numbers.
+    fn byte(&self, n: i64) -> Result<u8, AsmError> {
+        if (-128..256).contains(&n) {
+            Ok(n as u8)
+        } else {
+            Err(self.error(format!("{} doesn't fit in a byte", n)))
+        }
+    }
+
+    /// Emits an opcode with a register in X.
+    fn reg_op(&mut self, op: u16) -> Result<(), AsmError> {
+        let x = self.register()?;
+
+        self.emit(op | (x as u16) << 8)
+    }
+
+    /// Emits an opcode with an address in NNN.
+    fn addr_op(&mut self, op: u16) -> Result<(), AsmError> {
+        let line = self.line;
+        let value = self.value()?;
+
+        self.emit_value(&[(op >> 8) as u8, 0], value, Patch::Addr, 0, line)
+    }
+
+    /// Emits a single opcode.
+    fn emit(&mut self, op: u16) -> Result<(), AsmError> {
+        let line = self.line;
+
+        self.emit_bytes(&op.to_be_bytes(), line)
+    }
+
+    /// Emits bytes with a value patched in at `offset`, now if it's known
+    /// or once its label is defined.
+    fn emit_value(&mut self, bytes: &[u8], value: Value, patch: Patch, offset: usize, line: usize) -> Result<(), AsmError> {
+        let addr = self.here + offset;
+
+        self.emit_bytes(bytes, line)?;
+
+        match value {
+            Value::Known(n) => self.patch(addr, n, patch),
+            Value::Forward(name) => {
+                self.fixups.push(Fixup { addr, name, patch, line });
+                Ok(())
+            },
+        }
+    }
+
+    /// Writes bytes at the current address.
+    fn emit_bytes(&mut self, bytes: &[u8], line: usize) -> Result<(), AsmError> {
+        // `:next` labels the operand byte, for self-modifying code.
+        if let Some(name) = self.next.take() {
+            if self.labels.contains_key(&name) {
+                return Err(self.error(format!("Label '{}' is already defined", name)));
+            }
+
+            if self.here + 1 >= 0x10000 {
+                return Err(AsmError { line, message: format!(":next {} is past the end of memory", name) });
+            }
+
+            self.labels.insert(name, self.here as u16 + 1);
+        }
+
+        let start = self.here - BASE;
+        let end = start + bytes.len();
+
+        if self.here + bytes.len() > 0x10000 {
+            return Err(AsmError { line, message: "Program doesn't fit in memory".to_string() });
+        }
+
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+
+        self.rom[start..end].copy_from_slice(bytes);
+        self.lines.insert(self.here as u16, line);
+        self.here += bytes.len();
+
+        Ok(())
+    }
+
+    /// Writes a value into bytes already emitted.
+    fn patch(&mut self, addr: usize, value: i64, patch: Patch) -> Result<(), AsmError> {
+        let n = addr - BASE;
+
+        match patch {
+            Patch::Addr => {
+                if !(0..0x1000).contains(&value) {
+                    return Err(self.error(format!("Address {:#X} doesn't fit in 12 bits", value)));
+                }
+
+                self.rom[n] = self.rom[n] & 0xF0 | (value >> 8) as u8;
+                self.rom[n + 1] = value as u8;
+            },
+            Patch::Long => {
+                if !(0..0x10000).contains(&value) {
+                    return Err(self.error(format!("Address {:#X} doesn't fit in 16 bits", value)));
+                }
+
+                self.rom[n] = (value >> 8) as u8;
+                self.rom[n + 1] = value as u8;
+            },
+            Patch::Byte => self.rom[n] = self.byte(value)?,
+            Patch::Unpack(nibble) => self.rom[n] = nibble << 4 | (value >> 8) as u8 & 0xF,
+            Patch::Low => self.rom[n] = value as u8,
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the condition that holds when another doesn't.
+fn negate(cond: Cond) -> Cond {
+    match cond {
+        Cond::Eq(x, rhs) => Cond::Ne(x, rhs),
+        Cond::Ne(x, rhs) => Cond::Eq(x, rhs),
+        Cond::Lt(x, rhs) => Cond::Ge(x, rhs),
+        Cond::Ge(x, rhs) => Cond::Lt(x, rhs),
+        Cond::Gt(x, rhs) => Cond::Le(x, rhs),
+        Cond::Le(x, rhs) => Cond::Gt(x, rhs),
+        Cond::Key(x) => Cond::NotKey(x),
+        Cond::NotKey(x) => Cond::Key(x),
+    }
+}
+
+/// Parses a number in decimal, hex (`0x1F`, `#1F` or `$1F`) or binary
+/// (`0b101`), with an optional minus sign. This is the syntax for numbers
+/// everywhere: in source, debugger conditions and DAP variables. `#`
+/// starts a comment in source, so it only ever reaches here from the
+/// debuggers.
+pub fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))
+        .or_else(|| s.strip_prefix('#'))
+        .or_else(|| s.strip_prefix('$'));
+
+    let n = if let Some(hex) = hex {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if s.bytes().all(|b| b.is_ascii_digit()) && !s.is_empty() {
+        s.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -n } else { n })
+}
+
+/// True if a token can name a label, constant, alias or macro: anything
+/// that isn't a number, a directive, a keyword or punctuation.
+fn is_name(s: &str) -> bool {
+    const KEYWORDS: [&str; 41] = [
+        "return", "clear", "hires", "lores", "exit", "scroll-left", "scroll-right", "scroll-down",
+        "scroll-up", "audio", "plane", "bcd", "saveflags", "loadflags", "save", "load", "sprite",
+        "jump", "jump0", "native", "delay", "buzzer", "pitch", "i", "if", "then", "begin", "else",
+        "end", "loop", "while", "again", "key", "-key", "hex", "bighex", "long", "random", "HERE",
+        "PI", "E",
+    ];
+
+    let first = match s.chars().next() {
+        Some(c) => c,
+        None => return false,
+    };
+
+    (first.is_alphabetic() || first == '_')
+        && !KEYWORDS.contains(&s)
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_assembles_to_known_bytes() {
+        let source = "\
+: main
+    v0 := 5
+    i := ball
+    sprite v0 v1 3
+    loop again
+: ball
+    0x80 0x40 0x20
+";
+        let assembly = assemble(source).unwrap();
+
+        assert_eq!(assembly.rom, [0x60, 0x05, 0xA2, 0x08, 0xD0, 0x13, 0x12, 0x06, 0x80, 0x40, 0x20]);
+        assert_eq!(assembly.labels["main"], 0x200);
+        assert_eq!(assembly.labels["ball"], 0x208);
+        assert_eq!(assembly.addr(5), Some(0x206));
+    }
+
+    #[test]
+    fn numbers_take_every_prefix() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("-42"), Some(-42));
+        assert_eq!(parse_number("0x1f"), Some(0x1F));
+        assert_eq!(parse_number("#1F"), Some(0x1F));
+        assert_eq!(parse_number("$1f"), Some(0x1F));
+        assert_eq!(parse_number("0b101"), Some(5));
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("+1"), None);
+        assert_eq!(parse_number("0xg"), None);
+    }
+
+    #[test]
+    fn main_after_data_is_jumped_to() {
+        let assembly = assemble(": ball 0xF0\n: main i := ball\n").unwrap();
+
+        assert_eq!(assembly.rom, [0x12, 0x03, 0xF0, 0xA2, 0x02]);
+    }
+
+    #[test]
+    fn next_labels_the_operand_byte() {
+        let assembly = assemble(": main\n    :next target\n    v3 := 0\n    i := target\n").unwrap();
+
+        assert_eq!(assembly.rom, [0x63, 0x00, 0xA2, 0x01]);
+        assert_eq!(assembly.labels["target"], 0x201);
+    }
+
+    #[test]
+    fn next_at_the_end_of_memory_is_an_error() {
+        let error = assemble(": main\n:org 0xFFFF\n:next x\n0x12\n").unwrap_err();
+
+        assert_eq!(error.line, 4);
+    }
+
+    #[test]
+    fn errors_give_their_line() {
+        let error = assemble(": main\n    v0 := 1\n    jump nowhere\n").unwrap_err();
+
+        assert_eq!(error, AsmError { line: 3, message: "Undefined name 'nowhere'".to_string() });
+    }
+
+    #[test]
+    fn loops_past_12_bits_are_errors() {
+        let error = assemble(": main\n:org 0x1000\nloop\n    v0 += 1\nagain\n").unwrap_err();
+
+        assert_eq!(error, AsmError { line: 5, message: "Address 0x1000 doesn't fit in 12 bits".to_string() });
+    }
+}
@@ -0,0 +1,54 @@
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of time for the VM. Only differences between two readings
+/// are meaningful.
+pub trait Clock: Debug {
+    /// Returns the current time in nanoseconds.
+    fn now(&self) -> i64;
+}
+
+/// Wall time from the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// A virtual clock that only moves when it is told to. Clones share the
+/// same time, so a handle can be kept to advance a clock given to a VM.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+    now: Rc<Cell<i64>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as i64)
+            .unwrap_or(0)
+    }
+}
+
+impl ManualClock {
+    /// Creates a clock that starts at 0 ns.
+    pub fn new() -> ManualClock {
+        ManualClock::default()
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, ns: i64) {
+        self.now.set(self.now.get() + ns);
+    }
+
+    /// Sets the clock to an exact time.
+    pub fn set(&self, ns: i64) {
+        self.now.set(ns);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        self.now.get()
+    }
+}
@@ -0,0 +1,814 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::vm::asm::{assemble, parse_number, Assembly};
+use crate::vm::debug::{Action, Condition, Debugger, Operand, Stop};
+use crate::vm::disasm::Disassembly;
+use crate::vm::error::ChipperError;
+use crate::vm::json::Json;
+use crate::vm::platform::Platform;
+use crate::vm::vm::load_rom;
+
+/// The only thread there is.
+const THREAD: i64 = 1;
+
+/// The variables reference of the register scope.
+const REGISTERS: i64 = 1;
+
+/// Names of the registers shown as variables, after V0-VF.
+const SPECIAL: [&str; 5] = ["I", "PC", "SP", "DT", "ST"];
+
+/// A Debug Adapter Protocol server, which lets editors launch and debug
+/// ROMs. Messages are read from one stream and written to another,
+/// normally stdin and stdout.
+///
+/// Breakpoints can be set on lines of Octo source, when the program is
+/// an `.8o` file, or on instruction addresses. The registers are shown
+/// as variables and the return addresses on the stack as the call stack.
+pub struct DapServer<W: Write> {
+    /// Where responses and events are written.
+    out: W,
+
+    /// The sequence number of the last message sent.
+    seq: i64,
+
+    /// The program being debugged, once launched.
+    session: Option<Session>,
+
+    /// Set once the client has sent all of its configuration.
+    configured: bool,
+
+    /// True while the program is running.
+    running: bool,
+}
+
+/// A launched program.
+struct Session {
+    debugger: Debugger,
+
+    /// Path to the ROM or source file.
+    path: String,
+
+    /// The assembled source, if the program was an `.8o` file.
+    assembly: Option<Assembly>,
+
+    /// Used to name frames when there's no source.
+    disassembly: Disassembly,
+
+    /// Stop as soon as the program is configured rather than run.
+    stop_on_entry: bool,
+
+    /// Breakpoints set on source lines, by address.
+    source_breakpoints: Vec<(usize, Option<Condition>)>,
+
+    /// Breakpoints set on instruction addresses.
+    instruction_breakpoints: Vec<(usize, Option<Condition>)>,
+}
+
+impl<W: Write> DapServer<W> {
+    /// Creates a server writing to a stream.
+    pub fn new(out: W) -> DapServer<W> {
+        DapServer { out, seq: 0, session: None, configured: false, running: false }
+    }
+
+    /// Handles requests from a stream until the client disconnects. The
+    /// stream is read on another thread, so requests such as pause are
+    /// handled while the program runs.
+    pub fn serve<R: BufRead + Send + 'static>(&mut self, input: R) -> io::Result<()> {
+        let rx = spawn_reader(input);
+        let frame = Duration::from_nanos(1_000_000_000 / 60);
+        let mut next = Instant::now();
+
+        loop {
+            let message = if self.running {
+                match rx.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => break,
+                }
+            };
+
+            match message {
+                Some(Ok(text)) => {
+                    if !self.message(&text)? {
+                        break;
+                    }
+
+                    next = Instant::now();
+                },
+                Some(Err(err)) => return Err(err),
+                None => (),
+            }
+
+            if self.running {
+                self.run(None)?;
+
+                // Run at the speed of the program rather than flat out.
+                next += frame;
+
+                match next.checked_duration_since(Instant::now()) {
+                    Some(wait) => thread::sleep(wait),
+                    None => next = Instant::now(),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a message, returning false once the session is over.
+    fn message(&mut self, text: &str) -> io::Result<bool> {
+        let request: Json = match text.parse() {
+            Ok(request) => request,
+            Err(_) => return Ok(true),
+        };
+
+        if request.get("type").and_then(Json::as_str) != Some("request") {
+            return Ok(true);
+        }
+
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("").to_string();
+        let args = request.get("arguments").cloned().unwrap_or(Json::Null);
+
+        let result = match command.as_str() {
+            "initialize" => {
+                self.respond(&request, Ok(capabilities()))?;
+                self.event("initialized", Json::object(vec![]))?;
+                return Ok(true);
+            },
+            "launch" => {
+                let result = self.launch(&args);
+
+                self.respond(&request, result)?;
+                self.start()?;
+                return Ok(true);
+            },
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(&request, Ok(Json::Null))?;
+                self.start()?;
+                return Ok(true);
+            },
+            "setBreakpoints" => self.set_breakpoints(&args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(&args),
+            "setExceptionBreakpoints" => Ok(Json::object(vec![("breakpoints", Json::Array(vec![]))])),
+            "threads" => Ok(Json::object(vec![(
+                "threads",
+                Json::Array(vec![Json::object(vec![("id", THREAD.into()), ("name", "CHIP-8".into())])]),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(Json::object(vec![(
+                "scopes",
+                Json::Array(vec![Json::object(vec![
+                    ("name", "Registers".into()),
+                    ("variablesReference", REGISTERS.into()),
+                    ("expensive", false.into()),
+                ])]),
+            )])),
+            "variables" => self.variables(&args),
+            "setVariable" => self.set_variable(&args),
+            "evaluate" => self.evaluate(&args),
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let action = match command.as_str() {
+                    "continue" => Action::Continue,
+                    "next" => Action::StepOver,
+                    "stepIn" => Action::Step,
+                    _ => Action::StepOut,
+                };
+
+                if self.session.is_none() {
+                    Err("The program hasn't been launched".to_string())
+                } else {
+                    self.respond(&request, Ok(Json::object(vec![("allThreadsContinued", true.into())])))?;
+                    self.run(Some(action))?;
+                    return Ok(true);
+                }
+            },
+            "pause" => {
+                self.respond(&request, Ok(Json::Null))?;
+
+                if self.running {
+                    self.running = false;
+                    self.stopped("pause", None)?;
+                }
+
+                return Ok(true);
+            },
+            "disconnect" | "terminate" => {
+                self.respond(&request, Ok(Json::Null))?;
+
+                if command == "terminate" {
+                    self.event("terminated", Json::object(vec![]))?;
+                }
+
+                return Ok(false);
+            },
+            _ => Err(format!("Unsupported request '{}'", command)),
+        };
+
+        self.respond(&request, result)?;
+        Ok(true)
+    }
+
+    /// Loads the program named by the launch arguments.
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("program").and_then(Json::as_str).ok_or("No program given")?.to_string();
+
+        let (program, assembly) = if Path::new(&path).extension().is_some_and(|ext| ext == "8o") {
+            let source = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+            let assembly = assemble(&source).map_err(|e| format!("{}:{}: {}", path, e.line, e.message))?;
+
+            (assembly.rom.clone(), Some(assembly))
+        } else {
+            (fs::read(&path).map_err(|e| format!("{}: {}", path, e))?, None)
+        };
+
+        let platform = match args.get("platform").and_then(Json::as_str) {
+            Some(name) => name.parse().map_err(|e| format!("{}", e))?,
+            None => Platform::detect(&program),
+        };
+
+        let disassembly = Disassembly::new(&program, platform);
+        let mut vm = load_rom(program, platform).map_err(|e| format!("{}: {}", path, e))?;
+
+        if let Some(changes) = args.get("quirks").and_then(Json::as_str) {
+            let mut quirks = *vm.quirks();
+
+            quirks.apply(changes).map_err(|e| format!("{}", e))?;
+            vm.set_quirks(quirks);
+        }
+
+        if let Some(speed) = args.get("speed").and_then(Json::as_i64) {
+            if speed < 1 {
+                return Err(format!("Speed {} is too slow, it must be at least 1", speed));
+            }
+
+            // Below 60 instructions a second, a frame still runs one.
+            vm.set_speed(speed);
+            vm.set_cycles_per_frame((speed / 60).max(1));
+        }
+
+        self.session = Some(Session {
+            debugger: Debugger::new(vm),
+            path,
+            assembly,
+            disassembly,
+            stop_on_entry: args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false),
+            source_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+        });
+
+        Ok(Json::Null)
+    }
+
+    /// Starts the program once it has been both launched and configured.
+    fn start(&mut self) -> io::Result<()> {
+        let stop_on_entry = match &self.session {
+            Some(session) if self.configured => session.stop_on_entry,
+            _ => return Ok(()),
+        };
+
+        if stop_on_entry {
+            self.stopped("entry", None)
+        } else {
+            self.run(Some(Action::Continue))
+        }
+    }
+
+    /// Starts an action, or carries on with the one running, for a
+    /// frame's worth of instructions, then reports why it stopped.
+    fn run(&mut self, action: Option<Action>) -> io::Result<()> {
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+
+        let limit = session.debugger.vm().cycles_per_frame().max(1) as u64;
+
+        let result = match action {
+            Some(action) => session.debugger.run(action, limit),
+            None => session.debugger.resume(limit),
+        };
+
+        self.running = result == Ok(Stop::Limit);
+
+        match result {
+            Ok(Stop::Limit) => Ok(()),
+            Ok(Stop::Done) => self.stopped("step", None),
+            Ok(Stop::Breakpoint(_)) => self.stopped("breakpoint", None),
+            Ok(Stop::Watchpoint { .. }) => self.stopped("data breakpoint", None),
+            Ok(Stop::Exited) => {
+                self.event("exited", Json::object(vec![("exitCode", 0i64.into())]))?;
+                self.event("terminated", Json::object(vec![]))
+            },
+            Err(err) => self.stopped("exception", Some(err)),
+        }
+    }
+
+    /// Sends a stopped event.
+    fn stopped(&mut self, reason: &str, err: Option<ChipperError>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+
+        if let Some(err) = err {
+            body.push(("description", err.to_string().into()));
+            body.push(("text", err.to_string().into()));
+        }
+
+        self.event("stopped", Json::object(body))
+    }
+
+    /// Replaces the breakpoints set on source lines.
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_mut().ok_or("The program hasn't been launched")?;
+        let requested = args.get("breakpoints").and_then(Json::as_array).unwrap_or(&[]);
+        let mut replies = Vec::new();
+
+        session.source_breakpoints.clear();
+
+        for bp in requested {
+            let line = bp.get("line").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+            let addr = session.assembly.as_ref().and_then(|a| a.addr(line));
+
+            let reply = match (addr, parse_condition(bp)) {
+                (Some(addr), Ok(condition)) => {
+                    session.source_breakpoints.push((addr as usize, condition));
+
+                    Json::object(vec![
+                        ("verified", true.into()),
+                        ("line", session.line(addr as usize).unwrap_or(line).into()),
+                        ("instructionReference", format!("0x{:03X}", addr).into()),
+                    ])
+                },
+                (None, _) => unverified(line, "No code on or after this line"),
+                (_, Err(message)) => unverified(line, &message),
+            };
+
+            replies.push(reply);
+        }
+
+        session.apply_breakpoints();
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(replies))]))
+    }
+
+    /// Replaces the breakpoints set on instruction addresses.
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_mut().ok_or("The program hasn't been launched")?;
+        let requested = args.get("breakpoints").and_then(Json::as_array).unwrap_or(&[]);
+        let mut replies = Vec::new();
+
+        session.instruction_breakpoints.clear();
+
+        for bp in requested {
+            let reference = bp.get("instructionReference").and_then(Json::as_str).and_then(parse_number);
+            let offset = bp.get("offset").and_then(Json::as_i64).unwrap_or(0);
+            let addr = reference
+                .and_then(|r| r.checked_add(offset))
+                .filter(|&addr| addr >= 0)
+                .map(|addr| addr as usize);
+
+            let reply = match (addr, parse_condition(bp)) {
+                (Some(addr), Ok(condition)) => {
+                    session.instruction_breakpoints.push((addr, condition));
+
+                    let mut reply = vec![
+                        ("verified", true.into()),
+                        ("instructionReference", format!("0x{:03X}", addr).into()),
+                    ];
+
+                    if let Some(line) = session.line(addr) {
+                        reply.push(("line", line.into()));
+                    }
+
+                    Json::object(reply)
+                },
+                (None, _) => unverified(0, "Not an address"),
+                (_, Err(message)) => unverified(0, &message),
+            };
+
+            replies.push(reply);
+        }
+
+        session.apply_breakpoints();
+
+        Ok(Json::object(vec![("breakpoints", Json::Array(replies))]))
+    }
+
+    /// Returns the frame being executed, then a frame for each call on
+    /// the stack.
+    fn stack_trace(&self) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("The program hasn't been launched")?;
+        let vm = session.debugger.vm();
+        let mut addrs = vec![vm.pc()];
+
+        // Each return address is just past the call that pushed it.
+        addrs.extend(vm.stack().iter().rev().map(|ret| ret.wrapping_sub(2)));
+
+        let frames: Vec<Json> = addrs.iter().enumerate().map(|(id, &addr)| {
+            let mut frame = vec![
+                ("id", id.into()),
+                ("name", format!("{} ({:03X})", session.name(addr), addr).into()),
+                ("line", session.line(addr).unwrap_or(0).into()),
+                ("column", 1i64.into()),
+                ("instructionPointerReference", format!("0x{:03X}", addr).into()),
+            ];
+
+            if session.assembly.is_some() {
+                frame.push(("source", source(&session.path)));
+            }
+
+            Json::object(frame)
+        }).collect();
+
+        Ok(Json::object(vec![("totalFrames", frames.len().into()), ("stackFrames", Json::Array(frames))]))
+    }
+
+    /// Returns the registers.
+    fn variables(&self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("The program hasn't been launched")?;
+
+        if args.get("variablesReference").and_then(Json::as_i64) != Some(REGISTERS) {
+            return Ok(Json::object(vec![("variables", Json::Array(vec![]))]));
+        }
+
+        let names = (0..16).map(|n| format!("V{:X}", n)).chain(SPECIAL.iter().map(|s| s.to_string()));
+
+        let variables = names.map(|name| {
+            let value = session.register(&name).unwrap_or(0);
+
+            Json::object(vec![
+                ("value", format_value(&name, value).into()),
+                ("name", name.into()),
+                ("variablesReference", 0i64.into()),
+            ])
+        }).collect();
+
+        Ok(Json::object(vec![("variables", Json::Array(variables))]))
+    }
+
+    /// Changes a register.
+    fn set_variable(&mut self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_mut().ok_or("The program hasn't been launched")?;
+        let name = args.get("name").and_then(Json::as_str).unwrap_or("");
+        let text = args.get("value").and_then(Json::as_str).unwrap_or("");
+        let value = parse_number(text.trim()).filter(|&n| n >= 0).ok_or(format!("'{}' is not a number", text))? as usize;
+        let vm = session.debugger.vm_mut();
+
+        match name {
+            "I" => vm.registers_mut().i = value & (vm.memory().len() - 1),
+            "PC" => vm.set_pc(value),
+            "DT" => vm.registers_mut().dt = value as u8,
+            "ST" => vm.registers_mut().st = value as u8,
+            _ => match name.strip_prefix('V').and_then(|x| usize::from_str_radix(x, 16).ok()) {
+                Some(x) if x < 16 => vm.registers_mut().v[x] = value as u8,
+                _ => return Err(format!("{} can't be changed", name)),
+            },
+        }
+
+        let value = session.register(name).unwrap_or(0);
+
+        Ok(Json::object(vec![("value", format_value(name, value).into())]))
+    }
+
+    /// Evaluates a register name or a memory address in brackets, such
+    /// as `v3` or `[0x300]`, for hovers and the watch window.
+    fn evaluate(&self, args: &Json) -> Result<Json, String> {
+        let session = self.session.as_ref().ok_or("The program hasn't been launched")?;
+        let expression = args.get("expression").and_then(Json::as_str).unwrap_or("").trim();
+
+        let value = match expression.to_ascii_uppercase().as_str() {
+            "PC" => format_value("PC", session.debugger.vm().pc()),
+            "SP" => format_value("SP", session.debugger.vm().stack().len()),
+            _ => {
+                let operand: Operand = expression.parse().map_err(|_| format!("Can't evaluate '{}'", expression))?;
+                let vm = session.debugger.vm();
+                let regs = vm.registers();
+
+                match operand {
+                    Operand::V(x) => format_value("V", regs.v[x as usize] as usize),
+                    Operand::I => format_value("I", regs.i),
+                    Operand::Dt => format_value("DT", regs.dt as usize),
+                    Operand::St => format_value("ST", regs.st as usize),
+                    Operand::Memory(addr) => format_value("V", vm.memory().get(addr).copied().unwrap_or(0) as usize),
+                }
+            },
+        };
+
+        Ok(Json::object(vec![("result", value.into()), ("variablesReference", 0i64.into())]))
+    }
+
+    /// Sends the response to a request, or an error response.
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) -> io::Result<()> {
+        let mut response = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+
+        match result {
+            Ok(body) => {
+                response.push(("success", true.into()));
+
+                if body != Json::Null {
+                    response.push(("body", body));
+                }
+            },
+            Err(message) => {
+                response.push(("success", false.into()));
+                response.push(("message", message.into()));
+            },
+        }
+
+        self.send(response)
+    }
+
+    /// Sends an event.
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![("type", "event".into()), ("event", event.into()), ("body", body)])
+    }
+
+    /// Sends a message, numbering it.
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.push(("seq", self.seq.into()));
+
+        let text = Json::object(fields).to_string();
+
+        write!(self.out, "Content-Length: {}\r\n\r\n{}", text.len(), text)?;
+        self.out.flush()
+    }
+}
+
+impl Session {
+    /// Sets the debugger's breakpoints to those on lines and addresses.
+    fn apply_breakpoints(&mut self) {
+        self.debugger.clear_breakpoints();
+
+        for &(addr, condition) in self.source_breakpoints.iter().chain(self.instruction_breakpoints.iter()) {
+            self.debugger.set_breakpoint(addr, condition);
+        }
+    }
+
+    /// Returns the source line of an address.
+    fn line(&self, addr: usize) -> Option<usize> {
+        self.assembly.as_ref().and_then(|a| a.line(addr as u16))
+    }
+
+    /// Returns the name of the label an address comes after, from the
+    /// source or the disassembly.
+    fn name(&self, addr: usize) -> String {
+        let name = match &self.assembly {
+            Some(assembly) => assembly.labels.iter()
+                .filter(|&(_, &at)| at as usize <= addr)
+                .max_by_key(|&(_, &at)| at)
+                .map(|(name, _)| name.to_string()),
+            None => (self.disassembly.base()..=addr).rev()
+                .find_map(|at| self.disassembly.label(at))
+                .map(str::to_string),
+        };
+
+        name.unwrap_or_else(|| "?".to_string())
+    }
+
+    /// Returns a register by the name shown in the variables.
+    fn register(&self, name: &str) -> Option<usize> {
+        let vm = self.debugger.vm();
+        let regs = vm.registers();
+
+        match name {
+            "I" => Some(regs.i),
+            "PC" => Some(vm.pc()),
+            "SP" => Some(vm.stack().len()),
+            "DT" => Some(regs.dt as usize),
+            "ST" => Some(regs.st as usize),
+            _ => {
+                let x = usize::from_str_radix(name.strip_prefix('V')?, 16).ok()?;
+
+                regs.v.get(x).map(|&v| v as usize)
+            },
+        }
+    }
+}
+
+/// The features of the protocol supported.
+fn capabilities() -> Json {
+    Json::object(vec![
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsConditionalBreakpoints", true.into()),
+        ("supportsInstructionBreakpoints", true.into()),
+        ("supportsSetVariable", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+/// Describes the source file of the program.
+fn source(path: &str) -> Json {
+    let name = Path::new(path).file_name().map_or(path.to_string(), |n| n.to_string_lossy().into_owned());
+
+    Json::object(vec![("name", name.into()), ("path", path.into())])
+}
+
+/// A breakpoint that couldn't be set.
+fn unverified(line: usize, message: &str) -> Json {
+    Json::object(vec![("verified", false.into()), ("line", line.into()), ("message", message.into())])
+}
+
+/// Parses the condition of a breakpoint, if it has one.
+fn parse_condition(bp: &Json) -> Result<Option<Condition>, String> {
+    match bp.get("condition").and_then(Json::as_str) {
+        Some(s) if !s.trim().is_empty() => s.parse().map(Some).map_err(|e| format!("{}", e)),
+        _ => Ok(None),
+    }
+}
+
+/// Formats a register value: addresses as 3 hex digits, the stack
+/// pointer in decimal and bytes as 2 hex digits.
+fn format_value(name: &str, value: usize) -> String {
+    match name {
+        "I" | "PC" => format!("0x{:03X}", value),
+        "SP" => format!("{}", value),
+        _ => format!("0x{:02X} ({})", value, value),
+    }
+}
+
+/// Reads messages on a background thread. Each is sent as the text of
+/// its JSON body; the channel closes at the end of the stream.
+fn spawn_reader<R: BufRead + Send + 'static>(mut input: R) -> Receiver<io::Result<String>> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || loop {
+        match read_message(&mut input) {
+            Ok(Some(text)) => {
+                if tx.send(Ok(text)).is_err() {
+                    return;
+                }
+            },
+            Ok(None) => return,
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                return;
+            },
+        }
+    });
+
+    rx
+}
+
+/// Reads a message: headers, a blank line, then Content-Length bytes of
+/// JSON. Returns None at the end of the stream.
+fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<String>> {
+    let mut length = None;
+
+    loop {
+        let mut header = String::new();
+
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+
+        let header = header.trim();
+
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+
+            continue;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or(0)];
+
+    input.read_exact(&mut body)?;
+
+    String::from_utf8(body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Frames requests as the client would send them.
+    fn requests(requests: &[&str]) -> Cursor<Vec<u8>> {
+        let mut input = Vec::new();
+
+        for (seq, request) in requests.iter().enumerate() {
+            let text = format!(r#"{{"seq":{},"type":"request",{}}}"#, seq + 1, request);
+
+            write!(input, "Content-Length: {}\r\n\r\n{}", text.len(), text).unwrap();
+        }
+
+        Cursor::new(input)
+    }
+
+    /// Serves requests, returning the messages sent back.
+    fn serve(input: &[&str]) -> Vec<Json> {
+        let mut server = DapServer::new(Vec::new());
+
+        server.serve(requests(input)).unwrap();
+
+        let mut output = Cursor::new(server.out);
+        let mut messages = Vec::new();
+
+        while let Some(text) = read_message(&mut output).unwrap() {
+            messages.push(text.parse().unwrap());
+        }
+
+        messages
+    }
+
+    /// Returns the response to a command.
+    fn response<'a>(messages: &'a [Json], command: &str) -> &'a Json {
+        messages.iter()
+            .find(|m| m.get("type").and_then(Json::as_str) == Some("response") && m.get("command").and_then(Json::as_str) == Some(command))
+            .unwrap()
+    }
+
+    /// Writes a program to a temporary file, returning its path.
+    fn program(name: &str, contents: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("chipper-dap-{}-{}", std::process::id(), name));
+
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().replace('\\', "/")
+    }
+
+    fn launch(path: &str, extra: &str) -> String {
+        format!(r#""command":"launch","arguments":{{"program":"{}"{}}}"#, path, extra)
+    }
+
+    #[test]
+    fn stops_at_a_source_breakpoint() {
+        let path = program("loop.8o", b": main\n    v0 := 1\n    v1 := 2\n    loop again\n");
+        let messages = serve(&[
+            r#""command":"initialize","arguments":{}"#,
+            &launch(&path, ""),
+            &format!(r#""command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":3}}]}}"#, path),
+            r#""command":"configurationDone""#,
+            r#""command":"disconnect""#,
+        ]);
+
+        let breakpoints = response(&messages, "setBreakpoints").get("body").and_then(|b| b.get("breakpoints")).and_then(Json::as_array).unwrap();
+
+        assert_eq!(breakpoints[0].get("verified").and_then(Json::as_bool), Some(true));
+        assert_eq!(breakpoints[0].get("instructionReference").and_then(Json::as_str), Some("0x202"));
+
+        let stopped = messages.iter().find(|m| m.get("event").and_then(Json::as_str) == Some("stopped")).unwrap();
+
+        assert_eq!(stopped.get("body").and_then(|b| b.get("reason")).and_then(Json::as_str), Some("breakpoint"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn negative_offsets_are_unverified() {
+        let path = program("negative.ch8", &[0x12, 0x00]);
+        let messages = serve(&[
+            &launch(&path, ""),
+            r#""command":"setInstructionBreakpoints","arguments":{"breakpoints":[
+                {"instructionReference":"0x200","offset":-1024},
+                {"instructionReference":"0x200","offset":2}
+            ]}"#,
+            r#""command":"disconnect""#,
+        ]);
+
+        let breakpoints = response(&messages, "setInstructionBreakpoints").get("body").and_then(|b| b.get("breakpoints")).and_then(Json::as_array).unwrap();
+
+        assert_eq!(breakpoints[0].get("verified").and_then(Json::as_bool), Some(false));
+        assert_eq!(breakpoints[1].get("verified").and_then(Json::as_bool), Some(true));
+        assert_eq!(breakpoints[1].get("instructionReference").and_then(Json::as_str), Some("0x202"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn slow_speeds_still_run() {
+        let path = program("slow.ch8", &[0x12, 0x00]);
+        let mut server = DapServer::new(Vec::new());
+
+        assert!(server.launch(&format!(r#"{{"program":"{}","speed":30}}"#, path).parse().unwrap()).is_ok());
+        assert_eq!(server.session.as_ref().map(|s| s.debugger.vm().cycles_per_frame()), Some(1));
+
+        let messages = serve(&[&launch(&path, r#","speed":0"#), r#""command":"disconnect""#]);
+
+        assert_eq!(response(&messages, "launch").get("success").and_then(Json::as_bool), Some(false));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,668 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+use crate::vm::asm::parse_number;
+use crate::vm::error::ChipperError;
+use crate::vm::instruction::Instruction;
+use crate::vm::vm::VM;
+
+/// A VM under the control of a debugger. Execution stops at breakpoints,
+/// when watched memory is accessed, or once a step completes, so that a
+/// frontend can inspect the VM in between.
+#[derive(Debug)]
+pub struct Debugger {
+    /// The VM being debugged.
+    vm: VM,
+
+    /// Breakpoints by address, each with an optional condition that must
+    /// hold for execution to stop.
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+
+    /// Memory ranges being watched.
+    watchpoints: Vec<Watchpoint>,
+
+    /// The action interrupted by running out of cycles, which `resume`
+    /// carries on with.
+    pending: Option<Target>,
+}
+
+/// What to run the VM until.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Execute a single instruction.
+    Step,
+
+    /// Execute a single instruction, running a 2NNN call until it
+    /// returns.
+    StepOver,
+
+    /// Run until the current subroutine returns.
+    StepOut,
+
+    /// Run until the program counter reaches an address.
+    RunTo(usize),
+
+    /// Run until a breakpoint or watchpoint.
+    Continue,
+}
+
+/// Why the VM stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The action completed.
+    Done,
+
+    /// The program counter reached a breakpoint.
+    Breakpoint(usize),
+
+    /// The instruction at `pc` accessed watched memory at `addr`. The
+    /// instruction has already executed.
+    Watchpoint { pc: usize, addr: usize, access: Access },
+
+    /// The program exited.
+    Exited,
+
+    /// The cycle limit ran out first. `resume` carries on.
+    Limit,
+}
+
+/// A kind of memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+
+    /// Either reads or writes, for watchpoints.
+    Any,
+}
+
+/// A range of memory being watched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// The addresses watched.
+    pub range: Range<usize>,
+
+    /// The accesses that stop execution.
+    pub access: Access,
+}
+
+/// A condition on the state of the VM, such as `v3 == 5` or
+/// `[0x300] != 0`, that must hold for a breakpoint to stop execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    /// What is compared.
+    pub operand: Operand,
+
+    /// How it is compared.
+    pub compare: Compare,
+
+    /// What it is compared with.
+    pub value: usize,
+}
+
+/// A value a condition can compare.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    /// VX.
+    V(u8),
+
+    /// I.
+    I,
+
+    /// The delay timer.
+    Dt,
+
+    /// The sound timer.
+    St,
+
+    /// The byte of memory at an address.
+    Memory(usize),
+}
+
+/// A comparison.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Returned when text does not parse as a condition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCondition(pub String);
+
+/// Where an action ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    /// After one instruction.
+    Step,
+
+    /// When the program counter reaches an address with the stack no
+    /// deeper than a depth.
+    Return { pc: usize, depth: usize },
+
+    /// When the stack is shallower than a depth.
+    Out(usize),
+
+    /// When the program counter reaches an address.
+    Addr(usize),
+
+    /// Never.
+    Forever,
+}
+
+impl Debugger {
+    /// Creates a debugger for a VM, with no breakpoints or watchpoints.
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Returns the VM being debugged.
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Returns the VM being debugged, to change its state.
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Returns the VM, ending the debugging session.
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    /// Sets a breakpoint at an address, replacing any already there.
+    pub fn set_breakpoint(&mut self, addr: usize, condition: Option<Condition>) {
+        self.breakpoints.insert(addr, condition);
+    }
+
+    /// Removes the breakpoint at an address, returning true if there
+    /// was one.
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr).is_some()
+    }
+
+    /// Removes every breakpoint.
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Returns the breakpoints and their conditions, by address.
+    pub fn breakpoints(&self) -> &BTreeMap<usize, Option<Condition>> {
+        &self.breakpoints
+    }
+
+    /// Watches a range of memory for reads, writes or both.
+    pub fn add_watchpoint(&mut self, range: Range<usize>, access: Access) {
+        self.watchpoints.push(Watchpoint { range, access });
+    }
+
+    /// Removes the watchpoints covering an address, returning true if
+    /// there were any.
+    pub fn remove_watchpoint(&mut self, addr: usize) -> bool {
+        let n = self.watchpoints.len();
+
+        self.watchpoints.retain(|w| !w.range.contains(&addr));
+        self.watchpoints.len() != n
+    }
+
+    /// Removes every watchpoint.
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    /// Returns the watchpoints.
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Stop, ChipperError> {
+        self.run(Action::Step, u64::MAX)
+    }
+
+    /// Executes a single instruction, or a whole subroutine if it's a
+    /// 2NNN call.
+    pub fn step_over(&mut self) -> Result<Stop, ChipperError> {
+        self.run(Action::StepOver, u64::MAX)
+    }
+
+    /// Runs until the current subroutine returns.
+    pub fn step_out(&mut self) -> Result<Stop, ChipperError> {
+        self.run(Action::StepOut, u64::MAX)
+    }
+
+    /// Runs until the program counter reaches an address.
+    pub fn run_to(&mut self, addr: usize) -> Result<Stop, ChipperError> {
+        self.run(Action::RunTo(addr), u64::MAX)
+    }
+
+    /// Runs until a breakpoint, a watchpoint, or the program exits.
+    pub fn cont(&mut self) -> Result<Stop, ChipperError> {
+        self.run(Action::Continue, u64::MAX)
+    }
+
+    /// Starts an action, executing at most `limit` instructions. When
+    /// the limit runs out first, `Stop::Limit` is returned and `resume`
+    /// carries on with the action, which lets a frontend stay responsive
+    /// while a program runs.
+    ///
+    /// A breakpoint at the program counter is ignored for the first
+    /// instruction, so execution can continue from one.
+    pub fn run(&mut self, action: Action, limit: u64) -> Result<Stop, ChipperError> {
+        let pc = self.vm.pc();
+        let depth = self.vm.stack().len();
+
+        let target = match action {
+            Action::Step => Target::Step,
+            Action::StepOver => match self.vm.instruction(pc) {
+                Ok(inst @ Instruction::Call(_)) => Target::Return { pc: pc + inst.size(), depth },
+                _ => Target::Step,
+            },
+            Action::StepOut if depth == 0 => Target::Forever,
+            Action::StepOut => Target::Out(depth),
+            Action::RunTo(addr) => Target::Addr(addr),
+            Action::Continue => Target::Forever,
+        };
+
+        self.execute(target, limit, true)
+    }
+
+    /// Carries on with an action interrupted by the cycle limit, or
+    /// continues if there isn't one.
+    pub fn resume(&mut self, limit: u64) -> Result<Stop, ChipperError> {
+        let target = self.pending.take().unwrap_or(Target::Forever);
+
+        self.execute(target, limit, false)
+    }
+
+    /// Returns the memory accessed by an instruction if it executed now,
+    /// or None if it doesn't touch memory. Instruction fetches and font
+    /// lookups aren't included.
+    pub fn access(&self, inst: Instruction) -> Option<(Access, Range<usize>)> {
+        let i = self.vm.registers().i;
+        let span = |x: u8, y: u8| x.max(y) as usize - x.min(y) as usize + 1;
+
+        let (access, len) = match inst {
+            Instruction::Draw { n, .. } => {
+                let planes = self.vm.plane.count_ones() as usize;
+                let len = if n == 0 { 32 } else { n as usize };
+
+                (Access::Read, len * planes)
+            },
+            Instruction::Audio => (Access::Read, 16),
+            Instruction::Bcd { .. } => (Access::Write, 3),
+            Instruction::Store { x } => (Access::Write, x as usize + 1),
+            Instruction::Restore { x } => (Access::Read, x as usize + 1),
+            Instruction::SaveRange { x, y } => (Access::Write, span(x, y)),
+            Instruction::LoadRange { x, y } => (Access::Read, span(x, y)),
+            _ => return None,
+        };
+
+        if len == 0 {
+            None
+        } else {
+            Some((access, i..i + len))
+        }
+    }
+
+    /// Runs until a target is reached, something stops execution, or the
+    /// limit runs out.
+    fn execute(&mut self, target: Target, limit: u64, start: bool) -> Result<Stop, ChipperError> {
+        let mut first = start;
+
+        self.pending = None;
+
+        for _ in 0..limit {
+            if self.vm.exited() {
+                return Ok(Stop::Exited);
+            }
+
+            let pc = self.vm.pc();
+
+            if !first {
+                if self.reached(target) {
+                    return Ok(Stop::Done);
+                }
+
+                if self.breakpoint_hit(pc) {
+                    return Ok(Stop::Breakpoint(pc));
+                }
+            }
+
+            first = false;
+
+            let watched = self.watched(pc);
+
+            self.vm.step()?;
+
+            if let Some((addr, access)) = watched {
+                return Ok(Stop::Watchpoint { pc, addr, access });
+            }
+
+            if target == Target::Step {
+                return Ok(Stop::Done);
+            }
+        }
+
+        self.pending = Some(target);
+
+        Ok(Stop::Limit)
+    }
+
+    /// True once the VM has reached the end of an action.
+    fn reached(&self, target: Target) -> bool {
+        let pc = self.vm.pc();
+        let depth = self.vm.stack().len();
+
+        match target {
+            Target::Step => true,
+            Target::Return { pc: ret, depth: d } => pc == ret && depth <= d,
+            Target::Out(d) => depth < d,
+            Target::Addr(addr) => pc == addr,
+            Target::Forever => false,
+        }
+    }
+
+    /// True if there's a breakpoint at an address whose condition holds.
+    fn breakpoint_hit(&self, pc: usize) -> bool {
+        match self.breakpoints.get(&pc) {
+            Some(Some(condition)) => condition.holds(&self.vm),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Returns the first watched address the instruction at pc will
+    /// access, and how.
+    fn watched(&self, pc: usize) -> Option<(usize, Access)> {
+        let (access, range) = self.access(self.vm.instruction(pc).ok()?)?;
+
+        self.watchpoints.iter()
+            .filter(|w| w.access == Access::Any || w.access == access)
+            .filter_map(|w| {
+                let start = w.range.start.max(range.start);
+
+                if start < w.range.end.min(range.end) { Some(start) } else { None }
+            })
+            .min()
+            .map(|addr| (addr, access))
+    }
+}
+
+impl Condition {
+    /// True if the condition holds for the current state of a VM.
+    pub fn holds(&self, vm: &VM) -> bool {
+        let regs = vm.registers();
+
+        let lhs = match self.operand {
+            Operand::V(x) => regs.v[x as usize & 0xF] as usize,
+            Operand::I => regs.i,
+            Operand::Dt => regs.dt as usize,
+            Operand::St => regs.st as usize,
+            Operand::Memory(addr) => vm.memory().get(addr).copied().unwrap_or(0) as usize,
+        };
+
+        match self.compare {
+            Compare::Eq => lhs == self.value,
+            Compare::Ne => lhs != self.value,
+            Compare::Lt => lhs < self.value,
+            Compare::Le => lhs <= self.value,
+            Compare::Gt => lhs > self.value,
+            Compare::Ge => lhs >= self.value,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = InvalidCondition;
+
+    /// Parses `v0`-`vf`, `i`, `dt`, `st` or a memory address in square
+    /// brackets, such as `[0x300]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        let err = || InvalidCondition(s.to_string());
+
+        match lower.as_str() {
+            "i" => Ok(Operand::I),
+            "dt" => Ok(Operand::Dt),
+            "st" => Ok(Operand::St),
+            _ => {
+                if let Some(addr) = lower.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                    return match parse_number(addr.trim()) {
+                        Some(addr) if addr >= 0 => Ok(Operand::Memory(addr as usize)),
+                        _ => Err(err()),
+                    };
+                }
+
+                match lower.strip_prefix('v') {
+                    Some(x) if x.len() == 1 => u8::from_str_radix(x, 16).map(Operand::V).map_err(|_| err()),
+                    _ => Err(err()),
+                }
+            },
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = InvalidCondition;
+
+    /// Parses a condition such as `v3 == 5`, `i >= 0x300` or
+    /// `[#3A0] != 0`. The operator may be written without spaces.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ops = [
+            ("==", Compare::Eq),
+            ("!=", Compare::Ne),
+            ("<=", Compare::Le),
+            (">=", Compare::Ge),
+            ("<", Compare::Lt),
+            (">", Compare::Gt),
+        ];
+
+        for (text, compare) in ops.iter() {
+            if let Some(n) = s.find(text) {
+                let operand = s[..n].trim().parse().map_err(|_| InvalidCondition(s.to_string()))?;
+                let value = parse_number(s[n + text.len()..].trim())
+                    .filter(|&n| n >= 0)
+                    .ok_or_else(|| InvalidCondition(s.to_string()))?;
+
+                return Ok(Condition { operand, compare: *compare, value: value as usize });
+            }
+        }
+
+        Err(InvalidCondition(s.to_string()))
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::V(x) => write!(f, "v{:x}", x),
+            Operand::I => write!(f, "i"),
+            Operand::Dt => write!(f, "dt"),
+            Operand::St => write!(f, "st"),
+            Operand::Memory(addr) => write!(f, "[#{:03X}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Compare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Compare::Eq => "==",
+            Compare::Ne => "!=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} #{:X}", self.operand, self.compare, self.value)
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Any => "access",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+impl fmt::Display for InvalidCondition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid condition '{}', expected a register or [address], a comparison and a number", self.0)
+    }
+}
+
+impl Error for InvalidCondition {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::platform::Platform;
+    use crate::vm::vm::load_rom;
+
+    /// Calls a subroutine that loads V1 and V2, then counts in V0 and
+    /// stores V0-V1 at 0x300, forever.
+    const PROGRAM: [u8; 16] = [
+        0x22, 0x0A, // 200: CALL 20A
+        0x70, 0x01, // 202: ADD V0, 1
+        0xA3, 0x00, // 204: LD I, 300
+        0xF1, 0x55, // 206: LD [I], V1
+        0x12, 0x02, // 208: JP 202
+        0x61, 0x05, // 20A: LD V1, 5
+        0x62, 0x06, // 20C: LD V2, 6
+        0x00, 0xEE, // 20E: RET
+    ];
+
+    fn debugger() -> Debugger {
+        Debugger::new(load_rom(PROGRAM.to_vec(), Platform::Chip8).unwrap())
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_call() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.step_over(), Ok(Stop::Done));
+        assert_eq!(debugger.vm().pc(), 0x202);
+        assert_eq!(debugger.vm().stack().len(), 0);
+        assert_eq!(debugger.vm().registers().v[2], 6);
+    }
+
+    #[test]
+    fn step_out_returns_from_the_subroutine() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.step(), Ok(Stop::Done));
+        assert_eq!(debugger.vm().pc(), 0x20A);
+        assert_eq!(debugger.vm().stack().len(), 1);
+
+        assert_eq!(debugger.step_out(), Ok(Stop::Done));
+        assert_eq!(debugger.vm().pc(), 0x202);
+        assert_eq!(debugger.vm().stack().len(), 0);
+        assert_eq!(debugger.vm().registers().v[2], 6);
+    }
+
+    #[test]
+    fn breakpoint_at_pc_is_not_hit_again_straight_away() {
+        let mut debugger = debugger();
+
+        debugger.set_breakpoint(0x202, None);
+
+        assert_eq!(debugger.cont(), Ok(Stop::Breakpoint(0x202)));
+        assert_eq!(debugger.vm().registers().v[0], 0);
+
+        // Continuing goes once around the loop rather than stopping where it is.
+        assert_eq!(debugger.cont(), Ok(Stop::Breakpoint(0x202)));
+        assert_eq!(debugger.vm().registers().v[0], 1);
+    }
+
+    #[test]
+    fn conditional_breakpoint_stops_only_when_it_holds() {
+        let mut debugger = debugger();
+
+        debugger.set_breakpoint(0x208, Some("v0 == 3".parse().unwrap()));
+
+        assert_eq!(debugger.cont(), Ok(Stop::Breakpoint(0x208)));
+        assert_eq!(debugger.vm().registers().v[0], 3);
+
+        debugger.set_breakpoint(0x208, Some("v0 > 0xFF".parse().unwrap()));
+
+        assert_eq!(debugger.run(Action::Continue, 1000), Ok(Stop::Limit));
+    }
+
+    #[test]
+    fn conditions_parse() {
+        let parse = |s: &str| s.parse::<Condition>();
+
+        assert_eq!(parse("v3==5"), Ok(Condition { operand: Operand::V(3), compare: Compare::Eq, value: 5 }));
+        assert_eq!(parse("i >= 0x300"), Ok(Condition { operand: Operand::I, compare: Compare::Ge, value: 0x300 }));
+        assert_eq!(parse("[#3A0] != 0"), Ok(Condition { operand: Operand::Memory(0x3A0), compare: Compare::Ne, value: 0 }));
+        assert_eq!(parse("dt < $10"), Ok(Condition { operand: Operand::Dt, compare: Compare::Lt, value: 0x10 }));
+
+        for bad in ["v0 = 1", "vg == 1", "x == 1", "v0 == ", "[300] <= x"] {
+            assert_eq!(parse(bad), Err(InvalidCondition(bad.to_string())), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn store_into_watched_memory_stops() {
+        let mut debugger = debugger();
+
+        debugger.add_watchpoint(0x301..0x302, Access::Write);
+
+        assert_eq!(debugger.cont(), Ok(Stop::Watchpoint { pc: 0x206, addr: 0x301, access: Access::Write }));
+
+        // The store has already happened.
+        assert_eq!(debugger.vm().memory()[0x301], 5);
+        assert_eq!(debugger.vm().pc(), 0x208);
+    }
+
+    #[test]
+    fn reads_dont_stop_write_watchpoints() {
+        let mut debugger = debugger();
+
+        debugger.add_watchpoint(0x300..0x302, Access::Read);
+
+        assert_eq!(debugger.run(Action::Continue, 1000), Ok(Stop::Limit));
+    }
+
+    #[test]
+    fn resume_carries_on_after_the_limit() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.run(Action::RunTo(0x208), 2), Ok(Stop::Limit));
+        assert_eq!(debugger.vm().pc(), 0x20C);
+
+        assert_eq!(debugger.resume(2), Ok(Stop::Limit));
+        assert_eq!(debugger.vm().pc(), 0x202);
+
+        assert_eq!(debugger.resume(100), Ok(Stop::Done));
+        assert_eq!(debugger.vm().pc(), 0x208);
+
+        // With nothing left to do, resuming continues.
+        assert_eq!(debugger.resume(100), Ok(Stop::Limit));
+    }
+}
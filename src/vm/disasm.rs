@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::vm::instruction::Instruction;
+use crate::vm::platform::Platform;
+
+/// The assembly language a listing is written in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// The classic mnemonics, e.g. `LD V0, #08`, as written by `classic`.
+    #[default]
+    Classic,
+
+    /// Octo, e.g. `v0 := 0x08`, which can be assembled again.
+    Octo,
+}
+
+/// A program split into code and data by following every path of
+/// execution from the load address.
+///
+/// Jumps, calls and skips are followed; anything never reached is taken
+/// to be data, usually sprites. Targets of jumps and calls, and data
+/// loaded into I, are given labels, and the entry point is `main`. Jumps
+/// through BNNN can't be followed past their base address, so code
+/// reached only that way is listed as data.
+#[derive(Debug, Clone)]
+pub struct Disassembly {
+    /// Address the program is loaded at.
+    base: usize,
+
+    /// The program.
+    program: Vec<u8>,
+
+    /// Instructions found, by address.
+    code: BTreeMap<usize, Instruction>,
+
+    /// Labels by address.
+    labels: BTreeMap<usize, String>,
+}
+
+/// Why an address was given a label. Calls take precedence over jumps,
+/// and jumps over data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Data,
+    Jump,
+    Call,
+}
+
+impl Disassembly {
+    /// Disassembles a program as loaded on a platform.
+    pub fn new(program: &[u8], platform: Platform) -> Disassembly {
+        let base = platform.load_address();
+        let end = base + program.len();
+        let byte = |addr: usize| if (base..end).contains(&addr) { program[addr - base] } else { 0 };
+        let word = |addr: usize| u16::from_be_bytes([byte(addr), byte(addr + 1)]);
+        let decode = |addr: usize| {
+            let inst = if platform == Platform::XoChip {
+                Instruction::decode_long(word(addr), word(addr + 2))
+            } else {
+                Instruction::decode(word(addr))
+            };
+
+            inst.ok().filter(|&inst| platform.supports(inst))
+        };
+
+        let mut code = BTreeMap::new();
+        let mut claimed = BTreeSet::new();
+        let mut targets = BTreeMap::new();
+        let mut pending = vec![base];
+
+        while let Some(addr) = pending.pop() {
+            if addr < base || addr + 2 > end || claimed.contains(&addr) {
+                continue;
+            }
+
+            let inst = match decode(addr) {
+                Some(inst) if addr + inst.size() <= end => inst,
+                _ => continue,
+            };
+
+            // Skip instructions that overlap one already found.
+            if (addr..addr + inst.size()).any(|a| claimed.contains(&a)) {
+                continue;
+            }
+
+            claimed.extend(addr..addr + inst.size());
+            code.insert(addr, inst);
+
+            let next = addr + inst.size();
+            let mut target = |addr: u16, kind: Target| {
+                let entry = targets.entry(addr as usize).or_insert(kind);
+
+                *entry = kind.max(*entry);
+            };
+
+            match inst {
+                Instruction::Jump(to) => {
+                    target(to, Target::Jump);
+                    pending.push(to as usize);
+                },
+                Instruction::JumpV0(to) => {
+                    target(to, Target::Jump);
+                    pending.push(to as usize);
+                },
+                Instruction::Call(to) => {
+                    target(to, Target::Call);
+                    pending.push(to as usize);
+                    pending.push(next);
+                },
+                Instruction::Ret | Instruction::Exit => (),
+                Instruction::SkipEqImm { .. }
+                | Instruction::SkipNeImm { .. }
+                | Instruction::SkipEq { .. }
+                | Instruction::SkipNe { .. }
+                | Instruction::SkipKey { .. }
+                | Instruction::SkipNotKey { .. } => {
+                    let skipped = decode(next).map_or(2, |inst| inst.size());
+
+                    pending.push(next + skipped);
+                    pending.push(next);
+                },
+                Instruction::LoadI(addr) | Instruction::LoadILong(addr) => {
+                    target(addr, Target::Data);
+                    pending.push(next);
+                },
+                _ => pending.push(next),
+            }
+        }
+
+        // Only addresses that begin a line of the listing can be labeled:
+        // instructions and data bytes, one per line.
+        let mut labels: BTreeMap<usize, String> = targets.into_iter()
+            .filter(|&(addr, _)| (base..end).contains(&addr))
+            .filter(|&(addr, _)| code.contains_key(&addr) || !claimed.contains(&addr))
+            .map(|(addr, kind)| {
+                let prefix = match kind {
+                    Target::Data => "data",
+                    Target::Jump => "label",
+                    Target::Call => "sub",
+                };
+
+                (addr, format!("{}_{:03x}", prefix, addr))
+            })
+            .collect();
+
+        // Octo starts programs at main.
+        labels.insert(base, "main".to_string());
+
+        Disassembly {
+            base,
+            program: program.to_vec(),
+            code,
+            labels,
+        }
+    }
+
+    /// Address the program is loaded at.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    /// Returns every instruction found along with its address, in order.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, Instruction)> + '_ {
+        self.code.iter().map(|(&addr, &inst)| (addr, inst))
+    }
+
+    /// Returns the instruction beginning at an address, if there is one.
+    pub fn instruction(&self, addr: usize) -> Option<Instruction> {
+        self.code.get(&addr).copied()
+    }
+
+    /// True if the byte at an address is part of an instruction.
+    pub fn is_code(&self, addr: usize) -> bool {
+        self.code.range(..=addr)
+            .next_back()
+            .is_some_and(|(&start, inst)| addr < start + inst.size())
+    }
+
+    /// Returns the label given to an address, if any.
+    pub fn label(&self, addr: usize) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    /// Writes the whole program as a listing. Code is followed by its
+    /// address and bytes in a comment. Data is written one byte per line
+    /// with its bits drawn in a comment, so sprites can be seen.
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let (comment, indent) = match syntax {
+            Syntax::Classic => (";", "    "),
+            Syntax::Octo => ("#", "  "),
+        };
+
+        let mut out = String::new();
+        let mut addr = self.base;
+        let end = self.base + self.program.len();
+
+        if syntax == Syntax::Octo && self.base != 0x200 {
+            let _ = writeln!(out, ":org 0x{:03X}", self.base);
+        }
+
+        while addr < end {
+            if let Some(label) = self.label(addr) {
+                let _ = match syntax {
+                    Syntax::Classic => writeln!(out, "{}:", label),
+                    Syntax::Octo => writeln!(out, ": {}", label),
+                };
+            }
+
+            let (text, size) = match self.code.get(&addr) {
+                Some(&inst) => (self.format(inst, syntax), inst.size()),
+                None => {
+                    let b = self.program[addr - self.base];
+                    let text = match syntax {
+                        Syntax::Classic => format!("DB #{:02X}", b),
+                        Syntax::Octo => format!("0x{:02X}", b),
+                    };
+
+                    (text, 1)
+                },
+            };
+
+            let bytes = &self.program[addr - self.base..addr - self.base + size];
+            let detail = if self.code.contains_key(&addr) {
+                bytes.iter().map(|b| format!("{:02X}", b)).collect()
+            } else {
+                bits(bytes[0])
+            };
+
+            let _ = writeln!(out, "{}{:<24}{} {:03X}  {}", indent, text, comment, addr, detail);
+
+            addr += size;
+        }
+
+        out
+    }
+
+    /// Returns the name to write for an address operand: its label if it
+    /// has one, or else the number.
+    fn name(&self, addr: u16, syntax: Syntax) -> String {
+        match (self.label(addr as usize), syntax) {
+            (Some(label), _) => label.to_string(),
+            (None, Syntax::Classic) => format!("#{:03X}", addr),
+            (None, Syntax::Octo) => format!("0x{:03X}", addr),
+        }
+    }
+
+    /// Writes a single instruction, with labels for address operands.
+    fn format(&self, inst: Instruction, syntax: Syntax) -> String {
+        match syntax {
+            Syntax::Classic => match inst {
+                Instruction::Sys(addr) => format!("SYS {}", self.name(addr, syntax)),
+                Instruction::Jump(addr) => format!("JP {}", self.name(addr, syntax)),
+                Instruction::Call(addr) => format!("CALL {}", self.name(addr, syntax)),
+                Instruction::LoadI(addr) => format!("LD I, {}", self.name(addr, syntax)),
+                Instruction::JumpV0(addr) => format!("JP V0, {}", self.name(addr, syntax)),
+                Instruction::LoadILong(addr) => format!("LD I, LONG {}", self.name(addr, syntax)),
+                _ => classic(inst),
+            },
+            Syntax::Octo => self.format_octo(inst),
+        }
+    }
+
+    /// Writes a single instruction in Octo syntax. Octo has no skip
+    /// instructions, only `if ... then`, whose condition is the opposite
+    /// of the skip: `if v0 != 3 then` skips the next instruction when
+    /// V0 == 3.
+    fn format_octo(&self, inst: Instruction) -> String {
+        let name = |addr: u16| self.name(addr, Syntax::Octo);
+
+        match inst {
+            Instruction::Sys(addr) => format!("0x{:02X} 0x{:02X}", addr >> 8, addr & 0xFF),
+            Instruction::Cls => "clear".to_string(),
+            Instruction::Ret => "return".to_string(),
+            Instruction::ScrollDown(n) => format!("scroll-down {}", n),
+            Instruction::ScrollUp(n) => format!("scroll-up {}", n),
+            Instruction::ScrollRight => "scroll-right".to_string(),
+            Instruction::ScrollLeft => "scroll-left".to_string(),
+            Instruction::Exit => "exit".to_string(),
+            Instruction::LowRes => "lores".to_string(),
+            Instruction::HighRes => "hires".to_string(),
+            Instruction::Jump(addr) => format!("jump {}", name(addr)),
+            Instruction::Call(addr) => match self.label(addr as usize) {
+                Some(label) => label.to_string(),
+                None => format!(":call {}", name(addr)),
+            },
+            Instruction::SkipEqImm { x, nn } => format!("if v{:x} != 0x{:02X} then", x, nn),
+            Instruction::SkipNeImm { x, nn } => format!("if v{:x} == 0x{:02X} then", x, nn),
+            Instruction::SkipEq { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            Instruction::SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            Instruction::LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            Instruction::LoadImm { x, nn } => format!("v{:x} := 0x{:02X}", x, nn),
+            Instruction::AddImm { x, nn } => format!("v{:x} += 0x{:02X}", x, nn),
+            Instruction::Load { x, y } => format!("v{:x} := v{:x}", x, y),
+            Instruction::Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            Instruction::And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Instruction::Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Instruction::Add { x, y } => format!("v{:x} += v{:x}", x, y),
+            Instruction::Sub { x, y } => format!("v{:x} -= v{:x}", x, y),
+            Instruction::Shr { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            Instruction::SubN { x, y } => format!("v{:x} =- v{:x}", x, y),
+            Instruction::Shl { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            Instruction::SkipNe { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            Instruction::LoadI(addr) => format!("i := {}", name(addr)),
+            Instruction::JumpV0(addr) => format!("jump0 {}", name(addr)),
+            Instruction::Rand { x, nn } => format!("v{:x} := random 0x{:02X}", x, nn),
+            Instruction::Draw { x, y, n } => format!("sprite v{:x} v{:x} {}", x, y, n),
+            Instruction::SkipKey { x } => format!("if v{:x} -key then", x),
+            Instruction::SkipNotKey { x } => format!("if v{:x} key then", x),
+            Instruction::LoadILong(addr) => format!("i := long {}", name(addr)),
+            Instruction::Plane(n) => format!("plane {}", n),
+            Instruction::Audio => "audio".to_string(),
+            Instruction::LoadDelay { x } => format!("v{:x} := delay", x),
+            Instruction::WaitKey { x } => format!("v{:x} := key", x),
+            Instruction::SetDelay { x } => format!("delay := v{:x}", x),
+            Instruction::SetSound { x } => format!("buzzer := v{:x}", x),
+            Instruction::AddI { x } => format!("i += v{:x}", x),
+            Instruction::Font { x } => format!("i := hex v{:x}", x),
+            Instruction::BigFont { x } => format!("i := bighex v{:x}", x),
+            Instruction::Bcd { x } => format!("bcd v{:x}", x),
+            Instruction::Pitch { x } => format!("pitch := v{:x}", x),
+            Instruction::Store { x } => format!("save v{:x}", x),
+            Instruction::Restore { x } => format!("load v{:x}", x),
+            Instruction::StoreFlags { x } => format!("saveflags v{:x}", x),
+            Instruction::LoadFlags { x } => format!("loadflags v{:x}", x),
+        }
+    }
+}
+
+/// Writes an instruction in the classic mnemonics, with hex numbers
+/// prefixed by `#`, e.g. `LD V0, #08`.
+pub fn classic(inst: Instruction) -> String {
+    match inst {
+        Instruction::Sys(addr) => format!("SYS #{:03X}", addr),
+        Instruction::Cls => "CLS".to_string(),
+        Instruction::Ret => "RET".to_string(),
+        Instruction::ScrollDown(n) => format!("SCD {}", n),
+        Instruction::ScrollUp(n) => format!("SCU {}", n),
+        Instruction::ScrollRight => "SCR".to_string(),
+        Instruction::ScrollLeft => "SCL".to_string(),
+        Instruction::Exit => "EXIT".to_string(),
+        Instruction::LowRes => "LOW".to_string(),
+        Instruction::HighRes => "HIGH".to_string(),
+        Instruction::Jump(addr) => format!("JP #{:03X}", addr),
+        Instruction::Call(addr) => format!("CALL #{:03X}", addr),
+        Instruction::SkipEqImm { x, nn } => format!("SE V{:X}, #{:02X}", x, nn),
+        Instruction::SkipNeImm { x, nn } => format!("SNE V{:X}, #{:02X}", x, nn),
+        Instruction::SkipEq { x, y } => format!("SE V{:X}, V{:X}", x, y),
+        Instruction::SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+        Instruction::LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+        Instruction::LoadImm { x, nn } => format!("LD V{:X}, #{:02X}", x, nn),
+        Instruction::AddImm { x, nn } => format!("ADD V{:X}, #{:02X}", x, nn),
+        Instruction::Load { x, y } => format!("LD V{:X}, V{:X}", x, y),
+        Instruction::Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+        Instruction::And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+        Instruction::Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+        Instruction::Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+        Instruction::Sub { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+        Instruction::Shr { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+        Instruction::SubN { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+        Instruction::Shl { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+        Instruction::SkipNe { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+        Instruction::LoadI(addr) => format!("LD I, #{:03X}", addr),
+        Instruction::JumpV0(addr) => format!("JP V0, #{:03X}", addr),
+        Instruction::Rand { x, nn } => format!("RND V{:X}, #{:02X}", x, nn),
+        Instruction::Draw { x, y, n } => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        Instruction::SkipKey { x } => format!("SKP V{:X}", x),
+        Instruction::SkipNotKey { x } => format!("SKNP V{:X}", x),
+        Instruction::LoadILong(addr) => format!("LD I, LONG #{:04X}", addr),
+        Instruction::Plane(n) => format!("PLANE {}", n),
+        Instruction::Audio => "AUDIO".to_string(),
+        Instruction::LoadDelay { x } => format!("LD V{:X}, DT", x),
+        Instruction::WaitKey { x } => format!("LD V{:X}, K", x),
+        Instruction::SetDelay { x } => format!("LD DT, V{:X}", x),
+        Instruction::SetSound { x } => format!("LD ST, V{:X}", x),
+        Instruction::AddI { x } => format!("ADD I, V{:X}", x),
+        Instruction::Font { x } => format!("LD F, V{:X}", x),
+        Instruction::BigFont { x } => format!("LD HF, V{:X}", x),
+        Instruction::Bcd { x } => format!("LD B, V{:X}", x),
+        Instruction::Pitch { x } => format!("PITCH V{:X}", x),
+        Instruction::Store { x } => format!("LD [I], V{:X}", x),
+        Instruction::Restore { x } => format!("LD V{:X}, [I]", x),
+        Instruction::StoreFlags { x } => format!("LD R, V{:X}", x),
+        Instruction::LoadFlags { x } => format!("LD V{:X}, R", x),
+    }
+}
+
+/// Draws the bits of a byte the way the character patterns in
+/// `EMULATOR_ROM` are commented, e.g. `|  ****  |`.
+fn bits(b: u8) -> String {
+    let pixels: String = (0..8).map(|n| if b & (0x80 >> n) != 0 { '*' } else { ' ' }).collect();
+
+    format!("|{}|", pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::asm::assemble;
+
+    /// Calls a subroutine that draws a sprite, then loops around a skip.
+    const PROGRAM: [u8; 21] = [
+        0x22, 0x0A, // 200: CALL 20A
+        0x30, 0x00, // 202: SE V0, 0
+        0x12, 0x08, // 204: JP 208
+        0x00, 0xE0, // 206: CLS
+        0x12, 0x02, // 208: JP 202
+        0xA2, 0x10, // 20A: LD I, 210
+        0xD0, 0x15, // 20C: DRW V0, V0, 5
+        0x00, 0xEE, // 20E: RET
+        0xF0, 0x90, 0x90, 0x90, 0xF0,
+    ];
+
+    #[test]
+    fn code_data_and_labels_are_found() {
+        let disasm = Disassembly::new(&PROGRAM, Platform::Chip8);
+        let labels: Vec<(usize, &str)> = disasm.labels.iter().map(|(&addr, label)| (addr, label.as_str())).collect();
+
+        assert_eq!(labels, [
+            (0x200, "main"),
+            (0x202, "label_202"),
+            (0x208, "label_208"),
+            (0x20A, "sub_20a"),
+            (0x210, "data_210"),
+        ]);
+
+        assert_eq!(disasm.instructions().count(), 8);
+        assert_eq!(disasm.instruction(0x206), Some(Instruction::Cls));
+        assert!(disasm.is_code(0x20F));
+        assert!(!disasm.is_code(0x210));
+    }
+
+    #[test]
+    fn classic_listing_uses_labels() {
+        let listing = Disassembly::new(&PROGRAM, Platform::Chip8).listing(Syntax::Classic);
+
+        assert!(listing.contains("CALL sub_20a"));
+        assert!(listing.contains("LD I, data_210"));
+        assert!(listing.contains("DB #F0"));
+    }
+
+    #[test]
+    fn octo_listing_assembles_to_the_same_bytes() {
+        let listing = Disassembly::new(&PROGRAM, Platform::Chip8).listing(Syntax::Octo);
+
+        assert_eq!(assemble(&listing).unwrap().rom, PROGRAM);
+    }
+
+    #[test]
+    fn skip_over_long_load_skips_four_bytes() {
+        let program = [
+            0x30, 0x00, // 200: SE V0, 0
+            0xF0, 0x00, 0x02, 0x08, // 202: LD I, LONG 208
+            0x12, 0x06, // 206: JP 206
+            0xFF,
+        ];
+        let disasm = Disassembly::new(&program, Platform::XoChip);
+
+        assert_eq!(disasm.instruction(0x202), Some(Instruction::LoadILong(0x208)));
+        assert_eq!(disasm.instruction(0x204), None);
+        assert_eq!(disasm.instruction(0x206), Some(Instruction::Jump(0x206)));
+        assert_eq!(disasm.label(0x208), Some("data_208"));
+
+        assert_eq!(assemble(&disasm.listing(Syntax::Octo)).unwrap().rom, program);
+    }
+}
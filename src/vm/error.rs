@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fmt;
+use crate::vm::platform::Platform;
+
+/// Errors raised while loading a ROM or executing a program. Execution
+/// faults carry the address of the instruction that caused them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChipperError {
+    /// The ROM doesn't fit in the program memory of the platform.
+    RomTooLarge { size: usize, max: usize, platform: Platform },
+
+    /// The ROM has no bytes in it.
+    EmptyRom,
+
+    /// The opcode doesn't decode to any instruction.
+    UnknownOpcode { opcode: u16, addr: usize },
+
+    /// A 2NNN was executed with every stack cell in use.
+    StackOverflow { addr: usize },
+
+    /// A 00EE was executed with an empty stack.
+    StackUnderflow { addr: usize },
+
+    /// An instruction read or wrote memory past the end of memory.
+    MemoryOutOfRange { access: usize, addr: usize },
+
+    /// FX29 or FX30 was asked for a digit that has no font sprite.
+    InvalidFontDigit { digit: u8, addr: usize },
+
+    /// Movie playback diverged from the recording: the state hash after
+    /// a frame differs from the one recorded.
+    Desync { frame: usize, expected: u32, actual: u32 },
+}
+
+impl ChipperError {
+    /// The address of the instruction that faulted, if this error was
+    /// raised during execution.
+    pub fn addr(&self) -> Option<usize> {
+        match *self {
+            ChipperError::RomTooLarge { .. } | ChipperError::EmptyRom => None,
+            ChipperError::Desync { .. } => None,
+            ChipperError::UnknownOpcode { addr, .. } => Some(addr),
+            ChipperError::StackOverflow { addr } => Some(addr),
+            ChipperError::StackUnderflow { addr } => Some(addr),
+            ChipperError::MemoryOutOfRange { addr, .. } => Some(addr),
+            ChipperError::InvalidFontDigit { addr, .. } => Some(addr),
+        }
+    }
+}
+
+impl fmt::Display for ChipperError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChipperError::RomTooLarge { size, max, platform } => {
+                write!(f, "ROM is {} bytes, but at most {} fit in {} memory", size, max, platform)
+            },
+            ChipperError::EmptyRom => write!(f, "ROM is empty"),
+            ChipperError::UnknownOpcode { opcode, addr } => {
+                write!(f, "Unknown opcode {:04X} at {:03X}", opcode, addr)
+            },
+            ChipperError::StackOverflow { addr } => write!(f, "Stack overflow at {:03X}", addr),
+            ChipperError::StackUnderflow { addr } => write!(f, "Stack underflow at {:03X}", addr),
+            ChipperError::MemoryOutOfRange { access, addr } => {
+                write!(f, "Memory access to {:04X} out of range at {:03X}", access, addr)
+            },
+            ChipperError::InvalidFontDigit { digit, addr } => {
+                write!(f, "No font sprite for digit {:02X} at {:03X}", digit, addr)
+            },
+            ChipperError::Desync { frame, expected, actual } => {
+                write!(f, "Movie desynced at frame {}: expected state {:08X}, got {:08X}", frame, expected, actual)
+            },
+        }
+    }
+}
+
+impl Error for ChipperError {}
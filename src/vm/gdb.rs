@@ -0,0 +1,686 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::vm::debug::{Access, Action, Debugger, Stop};
+use crate::vm::error::ChipperError;
+
+/// Registers as gdb sees them: V0-VF, then I, PC, SP, DT and ST.
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chipper.chip8">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+/// Number of registers gdb knows about.
+const REGISTERS: usize = 21;
+
+/// Signals reported in stop replies.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// Serves a debugger to gdb, or any other client of the GDB Remote
+/// Serial Protocol, over a TCP connection.
+///
+/// Registers are V0-VF, I, PC, SP, DT and ST, described to the client in
+/// a target description. The description can't name CHIP-8 as an
+/// architecture, so gdb takes the byte order of its default one, which
+/// is little-endian almost everywhere: I and PC are sent least
+/// significant byte first to match, though memory stays big-endian.
+/// Software breakpoints, watchpoints and single stepping map onto the
+/// debugger.
+///
+/// While the program runs it is paced to real time, a 60th of a second
+/// of instructions at a time, and the client can interrupt it.
+pub struct GdbStub {
+    /// The VM being debugged.
+    debugger: Debugger,
+
+    /// The connection to the client.
+    stream: TcpStream,
+
+    /// Set once the client asks to stop acknowledging packets.
+    no_ack: bool,
+
+    /// Bytes read while checking for an interrupt, which are the start
+    /// of the next packet.
+    pending: VecDeque<u8>,
+}
+
+/// What to do after handling a packet.
+enum Reply {
+    /// Send a packet back.
+    Packet(String),
+
+    /// Resume the program, replying once it stops.
+    Resume(Action),
+
+    /// End the session, sending a packet back first if there is one.
+    Close(Option<String>),
+}
+
+impl GdbStub {
+    /// Creates a stub for a client that just connected.
+    pub fn new(debugger: Debugger, stream: TcpStream) -> GdbStub {
+        GdbStub { debugger, stream, no_ack: false, pending: VecDeque::new() }
+    }
+
+    /// Returns the debugger, ending the session.
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    /// Handles packets until the client detaches, kills the program or
+    /// disconnects.
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.receive()? {
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.send(&reply)?,
+                Reply::Resume(action) => {
+                    let reply = self.resume(action)?;
+
+                    self.send(&reply)?;
+                },
+                Reply::Close(reply) => {
+                    if let Some(reply) = reply {
+                        self.send(&reply)?;
+                    }
+
+                    break;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles a single packet.
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (cmd, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => {
+                let mut reply = String::new();
+
+                for n in 0..REGISTERS {
+                    reply.push_str(&self.register(n));
+                }
+
+                reply
+            },
+            "G" => {
+                let bytes = match decode_hex(args) {
+                    Some(bytes) => bytes,
+                    None => return Reply::Packet("E01".to_string()),
+                };
+                let mut pos = 0;
+
+                for n in 0..REGISTERS {
+                    let size = register_size(n);
+
+                    if let Some(value) = bytes.get(pos..pos + size) {
+                        self.set_register(n, value);
+                    }
+
+                    pos += size;
+                }
+
+                "OK".to_string()
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < REGISTERS => self.register(n),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    Some((usize::from_str_radix(n, 16).ok()?, decode_hex(value)?))
+                });
+
+                match parsed {
+                    Some((n, value)) if n < REGISTERS && value.len() == register_size(n) => {
+                        self.set_register(n, &value);
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match parse_range(args) {
+                Some((addr, len)) => match addr.checked_add(len).and_then(|end| self.debugger.vm().memory().get(addr..end)) {
+                    Some(bytes) => encode_hex(bytes),
+                    None => "E14".to_string(),
+                },
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, decode_hex(data)?)));
+
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len => match addr.checked_add(len).and_then(|end| self.debugger.vm_mut().memory_mut().get_mut(addr..end)) {
+                        Some(memory) => {
+                            memory.copy_from_slice(&data);
+                            "OK".to_string()
+                        },
+                        None => "E14".to_string(),
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "c" | "s" => {
+                if let Ok(addr) = usize::from_str_radix(args, 16) {
+                    self.debugger.vm_mut().set_pc(addr);
+                }
+
+                return Reply::Resume(if cmd == "c" { Action::Continue } else { Action::Step });
+            },
+            "Z" | "z" => return Reply::Packet(self.point(cmd == "Z", args)),
+            "H" | "T" => "OK".to_string(),
+            "D" => return Reply::Close(Some("OK".to_string())),
+            "k" => return Reply::Close(None),
+            "q" | "Q" | "v" => return self.query(packet),
+            _ => String::new(),
+        };
+
+        Reply::Packet(reply)
+    }
+
+    /// Handles the general query and multi-letter packets.
+    fn query(&mut self, packet: &str) -> Reply {
+        let reply = match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "vCont?" => "vCont;c;C;s;S".to_string(),
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+".to_string()
+            },
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => {
+                let range = &packet["qXfer:features:read:target.xml:".len()..];
+
+                match parse_range(range) {
+                    Some((offset, _)) if offset >= TARGET_XML.len() => "l".to_string(),
+                    Some((offset, len)) => {
+                        let end = offset.saturating_add(len).min(TARGET_XML.len());
+                        let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+
+                        format!("{}{}", more, &TARGET_XML[offset..end])
+                    },
+                    None => "E01".to_string(),
+                }
+            },
+            _ if packet.starts_with("vCont;") => {
+                // There's a single thread, so the first action applies.
+                let action = match packet[6..].chars().next() {
+                    Some('c') | Some('C') => Action::Continue,
+                    Some('s') | Some('S') => Action::Step,
+                    _ => return Reply::Packet("E01".to_string()),
+                };
+
+                return Reply::Resume(action);
+            },
+            _ => String::new(),
+        };
+
+        Reply::Packet(reply)
+    }
+
+    /// Sets or removes a breakpoint or watchpoint.
+    fn point(&mut self, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|s| usize::from_str_radix(s, 16).ok());
+        let len = parts.next().and_then(|s| usize::from_str_radix(s, 16).ok());
+
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len.max(1)),
+            _ => return "E01".to_string(),
+        };
+
+        let access = match kind {
+            // Software and hardware breakpoints are the same here.
+            Some("0") | Some("1") => {
+                if insert {
+                    self.debugger.set_breakpoint(addr, None);
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+
+                return "OK".to_string();
+            },
+            Some("2") => Access::Write,
+            Some("3") => Access::Read,
+            Some("4") => Access::Any,
+            _ => return String::new(),
+        };
+
+        let end = match addr.checked_add(len) {
+            Some(end) => end,
+            None => return "E14".to_string(),
+        };
+
+        if insert {
+            self.debugger.add_watchpoint(addr..end, access);
+        } else {
+            self.debugger.remove_watchpoint(addr);
+        }
+
+        "OK".to_string()
+    }
+
+    /// Runs the program until it stops, or the client interrupts it,
+    /// and returns the stop reply.
+    fn resume(&mut self, action: Action) -> io::Result<String> {
+        let frame = Duration::from_nanos(1_000_000_000 / 60);
+        let mut next = Instant::now();
+        let mut result = self.debugger.run(action, self.slice());
+
+        loop {
+            match result {
+                Ok(Stop::Limit) => (),
+                Ok(Stop::Exited) => return Ok("W00".to_string()),
+                Ok(Stop::Watchpoint { addr, access, .. }) => {
+                    let any = self.debugger.watchpoints().iter().any(|w| w.access == Access::Any && w.range.contains(&addr));
+
+                    let kind = match access {
+                        _ if any => "awatch",
+                        Access::Read => "rwatch",
+                        _ => "watch",
+                    };
+
+                    return Ok(format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr));
+                },
+                Ok(_) => return Ok(format!("S{:02x}", SIGTRAP)),
+                Err(ChipperError::UnknownOpcode { .. }) => return Ok(format!("S{:02x}", SIGILL)),
+                Err(_) => return Ok(format!("S{:02x}", SIGSEGV)),
+            }
+
+            if self.interrupted()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+
+            next += frame;
+
+            match next.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                None => next = Instant::now(),
+            }
+
+            result = self.debugger.resume(self.slice());
+        }
+    }
+
+    /// The number of instructions run between checks for an interrupt.
+    fn slice(&self) -> u64 {
+        self.debugger.vm().cycles_per_frame() as u64
+    }
+
+    /// True if the client sent an interrupt (Ctrl-C) while the program
+    /// was running. Anything else it sent is kept for `receive`.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        let mut buf = [0; 256];
+
+        self.stream.set_nonblocking(true)?;
+
+        let read = self.stream.read(&mut buf);
+
+        self.stream.set_nonblocking(false)?;
+
+        match read {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                self.pending.extend(buf[..n].iter().filter(|&&b| b != 0x03));
+                Ok(buf[..n].contains(&0x03))
+            },
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns a register as hex.
+    fn register(&self, n: usize) -> String {
+        let vm = self.debugger.vm();
+        let regs = vm.registers();
+
+        match n {
+            0..=15 => format!("{:02x}", regs.v[n]),
+            16 => encode_hex(&(regs.i as u16).to_le_bytes()),
+            17 => encode_hex(&(vm.pc() as u16).to_le_bytes()),
+            18 => format!("{:02x}", vm.stack().len()),
+            19 => format!("{:02x}", regs.dt),
+            _ => format!("{:02x}", regs.st),
+        }
+    }
+
+    /// Changes a register. The stack pointer can't be changed, since
+    /// the stack can't be grown from here.
+    fn set_register(&mut self, n: usize, value: &[u8]) {
+        let word = value.iter().rev().fold(0, |acc, &b| acc << 8 | b as usize);
+        let vm = self.debugger.vm_mut();
+
+        match n {
+            0..=15 => vm.registers_mut().v[n] = word as u8,
+            16 => vm.registers_mut().i = word & (vm.memory().len() - 1),
+            17 => vm.set_pc(word),
+            19 => vm.registers_mut().dt = word as u8,
+            20 => vm.registers_mut().st = word as u8,
+            _ => (),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it. Returns None once the
+    /// client disconnects.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acks and interrupts sent while the program was stopped.
+            match self.byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+
+            loop {
+                match self.byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+
+            for digit in &mut checksum {
+                *digit = match self.byte()? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+            }
+
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            let data = unescape(&data);
+
+            if self.no_ack {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    /// Sends a packet.
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    /// Reads a byte, or None at the end of the stream.
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(b) = self.pending.pop_front() {
+            return Ok(Some(b));
+        }
+
+        let mut byte = [0];
+
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+}
+
+/// The size of a register in bytes.
+fn register_size(n: usize) -> usize {
+    if n == 16 || n == 17 { 2 } else { 1 }
+}
+
+/// The modulo 256 sum of packet data.
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &b| sum.wrapping_add(b))
+}
+
+/// Removes the escaping of `#`, `$`, `}` and `*` from packet data.
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+
+    out
+}
+
+/// Parses `addr,length` in hex.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (addr, len) = s.split_once(',')?;
+
+    Some((usize::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+/// Encodes bytes as lowercase hex.
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes hex into bytes.
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len()).step_by(2).map(|n| u8::from_str_radix(s.get(n..n + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use crate::vm::platform::Platform;
+    use crate::vm::vm::load_rom;
+
+    /// Jumps to itself forever.
+    const LOOP: [u8; 2] = [0x12, 0x00];
+
+    /// Starts a stub on a local port debugging a program, and connects
+    /// to it.
+    fn connect(program: &[u8]) -> (TcpStream, thread::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let program = program.to_vec();
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            let vm = load_rom(program, Platform::Chip8).unwrap();
+
+            GdbStub::new(Debugger::new(vm), stream).serve()
+        });
+
+        let client = TcpStream::connect(addr).unwrap();
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        (client, server)
+    }
+
+    fn send(client: &mut TcpStream, data: &str) {
+        write!(client, "${}#{:02x}", data, checksum_of(data.as_bytes())).unwrap();
+    }
+
+    /// Reads the next packet, skipping acks.
+    fn reply(client: &mut TcpStream) -> String {
+        let mut byte = [0];
+
+        while client.read(&mut byte).unwrap() == 1 && byte[0] != b'$' {}
+
+        let mut data = Vec::new();
+
+        while client.read(&mut byte).unwrap() == 1 && byte[0] != b'#' {
+            data.push(byte[0]);
+        }
+
+        let mut checksum = [0; 2];
+
+        client.read_exact(&mut checksum).unwrap();
+        assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", checksum_of(&data)));
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn memory_is_read_and_written() {
+        let (mut client, server) = connect(&LOOP);
+
+        send(&mut client, "m200,2");
+        assert_eq!(reply(&mut client), "1200");
+
+        send(&mut client, "M300,2:abcd");
+        assert_eq!(reply(&mut client), "OK");
+
+        send(&mut client, "m300,2");
+        assert_eq!(reply(&mut client), "abcd");
+
+        send(&mut client, "k");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn ranges_past_the_end_of_memory_are_errors() {
+        let (mut client, server) = connect(&LOOP);
+
+        for packet in ["mffffffffffffffff,2", "Mffffffffffffffff,1:00", "Z2,ffffffffffffffff,2", "m1000,1"] {
+            send(&mut client, packet);
+            assert_eq!(reply(&mut client), "E14", "{}", packet);
+        }
+
+        send(&mut client, "k");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn registers_are_sent_little_endian() {
+        let (mut client, server) = connect(&LOOP);
+
+        send(&mut client, "p11");
+        assert_eq!(reply(&mut client), "0002");
+
+        send(&mut client, "P10=4503");
+        assert_eq!(reply(&mut client), "OK");
+
+        send(&mut client, "p10");
+        assert_eq!(reply(&mut client), "4503");
+
+        send(&mut client, "k");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn packets_sent_while_running_are_kept() {
+        let (mut client, server) = connect(&LOOP);
+
+        send(&mut client, "c");
+        thread::sleep(Duration::from_millis(50));
+
+        // The interrupt and the next packet arrive together.
+        client.write_all(b"\x03").unwrap();
+        send(&mut client, "m200,2");
+
+        assert_eq!(reply(&mut client), format!("S{:02x}", SIGINT));
+        assert_eq!(reply(&mut client), "1200");
+
+        send(&mut client, "D");
+        assert_eq!(reply(&mut client), "OK");
+        server.join().unwrap().unwrap();
+    }
+
+    /// Sets V0 to 1, then adds 1 to it forever.
+    const COUNT: [u8; 6] = [0x60, 0x01, 0x70, 0x01, 0x12, 0x02];
+
+    #[test]
+    fn continue_stops_at_a_breakpoint() {
+        let (mut client, server) = connect(&COUNT);
+
+        send(&mut client, "Z0,204,2");
+        assert_eq!(reply(&mut client), "OK");
+
+        send(&mut client, "c");
+        assert_eq!(reply(&mut client), format!("S{:02x}", SIGTRAP));
+
+        send(&mut client, "p11");
+        assert_eq!(reply(&mut client), "0402");
+
+        send(&mut client, "p0");
+        assert_eq!(reply(&mut client), "02");
+
+        // Continuing from the breakpoint goes once around the loop.
+        send(&mut client, "c");
+        assert_eq!(reply(&mut client), format!("S{:02x}", SIGTRAP));
+
+        send(&mut client, "p0");
+        assert_eq!(reply(&mut client), "03");
+
+        send(&mut client, "k");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let (mut client, server) = connect(&COUNT);
+
+        for (pc, v0) in [("0202", "01"), ("0402", "02"), ("0202", "02"), ("0402", "03")] {
+            send(&mut client, "s");
+            assert_eq!(reply(&mut client), format!("S{:02x}", SIGTRAP));
+
+            send(&mut client, "p11");
+            assert_eq!(reply(&mut client), pc);
+
+            send(&mut client, "p0");
+            assert_eq!(reply(&mut client), v0);
+        }
+
+        send(&mut client, "k");
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+use std::error::Error;
+use std::fmt;
+
+/// A single decoded CHIP-8 instruction. Register operands (X, Y) are
+/// indexes 0-F into the V registers, addresses are 12-bit except for
+/// the 16-bit XO-CHIP long load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0NNN - Call a RCA 1802 machine code routine at NNN.
+    Sys(u16),
+
+    /// 00E0 - Clear the screen.
+    Cls,
+
+    /// 00EE - Return from a subroutine.
+    Ret,
+
+    /// 00CN - Scroll the display down N lines (SCHIP).
+    ScrollDown(u8),
+
+    /// 00DN - Scroll the display up N lines (XO-CHIP).
+    ScrollUp(u8),
+
+    /// 00FB - Scroll the display right 4 pixels (SCHIP).
+    ScrollRight,
+
+    /// 00FC - Scroll the display left 4 pixels (SCHIP).
+    ScrollLeft,
+
+    /// 00FD - Exit the interpreter (SCHIP).
+    Exit,
+
+    /// 00FE - Switch to 64x32 low-res mode (SCHIP).
+    LowRes,
+
+    /// 00FF - Switch to 128x64 high-res mode (SCHIP).
+    HighRes,
+
+    /// 1NNN - Jump to address NNN.
+    Jump(u16),
+
+    /// 2NNN - Call the subroutine at NNN.
+    Call(u16),
+
+    /// 3XNN - Skip the next instruction if VX == NN.
+    SkipEqImm { x: u8, nn: u8 },
+
+    /// 4XNN - Skip the next instruction if VX != NN.
+    SkipNeImm { x: u8, nn: u8 },
+
+    /// 5XY0 - Skip the next instruction if VX == VY.
+    SkipEq { x: u8, y: u8 },
+
+    /// 5XY2 - Store VX through VY in memory starting at I (XO-CHIP).
+    SaveRange { x: u8, y: u8 },
+
+    /// 5XY3 - Load VX through VY from memory starting at I (XO-CHIP).
+    LoadRange { x: u8, y: u8 },
+
+    /// 6XNN - VX = NN.
+    LoadImm { x: u8, nn: u8 },
+
+    /// 7XNN - VX += NN, VF is unaffected.
+    AddImm { x: u8, nn: u8 },
+
+    /// 8XY0 - VX = VY.
+    Load { x: u8, y: u8 },
+
+    /// 8XY1 - VX |= VY.
+    Or { x: u8, y: u8 },
+
+    /// 8XY2 - VX &= VY.
+    And { x: u8, y: u8 },
+
+    /// 8XY3 - VX ^= VY.
+    Xor { x: u8, y: u8 },
+
+    /// 8XY4 - VX += VY, VF = carry.
+    Add { x: u8, y: u8 },
+
+    /// 8XY5 - VX -= VY, VF = not borrow.
+    Sub { x: u8, y: u8 },
+
+    /// 8XY6 - VX >>= 1, VF = the bit shifted out.
+    Shr { x: u8, y: u8 },
+
+    /// 8XY7 - VX = VY - VX, VF = not borrow.
+    SubN { x: u8, y: u8 },
+
+    /// 8XYE - VX <<= 1, VF = the bit shifted out.
+    Shl { x: u8, y: u8 },
+
+    /// 9XY0 - Skip the next instruction if VX != VY.
+    SkipNe { x: u8, y: u8 },
+
+    /// ANNN - I = NNN.
+    LoadI(u16),
+
+    /// BNNN - Jump to address NNN + V0.
+    JumpV0(u16),
+
+    /// CXNN - VX = random byte & NN.
+    Rand { x: u8, nn: u8 },
+
+    /// DXYN - Draw an N-byte sprite from I at <VX,VY>. When N is 0, a
+    /// 16x16 sprite is drawn instead (SCHIP).
+    Draw { x: u8, y: u8, n: u8 },
+
+    /// EX9E - Skip the next instruction if the key in VX is down.
+    SkipKey { x: u8 },
+
+    /// EXA1 - Skip the next instruction if the key in VX is up.
+    SkipNotKey { x: u8 },
+
+    /// F000 NNNN - I = NNNN, a 4-byte instruction (XO-CHIP).
+    LoadILong(u16),
+
+    /// FN01 - Select the bitplanes N that are drawn to (XO-CHIP).
+    Plane(u8),
+
+    /// F002 - Load the 16-byte audio pattern buffer from I (XO-CHIP).
+    Audio,
+
+    /// FX07 - VX = DT.
+    LoadDelay { x: u8 },
+
+    /// FX0A - Wait for a key press and store it in VX.
+    WaitKey { x: u8 },
+
+    /// FX15 - DT = VX.
+    SetDelay { x: u8 },
+
+    /// FX18 - ST = VX.
+    SetSound { x: u8 },
+
+    /// FX1E - I += VX.
+    AddI { x: u8 },
+
+    /// FX29 - I = address of the low-res font sprite for the digit in VX.
+    Font { x: u8 },
+
+    /// FX30 - I = address of the high-res font sprite for the digit in VX (SCHIP).
+    BigFont { x: u8 },
+
+    /// FX33 - Store the BCD of VX at I, I+1 and I+2.
+    Bcd { x: u8 },
+
+    /// FX3A - Set the audio pattern playback pitch to VX (XO-CHIP).
+    Pitch { x: u8 },
+
+    /// FX55 - Store V0 through VX in memory starting at I.
+    Store { x: u8 },
+
+    /// FX65 - Load V0 through VX from memory starting at I.
+    Restore { x: u8 },
+
+    /// FX75 - Store V0 through VX in the R user flags, X < 8 (SCHIP).
+    StoreFlags { x: u8 },
+
+    /// FX85 - Load V0 through VX from the R user flags, X < 8 (SCHIP).
+    LoadFlags { x: u8 },
+}
+
+/// Returned when an opcode does not map to any known instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    /// The opcode that failed to decode.
+    pub opcode: u16,
+}
+
+impl Instruction {
+    /// Decodes a big-endian opcode into an instruction. The XO-CHIP long
+    /// load (F000) needs its second word and is decoded by `decode_long`.
+    pub fn decode(op: u16) -> Result<Instruction, DecodeError> {
+        let x = ((op >> 8) & 0xF) as u8;
+        let y = ((op >> 4) & 0xF) as u8;
+        let n = (op & 0xF) as u8;
+        let nn = (op & 0xFF) as u8;
+        let nnn = op & 0xFFF;
+
+        let inst = match op >> 12 {
+            0x0 => match op {
+                0x00E0 => Instruction::Cls,
+                0x00EE => Instruction::Ret,
+                0x00FB => Instruction::ScrollRight,
+                0x00FC => Instruction::ScrollLeft,
+                0x00FD => Instruction::Exit,
+                0x00FE => Instruction::LowRes,
+                0x00FF => Instruction::HighRes,
+                _ if op & 0xFFF0 == 0x00C0 => Instruction::ScrollDown(n),
+                _ if op & 0xFFF0 == 0x00D0 => Instruction::ScrollUp(n),
+                _ => Instruction::Sys(nnn),
+            },
+            0x1 => Instruction::Jump(nnn),
+            0x2 => Instruction::Call(nnn),
+            0x3 => Instruction::SkipEqImm { x, nn },
+            0x4 => Instruction::SkipNeImm { x, nn },
+            0x5 => match n {
+                0x0 => Instruction::SkipEq { x, y },
+                0x2 => Instruction::SaveRange { x, y },
+                0x3 => Instruction::LoadRange { x, y },
+                _ => return Err(DecodeError { opcode: op }),
+            },
+            0x6 => Instruction::LoadImm { x, nn },
+            0x7 => Instruction::AddImm { x, nn },
+            0x8 => match n {
+                0x0 => Instruction::Load { x, y },
+                0x1 => Instruction::Or { x, y },
+                0x2 => Instruction::And { x, y },
+                0x3 => Instruction::Xor { x, y },
+                0x4 => Instruction::Add { x, y },
+                0x5 => Instruction::Sub { x, y },
+                0x6 => Instruction::Shr { x, y },
+                0x7 => Instruction::SubN { x, y },
+                0xE => Instruction::Shl { x, y },
+                _ => return Err(DecodeError { opcode: op }),
+            },
+            0x9 if n == 0 => Instruction::SkipNe { x, y },
+            0xA => Instruction::LoadI(nnn),
+            0xB => Instruction::JumpV0(nnn),
+            0xC => Instruction::Rand { x, nn },
+            0xD => Instruction::Draw { x, y, n },
+            0xE => match nn {
+                0x9E => Instruction::SkipKey { x },
+                0xA1 => Instruction::SkipNotKey { x },
+                _ => return Err(DecodeError { opcode: op }),
+            },
+            0xF => match nn {
+                0x01 => Instruction::Plane(x),
+                0x02 if x == 0 => Instruction::Audio,
+                0x07 => Instruction::LoadDelay { x },
+                0x0A => Instruction::WaitKey { x },
+                0x15 => Instruction::SetDelay { x },
+                0x18 => Instruction::SetSound { x },
+                0x1E => Instruction::AddI { x },
+                0x29 => Instruction::Font { x },
+                0x30 => Instruction::BigFont { x },
+                0x33 => Instruction::Bcd { x },
+                0x3A => Instruction::Pitch { x },
+                0x55 => Instruction::Store { x },
+                0x65 => Instruction::Restore { x },
+                0x75 => Instruction::StoreFlags { x },
+                0x85 => Instruction::LoadFlags { x },
+                _ => return Err(DecodeError { opcode: op }),
+            },
+            _ => return Err(DecodeError { opcode: op }),
+        };
+
+        Ok(inst)
+    }
+
+    /// Decodes an opcode along with the word that follows it, which is
+    /// only consumed by the XO-CHIP long load. Any other opcode is
+    /// decoded the same as `decode`.
+    pub fn decode_long(op: u16, next: u16) -> Result<Instruction, DecodeError> {
+        if op == 0xF000 {
+            Ok(Instruction::LoadILong(next))
+        } else {
+            Instruction::decode(op)
+        }
+    }
+
+    /// The size of the instruction in bytes. This is 2 for every
+    /// instruction except the XO-CHIP long load, which is 4.
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Returns the big-endian bytes of the instruction, which is the
+    /// encoded opcode followed by the address for the long load.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let op = self.encode();
+        let mut bytes = vec![(op >> 8) as u8, op as u8];
+
+        if let Instruction::LoadILong(addr) = *self {
+            bytes.extend_from_slice(&[(addr >> 8) as u8, addr as u8]);
+        }
+
+        bytes
+    }
+
+    /// Encodes the instruction back into its big-endian opcode. For any
+    /// opcode that decodes successfully, `decode(op)?.encode() == op`.
+    /// The long load encodes to its first word, F000.
+    pub fn encode(&self) -> u16 {
+        let xy = |hi: u16, x: u8, y: u8, lo: u16| hi << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | lo;
+        let xnn = |hi: u16, x: u8, nn: u8| hi << 12 | (x as u16 & 0xF) << 8 | nn as u16;
+        let nnn = |hi: u16, addr: u16| hi << 12 | addr & 0xFFF;
+
+        match *self {
+            Instruction::Sys(addr) => nnn(0x0, addr),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | n as u16 & 0xF,
+            Instruction::ScrollUp(n) => 0x00D0 | n as u16 & 0xF,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump(addr) => nnn(0x1, addr),
+            Instruction::Call(addr) => nnn(0x2, addr),
+            Instruction::SkipEqImm { x, nn } => xnn(0x3, x, nn),
+            Instruction::SkipNeImm { x, nn } => xnn(0x4, x, nn),
+            Instruction::SkipEq { x, y } => xy(0x5, x, y, 0x0),
+            Instruction::SaveRange { x, y } => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange { x, y } => xy(0x5, x, y, 0x3),
+            Instruction::LoadImm { x, nn } => xnn(0x6, x, nn),
+            Instruction::AddImm { x, nn } => xnn(0x7, x, nn),
+            Instruction::Load { x, y } => xy(0x8, x, y, 0x0),
+            Instruction::Or { x, y } => xy(0x8, x, y, 0x1),
+            Instruction::And { x, y } => xy(0x8, x, y, 0x2),
+            Instruction::Xor { x, y } => xy(0x8, x, y, 0x3),
+            Instruction::Add { x, y } => xy(0x8, x, y, 0x4),
+            Instruction::Sub { x, y } => xy(0x8, x, y, 0x5),
+            Instruction::Shr { x, y } => xy(0x8, x, y, 0x6),
+            Instruction::SubN { x, y } => xy(0x8, x, y, 0x7),
+            Instruction::Shl { x, y } => xy(0x8, x, y, 0xE),
+            Instruction::SkipNe { x, y } => xy(0x9, x, y, 0x0),
+            Instruction::LoadI(addr) => nnn(0xA, addr),
+            Instruction::JumpV0(addr) => nnn(0xB, addr),
+            Instruction::Rand { x, nn } => xnn(0xC, x, nn),
+            Instruction::Draw { x, y, n } => xy(0xD, x, y, n as u16 & 0xF),
+            Instruction::SkipKey { x } => xnn(0xE, x, 0x9E),
+            Instruction::SkipNotKey { x } => xnn(0xE, x, 0xA1),
+            Instruction::LoadILong(_) => 0xF000,
+            Instruction::Plane(n) => xnn(0xF, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LoadDelay { x } => xnn(0xF, x, 0x07),
+            Instruction::WaitKey { x } => xnn(0xF, x, 0x0A),
+            Instruction::SetDelay { x } => xnn(0xF, x, 0x15),
+            Instruction::SetSound { x } => xnn(0xF, x, 0x18),
+            Instruction::AddI { x } => xnn(0xF, x, 0x1E),
+            Instruction::Font { x } => xnn(0xF, x, 0x29),
+            Instruction::BigFont { x } => xnn(0xF, x, 0x30),
+            Instruction::Bcd { x } => xnn(0xF, x, 0x33),
+            Instruction::Pitch { x } => xnn(0xF, x, 0x3A),
+            Instruction::Store { x } => xnn(0xF, x, 0x55),
+            Instruction::Restore { x } => xnn(0xF, x, 0x65),
+            Instruction::StoreFlags { x } => xnn(0xF, x, 0x75),
+            Instruction::LoadFlags { x } => xnn(0xF, x, 0x85),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown opcode {:04X}", self.opcode)
+    }
+}
+
+impl Error for DecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for op in 0..=0xFFFF {
+            match Instruction::decode(op) {
+                Ok(inst) => assert_eq!(inst.encode(), op, "{:?}", inst),
+                Err(err) => assert_eq!(err.opcode, op),
+            }
+        }
+    }
+
+    #[test]
+    fn every_opcode_round_trips_through_bytes() {
+        for op in 0..=0xFFFF {
+            if let Ok(inst) = Instruction::decode_long(op, 0x1234) {
+                let bytes = inst.to_bytes();
+
+                assert_eq!(bytes.len(), inst.size());
+                assert_eq!(bytes[..2], op.to_be_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn long_load_takes_the_next_word() {
+        let inst = Instruction::decode_long(0xF000, 0xBEEF).unwrap();
+
+        assert_eq!(inst, Instruction::LoadILong(0xBEEF));
+        assert_eq!(inst.encode(), 0xF000);
+        assert_eq!(inst.to_bytes(), vec![0xF0, 0x00, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_errors() {
+        for op in [0x5001, 0x800F, 0x9001, 0xE000, 0xF0FF] {
+            assert_eq!(Instruction::decode(op), Err(DecodeError { opcode: op }));
+        }
+    }
+}
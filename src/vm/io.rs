@@ -0,0 +1,302 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A snapshot of video memory handed to a `Display`.
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    /// Video memory for each bitplane, MSB first, `pitch` bytes per line.
+    pub planes: [&'a [u8]; 2],
+
+    /// Number of bytes per scan line.
+    pub pitch: usize,
+
+    /// Width of the display in pixels.
+    pub width: usize,
+
+    /// Height of the display in pixels.
+    pub height: usize,
+}
+
+/// A rectangle of pixels on the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// A change to a key on the 16-key pad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Down(u8),
+    Up(u8),
+}
+
+/// Shows frames produced by the VM.
+pub trait Display {
+    /// Presents a complete frame.
+    fn present(&mut self, frame: &Frame);
+
+    /// Presents a frame where only the pixels within a region changed
+    /// since the last one presented. By default the whole frame is
+    /// presented again.
+    fn present_region(&mut self, frame: &Frame, region: Region) {
+        let _ = region;
+        self.present(frame);
+    }
+}
+
+/// Plays the sound of the VM.
+pub trait AudioSink {
+    /// Turns the beeper on or off. Called whenever the sound timer
+    /// starts or stops.
+    fn beep(&mut self, on: bool);
+
+    /// The sample rate to generate PCM samples at, or None if the sink
+    /// only wants `beep`.
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+
+    /// Receives one frame's worth of mono PCM samples in -1.0..=1.0.
+    /// Only called when `sample_rate` returns a rate.
+    fn samples(&mut self, samples: &[f32]) {
+        let _ = samples;
+    }
+}
+
+/// Supplies the state of the 16-key pad.
+pub trait InputSource {
+    /// Returns which of the 16 keys are held down.
+    fn poll(&mut self) -> [bool; 16];
+
+    /// Returns the key presses and releases since the last call. By
+    /// default there are none, and only `poll` is used.
+    fn events(&mut self) -> Vec<KeyEvent> {
+        Vec::new()
+    }
+
+    /// True once the user has asked to stop.
+    fn quit(&self) -> bool {
+        false
+    }
+}
+
+/// A display that shows nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullDisplay;
+
+/// An audio sink that plays nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullAudio;
+
+/// An input source where no key is ever pressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullInput;
+
+/// An input source that presses keys at given frames, for running
+/// programs without anyone at the keyboard.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedInput {
+    /// The presses to make, in no particular order.
+    presses: Vec<Press>,
+
+    /// The frame the next poll is for, counting from 0.
+    frame: usize,
+}
+
+/// A key held down for a number of frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Press {
+    /// The frame the key goes down in, counting from 0.
+    pub frame: usize,
+
+    /// The key, 0-F.
+    pub key: u8,
+
+    /// How many frames the key is held for.
+    pub frames: usize,
+}
+
+/// The RGB color shown for each color index of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    /// The color for each index, 0-3.
+    colors: [[u8; 3]; 4],
+}
+
+/// Returned when a palette can't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidPalette(pub String);
+
+/// Characters used for each color index when rendering a frame as text.
+const ASCII: [char; 4] = ['.', '#', '+', '@'];
+
+impl Frame<'_> {
+    /// Returns the color index (0-3) of a pixel, one bit per plane.
+    pub fn pixel(&self, x: usize, y: usize) -> u8 {
+        let addr = y * self.pitch + x / 8;
+        let bit = 0x80 >> (x % 8);
+
+        self.planes.iter()
+            .enumerate()
+            .fold(0, |c, (n, p)| if p[addr] & bit != 0 { c | 1 << n } else { c })
+    }
+
+    /// Renders the frame as text, one line per row, with `.` for pixels
+    /// that are off, `#` for plane 0, `+` for plane 1 and `@` for both.
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::with_capacity((self.width + 1) * self.height);
+
+        for y in 0..self.height {
+            out.extend((0..self.width).map(|x| ASCII[self.pixel(x, y) as usize]));
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Renders the frame as a plain (P1) portable bitmap. Any pixel that
+    /// is on in either plane is black.
+    pub fn to_pbm(&self) -> String {
+        let mut out = format!("P1\n{} {}\n", self.width, self.height);
+
+        for y in 0..self.height {
+            let row: Vec<&str> = (0..self.width)
+                .map(|x| if self.pixel(x, y) != 0 { "1" } else { "0" })
+                .collect();
+
+            out.push_str(&row.join(" "));
+            out.push('\n');
+        }
+
+        out
+    }
+
+    /// Renders the frame as 24-bit RGB, three bytes per pixel, with each
+    /// pixel drawn as a `scale` sized square.
+    pub fn to_rgb(&self, palette: &Palette, scale: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.width * self.height * scale * scale * 3);
+
+        for y in 0..self.height {
+            let row: Vec<u8> = (0..self.width)
+                .flat_map(|x| palette.color(self.pixel(x, y)).repeat(scale))
+                .collect();
+
+            for _ in 0..scale {
+                out.extend_from_slice(&row);
+            }
+        }
+
+        out
+    }
+}
+
+impl Palette {
+    /// Black and white, with yellow for plane 1 and red for both planes.
+    pub const DEFAULT: Palette = Palette { colors: [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xFF, 0xFF, 0x55], [0xFF, 0x55, 0x55]] };
+
+    /// Returns the color of a color index.
+    pub fn color(&self, index: u8) -> [u8; 3] {
+        self.colors[index as usize & 3]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::DEFAULT
+    }
+}
+
+impl FromStr for Palette {
+    type Err = InvalidPalette;
+
+    /// Parses up to 4 comma-separated hex colors, such as
+    /// "000000,ffcc00", which replace the default colors in order. Each
+    /// color may start with `#`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut palette = Palette::DEFAULT;
+        let colors: Vec<&str> = s.split(',').map(|c| c.trim().trim_start_matches('#')).collect();
+
+        if colors.len() > 4 {
+            return Err(InvalidPalette(s.to_string()));
+        }
+
+        for (color, hex) in palette.colors.iter_mut().zip(colors) {
+            let rgb = match u32::from_str_radix(hex, 16) {
+                Ok(rgb) if hex.len() == 6 => rgb,
+                _ => return Err(InvalidPalette(s.to_string())),
+            };
+
+            *color = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+        }
+
+        Ok(palette)
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, [r, g, b]) in self.colors.iter().enumerate() {
+            if n > 0 {
+                write!(f, ",")?;
+            }
+
+            write!(f, "{:02x}{:02x}{:02x}", r, g, b)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for InvalidPalette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid palette '{}', expected up to 4 comma-separated colors like 000000,ffffff", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPalette {}
+
+impl ScriptedInput {
+    /// Creates an input source that makes the given presses.
+    pub fn new(presses: Vec<Press>) -> ScriptedInput {
+        ScriptedInput { presses, frame: 0 }
+    }
+
+    /// The number of frames polled so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+}
+
+impl Display for NullDisplay {
+    fn present(&mut self, _: &Frame) {}
+}
+
+impl AudioSink for NullAudio {
+    fn beep(&mut self, _: bool) {}
+}
+
+impl InputSource for NullInput {
+    fn poll(&mut self) -> [bool; 16] {
+        [false; 16]
+    }
+}
+
+impl InputSource for ScriptedInput {
+    /// Returns the keys held down in the current frame and moves on to
+    /// the next one.
+    fn poll(&mut self) -> [bool; 16] {
+        let mut keys = [false; 16];
+
+        for press in self.presses.iter() {
+            if (press.frame..press.frame + press.frames).contains(&self.frame) {
+                keys[press.key as usize & 0xF] = true;
+            }
+        }
+
+        self.frame += 1;
+        keys
+    }
+}
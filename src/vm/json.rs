@@ -0,0 +1,457 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A JSON value, as used by the debug adapter protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+/// Returned when text does not parse as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonError {
+    /// Byte offset of the error in the text.
+    pub offset: usize,
+
+    /// What was expected there.
+    pub message: &'static str,
+}
+
+/// How deeply arrays and objects may nest. Each level is a recursive
+/// call, so without a limit a long run of `[` overflows the stack.
+pub const MAX_DEPTH: usize = 128;
+
+/// A recursive descent parser over the bytes of a JSON document.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+
+    /// The number of arrays and objects the parser is inside.
+    depth: usize,
+}
+
+impl Json {
+    /// Creates an object from key/value pairs.
+    pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Returns the value of a key, if this is an object that has it.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    /// Returns the string, if this is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the number, if this is one.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Json::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Returns the number as an integer, if this is one without a
+    /// fractional part.
+    pub fn as_i64(&self) -> Option<i64> {
+        self.as_f64().filter(|n| n.fract() == 0.0).map(|n| n as i64)
+    }
+
+    /// Returns the boolean, if this is one.
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Json::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    /// Returns the elements, if this is an array.
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> JsonError {
+        JsonError { offset: self.pos, message }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes a literal such as `true`.
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, JsonError> {
+        if self.text[self.pos..].starts_with(word) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.error("expected a value"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.skip_whitespace();
+
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') | Some(b'{') if self.depth == MAX_DEPTH => Err(self.error("nested too deeply")),
+            Some(b'[') => {
+                self.depth += 1;
+                let array = self.array();
+                self.depth -= 1;
+                array
+            },
+            Some(b'{') => {
+                self.depth += 1;
+                let object = self.object();
+                self.depth -= 1;
+                object
+            },
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') = self.peek() {
+            self.pos += 1;
+        }
+
+        self.text[start..self.pos].parse().map(Json::Number).map_err(|_| JsonError {
+            offset: start,
+            message: "invalid number",
+        })
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        let mut s = String::new();
+
+        self.pos += 1;
+
+        loop {
+            let c = self.text[self.pos..].chars().next().ok_or_else(|| self.error("unterminated string"))?;
+
+            self.pos += c.len_utf8();
+
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+
+                    self.pos += 1;
+
+                    match escape {
+                        b'"' => s.push('"'),
+                        b'\\' => s.push('\\'),
+                        b'/' => s.push('/'),
+                        b'b' => s.push('\x08'),
+                        b'f' => s.push('\x0c'),
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'u' => s.push(self.unicode()?),
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                },
+                _ => s.push(c),
+            }
+        }
+    }
+
+    /// Parses the hex digits of a `\u` escape, and the low half of a
+    /// surrogate pair if there is one.
+    fn unicode(&mut self) -> Result<char, JsonError> {
+        let high = self.hex4()?;
+
+        if (0xD800..0xDC00).contains(&high) && self.text[self.pos..].starts_with("\\u") {
+            self.pos += 2;
+
+            let low = self.hex4()?;
+
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("invalid surrogate pair"));
+            }
+
+            let c = 0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00);
+
+            return char::from_u32(c).ok_or_else(|| self.error("invalid surrogate pair"));
+        }
+
+        Ok(char::from_u32(high).unwrap_or('\u{FFFD}'))
+    }
+
+    fn hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.text.get(self.pos..self.pos + 4).ok_or_else(|| self.error("invalid escape"))?;
+        let n = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid escape"))?;
+
+        self.pos += 4;
+        Ok(n)
+    }
+
+    fn array(&mut self) -> Result<Json, JsonError> {
+        let mut items = Vec::new();
+
+        self.pos += 1;
+        self.skip_whitespace();
+
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                },
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, JsonError> {
+        let mut map = BTreeMap::new();
+
+        self.pos += 1;
+        self.skip_whitespace();
+
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(map));
+        }
+
+        loop {
+            self.skip_whitespace();
+
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected a key"));
+            }
+
+            let key = self.string()?;
+
+            self.skip_whitespace();
+
+            if self.peek() != Some(b':') {
+                return Err(self.error("expected ':'"));
+            }
+
+            self.pos += 1;
+            map.insert(key, self.value()?);
+            self.skip_whitespace();
+
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(map));
+                },
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+impl FromStr for Json {
+    type Err = JsonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { text: s, pos: 0, depth: 0 };
+        let value = parser.value()?;
+
+        parser.skip_whitespace();
+
+        if parser.pos == s.len() {
+            Ok(value)
+        } else {
+            Err(parser.error("unexpected text after the value"))
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(n: i64) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Self {
+        Json::Array(items)
+    }
+}
+
+/// Writes a string with quotes, escaping what JSON requires.
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    /// Writes the value as compact JSON.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                write!(f, "[")?;
+
+                for (n, item) in items.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, "{}", item)?;
+                }
+
+                write!(f, "]")
+            },
+            Json::Object(map) => {
+                write!(f, "{{")?;
+
+                for (n, (key, value)) in map.iter().enumerate() {
+                    if n > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid JSON at offset {}: {}", self.offset, self.message)
+    }
+}
+
+impl Error for JsonError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> &'static str {
+        text.parse::<Json>().unwrap_err().message
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let json: Json = r#""quote \" slash \\ \/ \b\f\n\r\t \u0041\u00e9""#.parse().unwrap();
+
+        assert_eq!(json, Json::from("quote \" slash \\ / \x08\x0c\n\r\t A\u{e9}"));
+        assert_eq!(json.to_string().parse::<Json>().unwrap(), json);
+        assert_eq!(Json::from("\x01").to_string(), r#""\u0001""#);
+    }
+
+    #[test]
+    fn surrogate_pairs_make_one_character() {
+        assert_eq!(r#""\ud83d\ude00""#.parse(), Ok(Json::from("\u{1F600}")));
+        assert_eq!(r#""\ud83d""#.parse(), Ok(Json::from("\u{FFFD}")));
+        assert_eq!(error(r#""\ud83d\u0041""#), "invalid surrogate pair");
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        assert_eq!(error(""), "expected a value");
+        assert_eq!(error("nul"), "expected a value");
+        assert_eq!(error(r#""abc"#), "unterminated string");
+        assert_eq!(error(r#""\q""#), "invalid escape");
+        assert_eq!(error(r#""\u12""#), "invalid escape");
+        assert_eq!(error("[1,]"), "expected a value");
+        assert_eq!(error("[1 2]"), "expected ',' or ']'");
+        assert_eq!(error(r#"{"a" 1}"#), "expected ':'");
+        assert_eq!(error("{1: 2}"), "expected a key");
+        assert_eq!(error("1.2.3"), "invalid number");
+        assert_eq!(error("{} {}"), "unexpected text after the value");
+    }
+
+    #[test]
+    fn nesting_is_limited() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+
+        assert!(nested(MAX_DEPTH).parse::<Json>().is_ok());
+        assert_eq!(error(&nested(MAX_DEPTH + 1)), "nested too deeply");
+        assert_eq!("[".repeat(100_000).parse::<Json>().unwrap_err().offset, MAX_DEPTH);
+    }
+}
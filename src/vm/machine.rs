@@ -0,0 +1,361 @@
+use crate::vm::error::ChipperError;
+use crate::vm::io::{AudioSink, Display, InputSource, KeyEvent, Region};
+use crate::vm::movie::{Movie, MovieError};
+use crate::vm::platform::Platform;
+use crate::vm::state::StateError;
+use crate::vm::vm::{FrameResult, VM};
+
+/// Audio pattern played on platforms without the XO-CHIP pattern
+/// buffer: a square wave, which at the default pitch is 250 Hz.
+const BEEP: [u8; 16] = [
+    0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+    0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+];
+
+/// Volume of generated PCM samples.
+const VOLUME: f32 = 0.25;
+
+/// A VM wired up to a display, an audio sink and an input source. Each
+/// frame the input is polled, the VM is run and any changes to video and
+/// sound are handed to the frontend.
+pub struct Machine {
+    /// The VM being run.
+    vm: VM,
+
+    /// Where frames are presented.
+    display: Box<dyn Display>,
+
+    /// Where sound is played.
+    audio: Box<dyn AudioSink>,
+
+    /// Where key presses come from.
+    input: Box<dyn InputSource>,
+
+    /// Video memory of each plane as of the last presented frame, used
+    /// to find the region that changed.
+    last: [Vec<u8>; 2],
+
+    /// Resolution of the last presented frame.
+    last_resolution: (usize, usize),
+
+    /// True while the beeper is on.
+    beeping: bool,
+
+    /// Position within the audio pattern buffer, in bits.
+    phase: f64,
+
+    /// The movie being recorded or played back, if any.
+    movie: MovieMode,
+}
+
+/// What the machine is doing with a movie.
+#[derive(Debug)]
+enum MovieMode {
+    /// Keys come from the input source and nothing is recorded.
+    Off,
+
+    /// Keys come from the input source and are recorded each frame.
+    Record(Movie),
+
+    /// Keys come from the movie, starting at `frame`.
+    Playback { movie: Movie, frame: usize },
+}
+
+impl Machine {
+    pub fn new(vm: VM, display: Box<dyn Display>, audio: Box<dyn AudioSink>, input: Box<dyn InputSource>) -> Machine {
+        Machine {
+            vm,
+            display,
+            audio,
+            input,
+            last: [Vec::new(), Vec::new()],
+            last_resolution: (0, 0),
+            beeping: false,
+            phase: 0.0,
+            movie: MovieMode::Off,
+        }
+    }
+
+    /// Returns the VM being run.
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    /// Returns the VM being run.
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+
+    /// Consumes the machine, returning the VM.
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    /// True once the input source has asked to stop.
+    pub fn quit(&self) -> bool {
+        self.input.quit()
+    }
+
+    /// Resets the VM and presents its blank display.
+    pub fn reset(&mut self) {
+        self.vm.reset();
+        self.refresh();
+    }
+
+    /// Restores a state saved with `VM::save_state` and presents its
+    /// display.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.vm.load_state(data)?;
+        self.refresh();
+
+        Ok(())
+    }
+
+    /// Resets the VM and starts recording a movie of the input given to
+    /// each frame. Any movie being recorded or played is discarded.
+    pub fn record_movie(&mut self) {
+        self.reset();
+        self.movie = MovieMode::Record(Movie::new(&self.vm));
+    }
+
+    /// Resets the VM to the state a movie was recorded from and starts
+    /// playing it back. Input is ignored until the movie ends.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        movie.prepare(&mut self.vm)?;
+        self.refresh();
+        self.movie = MovieMode::Playback { movie, frame: 0 };
+
+        Ok(())
+    }
+
+    /// Stops recording or playing back, returning the movie.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match std::mem::replace(&mut self.movie, MovieMode::Off) {
+            MovieMode::Off => None,
+            MovieMode::Record(movie) => Some(movie),
+            MovieMode::Playback { movie, .. } => Some(movie),
+        }
+    }
+
+    /// True while a movie is being recorded.
+    pub fn recording(&self) -> bool {
+        matches!(self.movie, MovieMode::Record(_))
+    }
+
+    /// True while a movie is being played back.
+    pub fn playing(&self) -> bool {
+        matches!(self.movie, MovieMode::Playback { .. })
+    }
+
+    /// Polls input, runs the VM for a single frame and presents the
+    /// results to the display and audio sink. During playback the keys
+    /// come from the movie instead, and the state is checked against the
+    /// recorded hashes; a mismatch is reported as `Desync`.
+    pub fn run_frame(&mut self) -> Result<FrameResult, ChipperError> {
+        let mut keys = self.input.poll();
+
+        // Presses that were released before the poll still count for a frame.
+        for event in self.input.events() {
+            if let KeyEvent::Down(key) = event {
+                keys[key as usize & 0xF] = true;
+            }
+        }
+
+        if let MovieMode::Playback { movie, frame } = &self.movie {
+            match movie.keys(*frame) {
+                Some(recorded) => keys = recorded,
+                None => self.movie = MovieMode::Off,
+            }
+        }
+
+        self.vm.set_keys(keys);
+
+        let result = self.vm.run_frame()?;
+
+        match &mut self.movie {
+            MovieMode::Off => {},
+            MovieMode::Record(movie) => movie.record(keys, &self.vm),
+            MovieMode::Playback { movie, frame } => {
+                *frame += 1;
+
+                if let Some(expected) = movie.hash_after(*frame) {
+                    let actual = self.vm.state_hash();
+
+                    if actual != expected {
+                        return Err(ChipperError::Desync { frame: *frame, expected, actual });
+                    }
+                }
+            },
+        }
+
+        // The first frame is always presented, even when it's blank.
+        if result.video_changed || self.last_resolution == (0, 0) {
+            self.present();
+        }
+
+        self.play(result.sound);
+
+        Ok(result)
+    }
+
+    /// Presents the whole frame to the display, regardless of whether
+    /// anything changed.
+    pub fn refresh(&mut self) {
+        self.last_resolution = (0, 0);
+        self.present();
+    }
+
+    /// Presents video memory to the display, limited to the region that
+    /// changed since the last frame presented.
+    fn present(&mut self) {
+        let frame = self.vm.frame();
+        let size = frame.pitch * frame.height;
+        let resolution = (frame.width, frame.height);
+
+        if resolution != self.last_resolution {
+            self.display.present(&frame);
+        } else if let Some(region) = self.changed(&frame.planes, frame.pitch, size) {
+            self.display.present_region(&frame, region);
+        }
+
+        for (last, plane) in self.last.iter_mut().zip(frame.planes.iter()) {
+            last.clear();
+            last.extend_from_slice(&plane[..size]);
+        }
+
+        self.last_resolution = resolution;
+    }
+
+    /// Returns the bounding box of every byte that differs from the last
+    /// frame, or None if nothing did.
+    fn changed(&self, planes: &[&[u8]; 2], pitch: usize, size: usize) -> Option<Region> {
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+
+        for (last, plane) in self.last.iter().zip(planes.iter()) {
+            for (addr, (a, b)) in last.iter().zip(plane[..size].iter()).enumerate() {
+                if a == b {
+                    continue;
+                }
+
+                let (col, row) = (addr % pitch, addr / pitch);
+
+                bounds = Some(match bounds {
+                    None => (col, row, col, row),
+                    Some((l, t, r, b)) => (l.min(col), t.min(row), r.max(col), b.max(row)),
+                });
+            }
+        }
+
+        bounds.map(|(l, t, r, b)| Region {
+            x: l * 8,
+            y: t,
+            width: (r - l + 1) * 8,
+            height: b - t + 1,
+        })
+    }
+
+    /// Turns the beeper on or off, and generates a frame of PCM samples
+    /// if the audio sink wants them.
+    fn play(&mut self, sound: bool) {
+        if sound != self.beeping {
+            self.audio.beep(sound);
+            self.beeping = sound;
+        }
+
+        let rate = match self.audio.sample_rate() {
+            Some(rate) => rate,
+            None => return,
+        };
+
+        let (pattern, pitch) = self.vm.audio_pattern();
+        let pattern = if self.vm.platform() == Platform::XoChip { *pattern } else { BEEP };
+        let step = 4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0) / rate as f64;
+        let mut samples = vec![0.0; rate as usize / 60];
+
+        if sound {
+            for sample in samples.iter_mut() {
+                let bit = self.phase as usize % 128;
+                let on = pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+
+                *sample = if on { VOLUME } else { -VOLUME };
+                self.phase = (self.phase + step) % 128.0;
+            }
+        } else {
+            self.phase = 0.0;
+        }
+
+        self.audio.samples(&samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use super::*;
+    use crate::vm::io::{Frame, NullAudio, NullInput};
+    use crate::vm::vm::load_rom;
+
+    /// Counts the frames presented to it.
+    struct Counter(Rc<Cell<usize>>);
+
+    impl Display for Counter {
+        fn present(&mut self, _: &Frame) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    /// Returns a machine running a program that clears the screen and
+    /// waits for a key, and a count of the frames presented.
+    fn machine() -> (Machine, Rc<Cell<usize>>) {
+        let vm = load_rom(vec![0x00, 0xE0, 0xF0, 0x0A], Platform::Chip8).unwrap();
+        let presented = Rc::new(Cell::new(0));
+        let display = Counter(presented.clone());
+
+        (Machine::new(vm, Box::new(display), Box::new(NullAudio), Box::new(NullInput)), presented)
+    }
+
+    #[test]
+    fn first_frame_is_presented() {
+        let (mut machine, presented) = machine();
+
+        machine.run_frame().unwrap();
+        assert_eq!(presented.get(), 1);
+
+        machine.run_frame().unwrap();
+        assert_eq!(presented.get(), 1);
+    }
+
+    #[test]
+    fn first_frame_is_presented_after_frames_without_a_display() {
+        let (machine, _) = machine();
+        let mut vm = machine.into_vm();
+
+        vm.run_frame().unwrap();
+
+        let presented = Rc::new(Cell::new(0));
+        let display = Counter(presented.clone());
+        let mut machine = Machine::new(vm, Box::new(display), Box::new(NullAudio), Box::new(NullInput));
+
+        machine.run_frame().unwrap();
+        assert_eq!(presented.get(), 1);
+    }
+
+    #[test]
+    fn reset_and_load_state_are_presented() {
+        let (mut machine, presented) = machine();
+
+        machine.run_frame().unwrap();
+
+        let state = machine.vm().save_state();
+
+        machine.reset();
+        assert_eq!(presented.get(), 2);
+
+        machine.load_state(&state).unwrap();
+        assert_eq!(presented.get(), 3);
+
+        machine.run_frame().unwrap();
+        assert_eq!(presented.get(), 3);
+    }
+}
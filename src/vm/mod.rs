@@ -1,2 +1,24 @@
-mod vm;
-mod rom;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod vm;
+pub mod rom;
+pub mod asm;
+pub mod clock;
+pub mod dap;
+pub mod debug;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
+pub mod instruction;
+pub mod io;
+pub mod json;
+pub mod machine;
+pub mod movie;
+pub mod platform;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod state;
+#[cfg(test)]
+mod test;
+pub mod timer;
+pub mod trap;
@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::fmt;
+use crate::vm::platform::Platform;
+use crate::vm::quirks::Quirks;
+use crate::vm::state::{self, crc32, Reader, StateError, Writer};
+use crate::vm::vm::VM;
+
+/// Every movie begins with these bytes.
+pub const MAGIC: [u8; 4] = *b"CH8M";
+
+/// The version of the movie format written by `to_bytes`.
+pub const VERSION: u16 = 1;
+
+/// How often, in frames, a hash of the VM state is recorded by default.
+pub const HASH_INTERVAL: u32 = 60;
+
+/// A recording of the input given to a program, one frame at a time,
+/// along with everything needed to replay it exactly: the ROM, platform,
+/// quirks and random seed it started from. Hashes of the VM state are
+/// recorded periodically so playback can detect when it desyncs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    /// CRC-32 of the program the movie was recorded with.
+    pub rom_hash: u32,
+
+    /// The platform the program ran on.
+    pub platform: Platform,
+
+    /// The quirks the program ran with.
+    pub quirks: Quirks,
+
+    /// The seed of the random number generator at the first frame.
+    pub seed: u32,
+
+    /// The number of instructions executed per frame.
+    pub cycles_per_frame: i64,
+
+    /// How often, in frames, a state hash is recorded.
+    pub hash_interval: u32,
+
+    /// The keys held down in each frame, one bit per key.
+    pub frames: Vec<u16>,
+
+    /// The frame number and state hash after that frame, every
+    /// `hash_interval` frames.
+    pub hashes: Vec<(u32, u32)>,
+}
+
+/// Reasons a movie can't be read or played back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovieError {
+    /// The movie file is malformed.
+    Format(StateError),
+
+    /// The movie was recorded with a different program.
+    RomMismatch { expected: u32, actual: u32 },
+}
+
+impl Movie {
+    /// Starts a movie from the current state of a VM. The VM should be
+    /// freshly reset so playback can reproduce it.
+    pub fn new(vm: &VM) -> Movie {
+        Movie {
+            rom_hash: crc32(vm.program()),
+            platform: vm.platform(),
+            quirks: *vm.quirks(),
+            seed: vm.seed(),
+            cycles_per_frame: vm.cycles_per_frame(),
+            hash_interval: HASH_INTERVAL,
+            frames: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// The number of frames recorded.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// True if no frames have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Records the keys given to a frame, and the state hash after it if
+    /// the frame falls on the hash interval.
+    pub fn record(&mut self, keys: [bool; 16], vm: &VM) {
+        self.frames.push(pack_keys(keys));
+
+        let frame = self.frames.len() as u32;
+
+        if frame.is_multiple_of(self.hash_interval) {
+            self.hashes.push((frame, vm.state_hash()));
+        }
+    }
+
+    /// Returns the keys held down in a frame, counting from 0.
+    pub fn keys(&self, frame: usize) -> Option<[bool; 16]> {
+        self.frames.get(frame).map(|&bits| unpack_keys(bits))
+    }
+
+    /// Returns the state hash recorded after a number of frames, if one was.
+    pub fn hash_after(&self, frames: usize) -> Option<u32> {
+        self.hashes.iter()
+            .find(|&&(frame, _)| frame as usize == frames)
+            .map(|&(_, hash)| hash)
+    }
+
+    /// Configures and resets a VM to the state the movie started from.
+    /// The VM must have the same program loaded on the same platform, and
+    /// the same kind of random number generator.
+    pub fn prepare(&self, vm: &mut VM) -> Result<(), MovieError> {
+        let actual = crc32(vm.program());
+
+        if actual != self.rom_hash || vm.platform() != self.platform {
+            return Err(MovieError::RomMismatch { expected: self.rom_hash, actual });
+        }
+
+        vm.set_quirks(self.quirks);
+        vm.set_cycles_per_frame(self.cycles_per_frame);
+        vm.reset();
+        vm.set_seed(self.seed);
+
+        Ok(())
+    }
+
+    /// Serializes the movie, framed by a magic header, format version
+    /// and a trailing CRC-32.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+
+        w.bytes.extend_from_slice(&MAGIC);
+        w.u16(VERSION);
+        w.u32(self.rom_hash);
+        w.u8(Platform::ALL.iter().position(|&p| p == self.platform).unwrap_or(0) as u8);
+        state::write_quirks(&mut w, &self.quirks);
+        w.u32(self.seed);
+        w.i64(self.cycles_per_frame);
+        w.u32(self.hash_interval);
+        w.u32(self.frames.len() as u32);
+
+        for &keys in self.frames.iter() {
+            w.u16(keys);
+        }
+
+        w.u32(self.hashes.len() as u32);
+
+        for &(frame, hash) in self.hashes.iter() {
+            w.u32(frame);
+            w.u32(hash);
+        }
+
+        let crc = crc32(&w.bytes);
+        w.u32(crc);
+        w.bytes
+    }
+
+    /// Reads a movie written by `to_bytes`.
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if data.len() < MAGIC.len() + 6 || data[..MAGIC.len()] != MAGIC {
+            return Err(MovieError::Format(StateError::BadMagic));
+        }
+
+        let (body, tail) = data.split_at(data.len() - 4);
+        let expected = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]);
+        let actual = crc32(body);
+        let mut r = Reader::new(&body[MAGIC.len()..]);
+        let version = r.u16()?;
+
+        if version != VERSION {
+            return Err(MovieError::Format(StateError::UnsupportedVersion(version)));
+        }
+
+        if expected != actual {
+            return Err(MovieError::Format(StateError::ChecksumMismatch { expected, actual }));
+        }
+
+        let mut movie = Movie {
+            rom_hash: r.u32()?,
+            platform: *Platform::ALL.get(r.u8()? as usize).ok_or(StateError::Invalid("platform"))?,
+            quirks: state::read_quirks(&mut r)?,
+            seed: r.u32()?,
+            cycles_per_frame: r.i64()?,
+            hash_interval: r.u32()?,
+            frames: Vec::new(),
+            hashes: Vec::new(),
+        };
+
+        for _ in 0..r.u32()? {
+            movie.frames.push(r.u16()?);
+        }
+
+        for _ in 0..r.u32()? {
+            movie.hashes.push((r.u32()?, r.u32()?));
+        }
+
+        if !r.is_empty() {
+            return Err(MovieError::Format(StateError::Invalid("trailing data")));
+        }
+
+        Ok(movie)
+    }
+}
+
+/// Packs the state of the 16 keys into bits.
+fn pack_keys(keys: [bool; 16]) -> u16 {
+    keys.iter().enumerate().fold(0, |bits, (n, &down)| bits | (down as u16) << n)
+}
+
+/// Unpacks bits into the state of the 16 keys.
+fn unpack_keys(bits: u16) -> [bool; 16] {
+    let mut keys = [false; 16];
+
+    for (n, key) in keys.iter_mut().enumerate() {
+        *key = bits & (1 << n) != 0;
+    }
+
+    keys
+}
+
+impl From<StateError> for MovieError {
+    fn from(err: StateError) -> Self {
+        MovieError::Format(err)
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Format(err) => write!(f, "Bad movie: {}", err),
+            MovieError::RomMismatch { expected, actual } => {
+                write!(f, "Movie was recorded with ROM {:08X}, but {:08X} is loaded", expected, actual)
+            },
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::error::ChipperError;
+    use crate::vm::io::{NullAudio, NullDisplay, NullInput, Press, ScriptedInput};
+    use crate::vm::machine::Machine;
+    use crate::vm::vm::load_rom;
+
+    /// Counts frames, and picks a random number in each frame key 5 is
+    /// held down.
+    const PROGRAM: [u8; 10] = [0x63, 0x05, 0xE3, 0xA1, 0xC4, 0xFF, 0x75, 0x01, 0x12, 0x02];
+
+    fn machine(input: ScriptedInput) -> Machine {
+        let vm = load_rom(PROGRAM.to_vec(), Platform::Chip8).unwrap();
+
+        Machine::new(vm, Box::new(NullDisplay), Box::new(NullAudio), Box::new(input))
+    }
+
+    /// Records 200 frames of presses of key 5 and returns the movie and
+    /// the final state hash.
+    fn record() -> (Movie, u32) {
+        let presses = vec![
+            Press { frame: 10, key: 5, frames: 3 },
+            Press { frame: 70, key: 5, frames: 40 },
+            Press { frame: 150, key: 2, frames: 5 },
+        ];
+        let mut machine = machine(ScriptedInput::new(presses));
+
+        machine.vm_mut().set_seed(1234);
+        machine.record_movie();
+
+        for _ in 0..200 {
+            machine.run_frame().unwrap();
+        }
+
+        (machine.stop_movie().unwrap(), machine.vm().state_hash())
+    }
+
+    #[test]
+    fn playback_reproduces_the_recording() {
+        let (movie, hash) = record();
+        let mut machine = machine(ScriptedInput::default());
+
+        assert_eq!(movie.len(), 200);
+        assert_eq!(movie.seed, 1234);
+
+        // The movie's seed replaces whatever the VM had.
+        machine.vm_mut().set_seed(99);
+        machine.play_movie(Movie::from_bytes(&movie.to_bytes()).unwrap()).unwrap();
+
+        for _ in 0..200 {
+            machine.run_frame().unwrap();
+        }
+
+        assert_eq!(machine.vm().state_hash(), hash);
+    }
+
+    #[test]
+    fn desync_is_detected() {
+        let (mut movie, _) = record();
+        let mut machine = machine(ScriptedInput::default());
+
+        // Hold key 5 one frame longer than was recorded.
+        movie.frames[13] |= 1 << 5;
+        machine.play_movie(movie).unwrap();
+
+        let err = (0..200).find_map(|_| machine.run_frame().err());
+
+        assert!(matches!(err, Some(ChipperError::Desync { frame: 60, .. })));
+    }
+
+    #[test]
+    fn playback_needs_the_same_rom() {
+        let (movie, _) = record();
+        let vm = load_rom(vec![0x12, 0x00], Platform::Chip8).unwrap();
+        let mut machine = Machine::new(vm, Box::new(NullDisplay), Box::new(NullAudio), Box::new(NullInput));
+
+        assert!(matches!(machine.play_movie(movie), Err(MovieError::RomMismatch { .. })));
+    }
+
+    #[test]
+    fn corrupt_movies_are_rejected() {
+        let (movie, _) = record();
+        let mut bytes = movie.to_bytes();
+
+        bytes[20] ^= 0x01;
+
+        assert!(matches!(Movie::from_bytes(&bytes), Err(MovieError::Format(StateError::ChecksumMismatch { .. }))));
+        assert!(matches!(Movie::from_bytes(b"CH8S"), Err(MovieError::Format(StateError::BadMagic))));
+    }
+}
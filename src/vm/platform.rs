@@ -0,0 +1,230 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::vm::instruction::Instruction;
+use crate::vm::quirks::Quirks;
+
+/// The machines and interpreters that CHIP-8 programs were written for.
+/// Each one fixes where programs are loaded, how large they may be, the
+/// display geometry, stack depth, speed and quirks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Platform {
+    /// The original CHIP-8 interpreter on the RCA COSMAC VIP.
+    #[default]
+    Chip8,
+
+    /// The two-page HiRes CHIP-8 variant for the VIP, with a 64x64 display.
+    HiResChip8,
+
+    /// CHIP-48 on the HP-48 calculators.
+    Chip48,
+
+    /// SUPER-CHIP 1.1 on the HP-48, with a 128x64 high-res mode.
+    SuperChip,
+
+    /// XO-CHIP, with 64 KiB of memory, bitplanes and audio patterns.
+    XoChip,
+
+    /// The ETI-660 learning computer, with a 64x48 display.
+    Eti660,
+}
+
+/// Returned when a platform name isn't recognized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPlatform(pub String);
+
+impl Platform {
+    /// Every platform, in order.
+    pub const ALL: [Platform; 6] = [
+        Platform::Chip8,
+        Platform::HiResChip8,
+        Platform::Chip48,
+        Platform::SuperChip,
+        Platform::XoChip,
+        Platform::Eti660,
+    ];
+
+    /// The short name of the platform, as accepted by `from_str`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip-8",
+            Platform::HiResChip8 => "hires-chip-8",
+            Platform::Chip48 => "chip-48",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xo-chip",
+            Platform::Eti660 => "eti-660",
+        }
+    }
+
+    /// Size of addressable memory in bytes.
+    pub fn memory_size(&self) -> usize {
+        match self {
+            Platform::XoChip => 0x10000,
+            _ => 0x1000,
+        }
+    }
+
+    /// Address programs are loaded at and begin executing from.
+    pub fn load_address(&self) -> usize {
+        match self {
+            Platform::Eti660 => 0x600,
+            _ => 0x200,
+        }
+    }
+
+    /// The largest program that can be loaded. On the VIP and ETI-660 the
+    /// top of memory is reserved for the stack, interpreter work area and
+    /// display refresh.
+    pub fn max_program_size(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::HiResChip8 | Platform::Eti660 => 0xEA0 - self.load_address(),
+            Platform::Chip48 | Platform::SuperChip => 0x1000 - self.load_address(),
+            Platform::XoChip => 0x10000 - self.load_address(),
+        }
+    }
+
+    /// The display resolution (width, height) in pixels at startup.
+    pub fn resolution(&self) -> (usize, usize) {
+        match self {
+            Platform::HiResChip8 => (64, 64),
+            Platform::Eti660 => (64, 48),
+            _ => (64, 32),
+        }
+    }
+
+    /// The resolution of the high-res mode that 00FF switches to, if
+    /// the platform has one.
+    pub fn hires_resolution(&self) -> Option<(usize, usize)> {
+        match self {
+            Platform::SuperChip | Platform::XoChip => Some((128, 64)),
+            _ => None,
+        }
+    }
+
+    /// The number of cells in the call stack.
+    pub fn stack_depth(&self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::HiResChip8 | Platform::Eti660 => 12,
+            _ => 16,
+        }
+    }
+
+    /// The default number of instructions executed per second.
+    pub fn speed(&self) -> i64 {
+        match self {
+            Platform::Chip8 | Platform::HiResChip8 | Platform::Eti660 => 700,
+            Platform::Chip48 | Platform::SuperChip => 1800,
+            Platform::XoChip => 60_000,
+        }
+    }
+
+    /// The quirks programs for the platform expect.
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 | Platform::HiResChip8 | Platform::Eti660 => Quirks::COSMAC_VIP,
+            Platform::Chip48 => Quirks::CHIP_48,
+            Platform::SuperChip => Quirks::SCHIP_1_1,
+            Platform::XoChip => Quirks::XO_CHIP,
+        }
+    }
+
+    /// True if the platform has the 8x10 high-res font used by FX30.
+    pub fn has_big_font(&self) -> bool {
+        matches!(self, Platform::SuperChip | Platform::XoChip)
+    }
+
+    /// True if the platform has an instruction. SUPER-CHIP adds scrolling,
+    /// exit, the resolution switch, the big font and the user flags, and
+    /// XO-CHIP adds the rest of its extensions on top. The others only
+    /// run the original instruction set.
+    pub fn supports(&self, inst: Instruction) -> bool {
+        match inst {
+            Instruction::ScrollUp(_)
+            | Instruction::SaveRange { .. }
+            | Instruction::LoadRange { .. }
+            | Instruction::LoadILong(_)
+            | Instruction::Plane(_)
+            | Instruction::Audio
+            | Instruction::Pitch { .. } => *self == Platform::XoChip,
+            Instruction::BigFont { .. } => self.has_big_font(),
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::StoreFlags { .. }
+            | Instruction::LoadFlags { .. } => matches!(self, Platform::SuperChip | Platform::XoChip),
+            _ => true,
+        }
+    }
+
+    /// Guesses the platform a program was written for from its size and
+    /// the instructions it uses. Every word is decoded, data included, so
+    /// this can be fooled, but sprite data rarely looks like the SCHIP
+    /// and XO-CHIP extensions.
+    pub fn detect(program: &[u8]) -> Platform {
+        if program.len() > Platform::SuperChip.max_program_size() {
+            return Platform::XoChip;
+        }
+
+        // Hi-res CHIP-8 programs start by jumping into the hi-res
+        // interpreter loaded along with them.
+        if program.starts_with(&[0x12, 0x60]) {
+            return Platform::HiResChip8;
+        }
+
+        let mut platform = Platform::Chip8;
+
+        for word in program.chunks_exact(2) {
+            let op = u16::from_be_bytes([word[0], word[1]]);
+
+            match Instruction::decode(op) {
+                Ok(inst) if !Platform::SuperChip.supports(inst) => return Platform::XoChip,
+                Err(_) if op == 0xF000 => return Platform::XoChip,
+                Ok(inst) if !Platform::Chip8.supports(inst) => platform = Platform::SuperChip,
+                _ => (),
+            }
+        }
+
+        if platform == Platform::Chip8 && program.len() > Platform::Chip8.max_program_size() {
+            platform = Platform::Chip48;
+        }
+
+        platform
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = UnknownPlatform;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase().replace('_', "-");
+
+        match name.as_str() {
+            "chip8" | "vip" => Ok(Platform::Chip8),
+            "hires" | "hires-chip8" => Ok(Platform::HiResChip8),
+            "chip48" => Ok(Platform::Chip48),
+            "super-chip" | "superchip" | "schip1.1" => Ok(Platform::SuperChip),
+            "xochip" | "octo" => Ok(Platform::XoChip),
+            "eti660" => Ok(Platform::Eti660),
+            _ => Platform::ALL.iter()
+                .find(|p| p.name() == name)
+                .copied()
+                .ok_or(UnknownPlatform(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for UnknownPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown platform '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownPlatform {}
@@ -0,0 +1,212 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// How FX55 and FX65 leave the I register once they are done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// I is not modified (SCHIP 1.1).
+    Unchanged,
+
+    /// I is incremented by X (CHIP-48 and SCHIP 1.0).
+    ByX,
+
+    /// I is incremented by X + 1, pointing past the last register
+    /// (COSMAC VIP and XO-CHIP).
+    ByXPlusOne,
+}
+
+/// Quirks are the behaviors that interpreters disagree on. Which set a
+/// program expects depends on the era and machine it was written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VY and store the result in VX. Otherwise VX
+    /// is shifted in place and VY is ignored.
+    pub shift_vy: bool,
+
+    /// How FX55 and FX65 modify I.
+    pub index_increment: IndexIncrement,
+
+    /// BNNN is treated as BXNN, jumping to XNN + VX instead of NNN + V0.
+    pub jump_vx: bool,
+
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0.
+    pub vf_reset: bool,
+
+    /// Sprites are clipped at the edges of the screen. Otherwise the
+    /// pixels that fall off wrap around to the opposite edge.
+    pub clip_sprites: bool,
+
+    /// DXYN waits for the next vertical blank (60 Hz) before drawing,
+    /// limiting programs to 60 sprites per second.
+    pub display_wait: bool,
+}
+
+/// Returned when a quirk or preset name isn't recognized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownQuirk(pub String);
+
+impl Quirks {
+    /// The original CHIP-8 interpreter on the RCA COSMAC VIP.
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_vy: true,
+        index_increment: IndexIncrement::ByXPlusOne,
+        jump_vx: false,
+        vf_reset: true,
+        clip_sprites: true,
+        display_wait: true,
+    };
+
+    /// CHIP-48 on the HP-48 calculators.
+    pub const CHIP_48: Quirks = Quirks {
+        shift_vy: false,
+        index_increment: IndexIncrement::ByX,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// SUPER-CHIP 1.0, which kept the CHIP-48 behaviors.
+    pub const SCHIP_1_0: Quirks = Quirks {
+        shift_vy: false,
+        index_increment: IndexIncrement::ByX,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// SUPER-CHIP 1.1, which stopped modifying I on FX55 and FX65.
+    pub const SCHIP_1_1: Quirks = Quirks {
+        shift_vy: false,
+        index_increment: IndexIncrement::Unchanged,
+        jump_vx: true,
+        vf_reset: false,
+        clip_sprites: true,
+        display_wait: false,
+    };
+
+    /// XO-CHIP, as implemented by Octo.
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_vy: true,
+        index_increment: IndexIncrement::ByXPlusOne,
+        jump_vx: false,
+        vf_reset: false,
+        clip_sprites: false,
+        display_wait: false,
+    };
+
+    /// Returns the preset with a name, e.g. "vip" or "schip-1.1".
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().replace('_', "-").as_str() {
+            "vip" | "cosmac-vip" | "chip-8" | "chip8" => Some(Quirks::COSMAC_VIP),
+            "chip-48" | "chip48" => Some(Quirks::CHIP_48),
+            "schip-1.0" | "schip1.0" => Some(Quirks::SCHIP_1_0),
+            "schip-1.1" | "schip1.1" | "schip" => Some(Quirks::SCHIP_1_1),
+            "xo-chip" | "xochip" | "octo" => Some(Quirks::XO_CHIP),
+            _ => None,
+        }
+    }
+
+    /// Applies a comma-separated list of changes. Each is a preset name,
+    /// which replaces every quirk, a quirk name to enable it, the name
+    /// prefixed with `-` to disable it, or `index=` followed by
+    /// `unchanged`, `x` or `x+1`. The quirk names are `shift-vy`,
+    /// `jump-vx`, `vf-reset`, `clip` and `display-wait`.
+    pub fn apply(&mut self, changes: &str) -> Result<(), UnknownQuirk> {
+        for change in changes.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+            let change = change.to_ascii_lowercase();
+            let change = change.as_str();
+
+            if let Some(preset) = Quirks::preset(change) {
+                *self = preset;
+                continue;
+            }
+
+            if let Some(index) = change.strip_prefix("index=") {
+                self.index_increment = match index {
+                    "unchanged" | "0" => IndexIncrement::Unchanged,
+                    "x" => IndexIncrement::ByX,
+                    "x+1" => IndexIncrement::ByXPlusOne,
+                    _ => return Err(UnknownQuirk(change.to_string())),
+                };
+                continue;
+            }
+
+            let (name, on) = match change.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (change.strip_prefix('+').unwrap_or(change), true),
+            };
+
+            match name {
+                "shift-vy" => self.shift_vy = on,
+                "jump-vx" => self.jump_vx = on,
+                "vf-reset" => self.vf_reset = on,
+                "clip" => self.clip_sprites = on,
+                "display-wait" => self.display_wait = on,
+                _ => return Err(UnknownQuirk(change.to_string())),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = UnknownQuirk;
+
+    /// Parses a list of changes to the default quirks. See `apply`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quirks = Quirks::default();
+
+        quirks.apply(s)?;
+        Ok(quirks)
+    }
+}
+
+impl fmt::Display for UnknownQuirk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown quirk '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownQuirk {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_replace_every_quirk() {
+        assert_eq!("vip".parse(), Ok(Quirks::COSMAC_VIP));
+        assert_eq!("chip-48".parse(), Ok(Quirks::CHIP_48));
+        assert_eq!("schip-1.0".parse(), Ok(Quirks::SCHIP_1_0));
+        assert_eq!("SCHIP_1.1".parse(), Ok(Quirks::SCHIP_1_1));
+        assert_eq!("-clip,octo".parse(), Ok(Quirks::XO_CHIP));
+    }
+
+    #[test]
+    fn changes_apply_in_order() {
+        let quirks: Quirks = "schip, -clip, +shift-vy, index=x+1, display-wait".parse().unwrap();
+
+        assert_eq!(quirks, Quirks {
+            shift_vy: true,
+            index_increment: IndexIncrement::ByXPlusOne,
+            clip_sprites: false,
+            display_wait: true,
+            ..Quirks::SCHIP_1_1
+        });
+    }
+
+    #[test]
+    fn unknown_changes_are_errors() {
+        assert_eq!("vip,wobble".parse::<Quirks>(), Err(UnknownQuirk("wobble".to_string())));
+        assert_eq!("index=2".parse::<Quirks>(), Err(UnknownQuirk("index=2".to_string())));
+    }
+}
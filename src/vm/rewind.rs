@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use crate::vm::state::StateError;
+use crate::vm::vm::VM;
+
+/// A ring buffer of per-frame save states for stepping back in time.
+///
+/// Only the newest state is kept whole. Every older state is stored as
+/// the XOR of it and the state after it, with runs of zero bytes (the
+/// parts that didn't change) run-length encoded. Since memory and video
+/// barely change from one frame to the next, each snapshot is usually a
+/// few dozen bytes.
+#[derive(Debug)]
+pub struct Rewind {
+    /// The maximum number of frames that can be stepped back.
+    capacity: usize,
+
+    /// The newest state, whole.
+    latest: Vec<u8>,
+
+    /// Deltas to step back from the newest state, oldest first.
+    deltas: VecDeque<Delta>,
+}
+
+/// The difference between a state and the state before it.
+#[derive(Debug)]
+struct Delta {
+    /// Length of the older state.
+    len: usize,
+
+    /// The XOR of both states, zero-run encoded.
+    data: Vec<u8>,
+}
+
+impl Rewind {
+    /// Creates a rewind buffer holding up to `capacity` frames of history.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            latest: Vec::new(),
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// The maximum number of frames that can be stepped back.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The number of frames that can be stepped back right now.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// True if there is no history to step back into.
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Approximate number of bytes used by the history.
+    pub fn memory_usage(&self) -> usize {
+        self.latest.len() + self.deltas.iter().map(|d| d.data.len()).sum::<usize>()
+    }
+
+    /// Forgets all history.
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.deltas.clear();
+    }
+
+    /// Takes a snapshot of the VM. Call this once per frame. Once the
+    /// buffer is full the oldest snapshot is dropped.
+    pub fn push(&mut self, vm: &VM) {
+        let state = vm.save_state();
+
+        if !self.latest.is_empty() {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+
+            if self.capacity > 0 {
+                self.deltas.push_back(Delta {
+                    len: self.latest.len(),
+                    data: encode(&self.latest, &state),
+                });
+            }
+        }
+
+        self.latest = state;
+    }
+
+    /// Steps back up to `frames` snapshots and restores the VM to that
+    /// point. Newer snapshots are discarded, so emulation resumes from
+    /// there. Returns the number of frames actually stepped back, which
+    /// is fewer than asked when the history runs out.
+    pub fn rewind(&mut self, vm: &mut VM, frames: usize) -> Result<usize, StateError> {
+        if self.latest.is_empty() {
+            return Ok(0);
+        }
+
+        let n = frames.min(self.deltas.len());
+
+        for _ in 0..n {
+            let delta = self.deltas.pop_back().unwrap();
+
+            apply(&mut self.latest, &delta.data);
+            self.latest.truncate(delta.len);
+        }
+
+        vm.load_state(&self.latest)?;
+
+        Ok(n)
+    }
+}
+
+/// Encodes the XOR of two buffers as pairs of varints, the number of
+/// zero bytes followed by the number of literal bytes, with the literal
+/// bytes after each pair. The shorter buffer is padded with zeros.
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let n = old.len().max(new.len());
+    let byte = |i: usize| old.get(i).unwrap_or(&0) ^ new.get(i).unwrap_or(&0);
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < n {
+        let zeros = (i..n).take_while(|&k| byte(k) == 0).count();
+        let start = i + zeros;
+        let literal = (start..n).take_while(|&k| byte(k) != 0).count();
+
+        write_varint(&mut out, zeros);
+        write_varint(&mut out, literal);
+        out.extend((start..start + literal).map(byte));
+
+        i = start + literal;
+    }
+
+    out
+}
+
+/// XORs an encoded delta into a buffer, growing it if needed.
+fn apply(buf: &mut Vec<u8>, data: &[u8]) {
+    let mut pos = 0;
+    let mut i = 0;
+
+    while pos < data.len() {
+        let zeros = read_varint(data, &mut pos);
+        let literal = read_varint(data, &mut pos);
+
+        i += zeros;
+
+        if buf.len() < i + literal {
+            buf.resize(i + literal, 0);
+        }
+
+        for (b, d) in buf[i..i + literal].iter_mut().zip(&data[pos..pos + literal]) {
+            *b ^= d;
+        }
+
+        i += literal;
+        pos += literal;
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+
+    out.push(n as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+
+    while let Some(&b) = data.get(*pos) {
+        *pos += 1;
+        n |= ((b & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::platform::Platform;
+    use crate::vm::test::program;
+
+    /// Runs frames, pushing a snapshot before each, and returns the
+    /// state hash at each snapshot.
+    fn run(vm: &mut VM, rewind: &mut Rewind, frames: usize) -> Vec<u32> {
+        (0..frames).map(|_| {
+            let hash = vm.state_hash();
+
+            rewind.push(vm);
+            vm.run_frame().unwrap();
+            hash
+        }).collect()
+    }
+
+    #[test]
+    fn rewind_restores_exact_state() {
+        let mut vm = program(Platform::Chip8);
+        let mut rewind = Rewind::new(100);
+        let hashes = run(&mut vm, &mut rewind, 50);
+
+        assert_eq!(rewind.rewind(&mut vm, 10).unwrap(), 10);
+        assert_eq!(vm.state_hash(), hashes[39]);
+
+        assert_eq!(rewind.rewind(&mut vm, 5).unwrap(), 5);
+        assert_eq!(vm.state_hash(), hashes[34]);
+        assert_eq!(rewind.len(), 34);
+    }
+
+    #[test]
+    fn resuming_after_rewind_repeats_the_run() {
+        let mut vm = program(Platform::Chip8);
+        let mut rewind = Rewind::new(100);
+        let hashes = run(&mut vm, &mut rewind, 40);
+
+        rewind.rewind(&mut vm, 20).unwrap();
+
+        let replayed = run(&mut vm, &mut rewind, 20);
+
+        assert_eq!(replayed, hashes[19..39]);
+    }
+
+    #[test]
+    fn history_is_limited_to_capacity() {
+        let mut vm = program(Platform::Chip8);
+        let mut rewind = Rewind::new(8);
+        let hashes = run(&mut vm, &mut rewind, 30);
+
+        assert_eq!(rewind.len(), 8);
+        assert_eq!(rewind.rewind(&mut vm, 100).unwrap(), 8);
+        assert_eq!(vm.state_hash(), hashes[21]);
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let old = vec![1, 2, 3, 0, 0, 0, 7, 8, 9, 10];
+        let new = vec![1, 2, 4, 0, 0, 0, 7, 8];
+        let mut buf = new.clone();
+
+        apply(&mut buf, &encode(&old, &new));
+        buf.truncate(old.len());
+
+        assert_eq!(buf, old);
+    }
+}
@@ -0,0 +1,172 @@
+use std::fmt::Debug;
+use crate::vm::rom::INTERPRETER;
+
+/// The seed CXNN starts from when none is given, so runs are the same
+/// every time.
+pub const DEFAULT_SEED: u32 = 1;
+
+/// A source of random bytes for CXNN. Generators are deterministic: the
+/// same seed always produces the same stream, so replays and tests can
+/// reproduce a run exactly.
+pub trait Rng: Debug {
+    /// Returns the next random byte.
+    fn next_byte(&mut self) -> u8;
+
+    /// Restarts the stream from a seed.
+    fn seed(&mut self, seed: u32);
+
+    /// Returns the current state as a seed. Seeding a generator of the
+    /// same kind with it continues the stream from this point.
+    fn state(&self) -> u32;
+
+    /// Returns which generator this is, so save states can recreate it.
+    fn kind(&self) -> RngKind;
+}
+
+/// The generators CXNN can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngKind {
+    /// `XorShiftRng`.
+    XorShift,
+
+    /// `VipRng`.
+    Vip,
+}
+
+/// The default generator: 32-bit xorshift, returning the top byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XorShiftRng {
+    state: u32,
+}
+
+/// The random routine of the original COSMAC VIP interpreter.
+///
+/// The VIP keeps a 16-bit seed in R9. Each CXNN increments it, then uses
+/// the low byte to index the page of interpreter code it's running from,
+/// adds the byte found there to the high byte, rotates the sum through
+/// the carry and adds it again. The result becomes the new high byte.
+/// The stream is poor, but it is the one VIP programs were written for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VipRng {
+    r9: u16,
+}
+
+impl RngKind {
+    /// Creates a generator of this kind from a seed.
+    pub fn create(self, seed: u32) -> Box<dyn Rng> {
+        match self {
+            RngKind::XorShift => Box::new(XorShiftRng::new(seed)),
+            RngKind::Vip => Box::new(VipRng::new(seed)),
+        }
+    }
+}
+
+impl XorShiftRng {
+    /// Creates a generator from a seed.
+    pub fn new(seed: u32) -> XorShiftRng {
+        XorShiftRng { state: seed.max(1) }
+    }
+}
+
+impl Default for XorShiftRng {
+    fn default() -> Self {
+        XorShiftRng::new(DEFAULT_SEED)
+    }
+}
+
+impl Rng for XorShiftRng {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state >> 24) as u8
+    }
+
+    /// Seeds the generator. A zero seed is replaced by 1, since xorshift
+    /// never leaves 0.
+    fn seed(&mut self, seed: u32) {
+        self.state = seed.max(1);
+    }
+
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    fn kind(&self) -> RngKind {
+        RngKind::XorShift
+    }
+}
+
+impl VipRng {
+    /// Creates a generator with R9 set from the low 16 bits of a seed.
+    pub fn new(seed: u32) -> VipRng {
+        VipRng { r9: seed as u16 }
+    }
+}
+
+impl Rng for VipRng {
+    fn next_byte(&mut self) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+
+        let [hi, lo] = self.r9.to_be_bytes();
+
+        // CXNN runs from the second page of the interpreter.
+        let (sum, carry) = INTERPRETER[0x100 | lo as usize].overflowing_add(hi);
+        let rotated = (sum >> 1) | (carry as u8) << 7;
+        let byte = rotated.wrapping_add(sum);
+
+        self.r9 = u16::from_be_bytes([byte, lo]);
+
+        byte
+    }
+
+    fn seed(&mut self, seed: u32) {
+        self.r9 = seed as u16;
+    }
+
+    fn state(&self) -> u32 {
+        self.r9 as u32
+    }
+
+    fn kind(&self) -> RngKind {
+        RngKind::Vip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(rng: &mut dyn Rng, seed: u32) -> Vec<u8> {
+        rng.seed(seed);
+        (0..64).map(|_| rng.next_byte()).collect()
+    }
+
+    #[test]
+    fn xorshift_is_deterministic() {
+        let mut rng = XorShiftRng::default();
+
+        assert_eq!(stream(&mut rng, 42), stream(&mut XorShiftRng::new(7), 42));
+        assert_ne!(stream(&mut rng, 42), stream(&mut rng, 43));
+    }
+
+    #[test]
+    fn vip_is_deterministic() {
+        let mut rng = VipRng::default();
+
+        assert_eq!(stream(&mut rng, 42), stream(&mut VipRng::new(7), 42));
+        assert_ne!(stream(&mut rng, 42), stream(&mut rng, 43));
+    }
+
+    #[test]
+    fn state_continues_the_stream() {
+        let mut a = XorShiftRng::new(99);
+
+        a.next_byte();
+
+        let mut b = XorShiftRng::new(a.state());
+
+        assert_eq!((0..16).map(|_| a.next_byte()).collect::<Vec<_>>(), (0..16).map(|_| b.next_byte()).collect::<Vec<_>>());
+    }
+}
@@ -1,5 +1,5 @@
 /// First 512 bytes for the emulator.
-pub const EMULATOR_ROM: [uint8; 0x200] = [
+pub const EMULATOR_ROM: [u8; 0x200] = [
     // 4x5 low-res mode font sprites (0-F)
     0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20,
     0x20, 0x70, 0xF0, 0x10, 0xF0, 0x80, 0xF0, 0xF0,
@@ -127,13 +127,13 @@ pub const EMULATOR_ROM: [uint8; 0x200] = [
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    }
+];
 
-    // A "dummy" program that does nothing but loop in on itself.
-    var Dummy = []byte{0x12, 0x00}
+/// A "dummy" program that does nothing but loop in on itself.
+pub const DUMMY: [u8; 2] = [0x12, 0x00];
 
-    // Boot is a CHIP-8 program that can be loaded in case nothing is.
-    var Boot = []byte{
+/// Boot is a CHIP-8 program that can be loaded in case nothing is.
+pub const BOOT: &[u8] = &[
     0xA2, 0x5B, 0x60, 0x0B, 0x61, 0x03, 0x62, 0x07,
     0xD0, 0x17, 0x70, 0x07, 0xF2, 0x1E, 0xD0, 0x17,
     0x70, 0x07, 0xF2, 0x1E, 0xD0, 0x17, 0x70, 0x07,
@@ -151,10 +151,10 @@ pub const EMULATOR_ROM: [uint8; 0x200] = [
     0xF8, 0xCC, 0xCC, 0xF8, 0xC0, 0xC0, 0xC0, 0x00,
     0x00, 0x00, 0xF0, 0x00, 0x00, 0x00, 0x78, 0xCC,
     0xCC, 0x78, 0xCC, 0xCC, 0x78,
-    }
+];
 
-    // This is a CDP1802 interpreter for CHIP-8. It does NOT support CHIP-8E!
-    var Interpreter = []byte{
+/// This is a CDP1802 interpreter for CHIP-8. It does NOT support CHIP-8E!
+pub const INTERPRETER: &[u8] = &[
     0x91, 0xBB, 0xFF, 0x01, 0xB2, 0xB6, 0xF8, 0xCF,
     0xA2, 0xF8, 0x81, 0xB1, 0xF8, 0x46, 0xA1, 0x90,
     0xB4, 0xF8, 0x1B, 0xA4, 0xF8, 0x01, 0xB5, 0xF8,
@@ -218,5 +218,5 @@ pub const EMULATOR_ROM: [uint8; 0x200] = [
     0xF4, 0x56, 0x76, 0xE6, 0xF4, 0xB9, 0x56, 0x45,
     0xF2, 0x56, 0xD4, 0x45, 0xAA, 0x86, 0xFA, 0x0F,
     0xBA, 0xD4, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-    0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B
-]
\ No newline at end of file
+    0x00, 0x00, 0x00, 0x00, 0x00, 0xE0, 0x00, 0x4B,
+];
@@ -0,0 +1,639 @@
+use std::error::Error;
+use std::fmt;
+use crate::vm::platform::Platform;
+use crate::vm::quirks::{IndexIncrement, Quirks};
+use crate::vm::rng::RngKind;
+use crate::vm::timer::TimerMode;
+use crate::vm::trap::{Trap, TrapPolicy};
+use crate::vm::vm::VM;
+
+/// Every save state begins with these bytes.
+pub const MAGIC: [u8; 4] = *b"CH8S";
+
+/// The version of the save state format written by `save_state`. Any
+/// other version is refused by `load_state`.
+pub const VERSION: u16 = 3;
+
+/// Reasons a save state can't be restored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The data doesn't begin with the save state magic.
+    BadMagic,
+
+    /// The state was written by a different version of the format.
+    UnsupportedVersion(u16),
+
+    /// The checksum doesn't match the contents, so the data is corrupt.
+    ChecksumMismatch { expected: u32, actual: u32 },
+
+    /// The data ends before the state does.
+    Truncated,
+
+    /// A field holds a value that can't be restored.
+    Invalid(&'static str),
+}
+
+/// Appends little-endian values to a byte buffer.
+#[derive(Debug, Default)]
+pub struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+/// Reads little-endian values from a byte buffer.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Writer {
+    pub fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    pub fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    pub fn u16(&mut self, n: u16) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, n: u32) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, n: i64) {
+        self.bytes.extend_from_slice(&n.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed byte slice.
+    pub fn slice(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.bytes.extend_from_slice(data);
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, pos: 0 }
+    }
+
+    /// True once every byte has been read.
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], StateError> {
+        if self.pos + n > self.bytes.len() {
+            return Err(StateError::Truncated);
+        }
+
+        self.pos += n;
+
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("boolean")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, StateError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(i64::from_le_bytes(b))
+    }
+
+    /// Reads a length-prefixed byte slice.
+    pub fn slice(&mut self) -> Result<&'a [u8], StateError> {
+        let n = self.u32()? as usize;
+        self.take(n)
+    }
+}
+
+/// Computes the CRC-32 (IEEE) of some data.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= b as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+impl VM {
+    /// Serializes the complete state of the VM: memory, video, stack,
+    /// registers, timers, keys, display mode, cycle count, random number
+    /// generator, platform and quirks. The state is framed by a magic header, a format version
+    /// and a trailing CRC-32 of everything before it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+
+        w.bytes.extend_from_slice(&MAGIC);
+        w.u16(VERSION);
+
+        // machine configuration
+        w.u8(Platform::ALL.iter().position(|&p| p == self.platform).unwrap_or(0) as u8);
+        write_quirks(&mut w, &self.quirks);
+        w.u8(self.trap_policy as u8);
+        w.u8(self.timer.mode as u8);
+        w.u8(self.stack_depth as u8);
+        w.i64(self.speed);
+        w.i64(self.cycles_per_frame);
+
+        // memory
+        w.u32(self.rom_size as u32);
+        w.slice(&self.rom);
+        w.slice(&self.memory);
+
+        // video and audio
+        w.slice(&self.video[0]);
+        w.slice(&self.video[1]);
+        w.u8(self.plane);
+        w.u8(self.pitch as u8);
+        w.u8(self.height as u8);
+        w.bytes.extend_from_slice(&self.pattern);
+        w.u8(self.audio_pitch);
+
+        // stack and registers
+        for &addr in self.stack.iter() {
+            w.u32(addr as u32);
+        }
+
+        w.u8(self.sp as u8);
+        w.u32(self.pc as u32);
+        w.u32(self.regs.i as u32);
+        w.bytes.extend_from_slice(&self.regs.v);
+        w.bytes.extend_from_slice(&self.regs.r);
+        w.u8(self.regs.dt);
+        w.u8(self.regs.st);
+
+        // execution state
+        w.u16(self.keys.iter().enumerate().fold(0, |k, (n, &down)| k | (down as u16) << n));
+        w.i64(self.cycles);
+        w.i64(self.timer.ticks);
+        w.i64(self.timer.base_cycle);
+        w.i64(self.timer.base_tick);
+        w.i64(self.elapsed());
+        w.i64(self.draw_frame);
+        w.u8(self.rng.kind() as u8);
+        w.u32(self.rng.state());
+        w.u32(self.seed);
+        w.bool(self.exited);
+        w.bool(self.waiting);
+        write_trap(&mut w, self.trap);
+
+        let crc = crc32(&w.bytes);
+        w.u32(crc);
+        w.bytes
+    }
+
+    /// Returns a CRC-32 of the parts of the state that a program can
+    /// affect: memory, video, stack, registers, timers and cycle count.
+    /// Unlike `save_state`, this doesn't depend on the host clock, so two
+    /// runs of the same program with the same input hash the same.
+    pub fn state_hash(&self) -> u32 {
+        let mut w = Writer::default();
+
+        w.bytes.extend_from_slice(&self.memory);
+        w.bytes.extend_from_slice(&self.video[0]);
+        w.bytes.extend_from_slice(&self.video[1]);
+
+        for &addr in self.stack.iter() {
+            w.u32(addr as u32);
+        }
+
+        w.u8(self.sp as u8);
+        w.u32(self.pc as u32);
+        w.u32(self.regs.i as u32);
+        w.bytes.extend_from_slice(&self.regs.v);
+        w.bytes.extend_from_slice(&self.regs.r);
+        w.u8(self.regs.dt);
+        w.u8(self.regs.st);
+        w.i64(self.cycles);
+
+        crc32(&w.bytes)
+    }
+
+    /// Returns a CRC-32 of the visible part of both video planes.
+    pub fn video_hash(&self) -> u32 {
+        let size = self.pitch * self.height;
+        let mut w = Writer::default();
+
+        w.bytes.extend_from_slice(&self.video[0][..size]);
+        w.bytes.extend_from_slice(&self.video[1][..size]);
+
+        crc32(&w.bytes)
+    }
+
+    /// Returns a CRC-32 of addressable memory.
+    pub fn memory_hash(&self) -> u32 {
+        crc32(&self.memory)
+    }
+
+    /// Restores a state written by `save_state`. The state is checked
+    /// completely before anything is modified, so on error the VM is
+    /// left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        if data.len() < MAGIC.len() || data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+
+        let mut r = Reader::new(&data[MAGIC.len()..]);
+        let version = r.u16()?;
+
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        if data.len() < MAGIC.len() + 6 {
+            return Err(StateError::Truncated);
+        }
+
+        let (body, tail) = data.split_at(data.len() - 4);
+        let expected = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]);
+        let actual = crc32(body);
+
+        if expected != actual {
+            return Err(StateError::ChecksumMismatch { expected, actual });
+        }
+
+        let mut r = Reader::new(&body[MAGIC.len() + 2..]);
+        let mut vm = VM::new(*Platform::ALL.get(r.u8()? as usize).ok_or(StateError::Invalid("platform"))?);
+
+        vm.quirks = read_quirks(&mut r)?;
+        vm.trap_policy = match r.u8()? {
+            0 => TrapPolicy::Halt,
+            1 => TrapPolicy::Ignore,
+            2 => TrapPolicy::Wrap,
+            _ => return Err(StateError::Invalid("trap policy")),
+        };
+        vm.timer.mode = match r.u8()? {
+            0 => TimerMode::Virtual,
+            1 => TimerMode::RealTime,
+            _ => return Err(StateError::Invalid("timer mode")),
+        };
+        vm.stack_depth = match r.u8()? as usize {
+            n @ 1..=16 => n,
+            _ => return Err(StateError::Invalid("stack depth")),
+        };
+        vm.speed = match r.i64()? {
+            n if n >= 1 => n,
+            _ => return Err(StateError::Invalid("speed")),
+        };
+        vm.cycles_per_frame = match r.i64()? {
+            n if n >= 1 => n,
+            _ => return Err(StateError::Invalid("cycles per frame")),
+        };
+
+        vm.rom_size = r.u32()? as usize;
+        copy_exact(&mut vm.rom, r.slice()?, "rom")?;
+        copy_exact(&mut vm.memory, r.slice()?, "memory")?;
+
+        if vm.rom_size > vm.platform.max_program_size() {
+            return Err(StateError::Invalid("rom size"));
+        }
+
+        copy_exact(&mut vm.video[0], r.slice()?, "video")?;
+        copy_exact(&mut vm.video[1], r.slice()?, "video")?;
+        vm.plane = r.u8()? & 0x3;
+        vm.pitch = r.u8()? as usize;
+        vm.height = r.u8()? as usize;
+
+        // Only the resolutions the platform can switch between are valid.
+        let modes = [Some(vm.platform.resolution()), vm.platform.hires_resolution()];
+
+        if !modes.iter().flatten().any(|&(width, height)| (width / 8, height) == (vm.pitch, vm.height)) {
+            return Err(StateError::Invalid("display mode"));
+        }
+
+        vm.pattern.copy_from_slice(r.take(16)?);
+        vm.audio_pitch = r.u8()?;
+
+        for cell in vm.stack.iter_mut() {
+            *cell = r.u32()? as usize;
+        }
+
+        let mask = vm.memory.len() - 1;
+
+        vm.sp = r.u8()? as usize;
+        vm.pc = r.u32()? as usize & mask;
+        vm.regs.i = r.u32()? as usize & mask;
+        vm.regs.v.copy_from_slice(r.take(16)?);
+        vm.regs.r.copy_from_slice(r.take(8)?);
+        vm.regs.dt = r.u8()?;
+        vm.regs.st = r.u8()?;
+
+        if vm.sp > vm.stack_depth {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+
+        let keys = r.u16()?;
+
+        for (n, key) in vm.keys.iter_mut().enumerate() {
+            *key = keys & (1 << n) != 0;
+        }
+
+        vm.cycles = r.i64()?;
+        vm.timer.ticks = r.i64()?;
+        vm.timer.base_cycle = r.i64()?;
+        vm.timer.base_tick = r.i64()?;
+
+        let elapsed = r.i64()?;
+
+        vm.draw_frame = r.i64()?;
+
+        let kind = match r.u8()? {
+            0 => RngKind::XorShift,
+            1 => RngKind::Vip,
+            _ => return Err(StateError::Invalid("generator")),
+        };
+
+        vm.rng = kind.create(r.u32()?);
+        vm.seed = r.u32()?;
+        vm.exited = r.bool()?;
+        vm.waiting = r.bool()?;
+        vm.trap = read_trap(&mut r)?;
+
+        if !r.is_empty() {
+            return Err(StateError::Invalid("trailing data"));
+        }
+
+        // The clock belongs to the host, so keep it and carry on from the
+        // same point in emulated time.
+        std::mem::swap(&mut vm.clock, &mut self.clock);
+        vm.start = vm.clock.now() - elapsed;
+
+        vm.dirty = true;
+
+        *self = vm;
+
+        Ok(())
+    }
+}
+
+pub(crate) fn write_quirks(w: &mut Writer, quirks: &Quirks) {
+    w.bool(quirks.shift_vy);
+    w.u8(quirks.index_increment as u8);
+    w.bool(quirks.jump_vx);
+    w.bool(quirks.vf_reset);
+    w.bool(quirks.clip_sprites);
+    w.bool(quirks.display_wait);
+}
+
+pub(crate) fn read_quirks(r: &mut Reader) -> Result<Quirks, StateError> {
+    Ok(Quirks {
+        shift_vy: r.bool()?,
+        index_increment: match r.u8()? {
+            0 => IndexIncrement::Unchanged,
+            1 => IndexIncrement::ByX,
+            2 => IndexIncrement::ByXPlusOne,
+            _ => return Err(StateError::Invalid("index increment")),
+        },
+        jump_vx: r.bool()?,
+        vf_reset: r.bool()?,
+        clip_sprites: r.bool()?,
+        display_wait: r.bool()?,
+    })
+}
+
+fn write_trap(w: &mut Writer, trap: Option<Trap>) {
+    match trap {
+        None => w.u8(0),
+        Some(Trap::UnknownOpcode { opcode, addr }) => {
+            w.u8(1);
+            w.u16(opcode);
+            w.u32(addr as u32);
+        },
+        Some(Trap::StackOverflow { addr }) => {
+            w.u8(2);
+            w.u32(addr as u32);
+        },
+        Some(Trap::StackUnderflow { addr }) => {
+            w.u8(3);
+            w.u32(addr as u32);
+        },
+        Some(Trap::MemoryOutOfRange { access, addr }) => {
+            w.u8(4);
+            w.u32(access as u32);
+            w.u32(addr as u32);
+        },
+        Some(Trap::InvalidFontDigit { digit, addr }) => {
+            w.u8(5);
+            w.u8(digit);
+            w.u32(addr as u32);
+        },
+    }
+}
+
+fn read_trap(r: &mut Reader) -> Result<Option<Trap>, StateError> {
+    Ok(match r.u8()? {
+        0 => None,
+        1 => Some(Trap::UnknownOpcode { opcode: r.u16()?, addr: r.u32()? as usize }),
+        2 => Some(Trap::StackOverflow { addr: r.u32()? as usize }),
+        3 => Some(Trap::StackUnderflow { addr: r.u32()? as usize }),
+        4 => Some(Trap::MemoryOutOfRange { access: r.u32()? as usize, addr: r.u32()? as usize }),
+        5 => Some(Trap::InvalidFontDigit { digit: r.u8()?, addr: r.u32()? as usize }),
+        _ => return Err(StateError::Invalid("trap")),
+    })
+}
+
+/// Copies a saved buffer over one of the VM, which must be the same size.
+fn copy_exact(dst: &mut [u8], src: &[u8], what: &'static str) -> Result<(), StateError> {
+    if dst.len() != src.len() {
+        return Err(StateError::Invalid(what));
+    }
+
+    dst.copy_from_slice(src);
+
+    Ok(())
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => {
+                write!(f, "Save state version {} isn't supported (expected {})", v, VERSION)
+            },
+            StateError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Save state is corrupt (checksum {:08X}, expected {:08X})", actual, expected)
+            },
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(what) => write!(f, "Save state has an invalid {}", what),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::rng::VipRng;
+    use crate::vm::test::program;
+    use crate::vm::vm::load_rom;
+
+    fn vm(platform: Platform) -> VM {
+        let mut vm = program(platform);
+
+        for _ in 0..10 {
+            vm.run_frame().unwrap();
+        }
+
+        vm
+    }
+
+    #[test]
+    fn load_resumes_exactly() {
+        let mut vm = vm(Platform::Chip8);
+        let state = vm.save_state();
+        let hash = vm.state_hash();
+        let mut hashes = Vec::new();
+
+        for _ in 0..30 {
+            vm.run_frame().unwrap();
+            hashes.push(vm.state_hash());
+        }
+
+        vm.load_state(&state).unwrap();
+        assert_eq!(vm.state_hash(), hash);
+
+        for expected in hashes {
+            vm.run_frame().unwrap();
+            assert_eq!(vm.state_hash(), expected);
+        }
+    }
+
+    #[test]
+    fn load_into_a_fresh_vm() {
+        let vm = vm(Platform::SuperChip);
+        let mut other = VM::default();
+
+        other.load_state(&vm.save_state()).unwrap();
+
+        assert_eq!(other.platform(), Platform::SuperChip);
+        assert_eq!(other.state_hash(), vm.state_hash());
+        assert_eq!(other.seed(), vm.seed());
+    }
+
+    #[test]
+    fn corrupt_state_is_rejected() {
+        let mut vm = vm(Platform::Chip8);
+        let mut state = vm.save_state();
+        let hash = vm.state_hash();
+
+        state[100] ^= 0x01;
+
+        assert!(matches!(vm.load_state(&state), Err(StateError::ChecksumMismatch { .. })));
+        assert_eq!(vm.state_hash(), hash);
+    }
+
+    #[test]
+    fn bad_headers_are_rejected() {
+        let mut vm = vm(Platform::Chip8);
+        let state = vm.save_state();
+
+        assert_eq!(vm.load_state(b"NOPE"), Err(StateError::BadMagic));
+        assert_eq!(vm.load_state(&state[..6]), Err(StateError::Truncated));
+
+        let mut newer = state.clone();
+        newer[4] = VERSION as u8 + 1;
+
+        assert_eq!(vm.load_state(&newer), Err(StateError::UnsupportedVersion(VERSION + 1)));
+    }
+
+    #[test]
+    fn impossible_display_modes_are_rejected() {
+        let mut vm = vm(Platform::Chip8);
+
+        vm.height = 0;
+        let blank = vm.save_state();
+
+        vm.pitch = 16;
+        vm.height = 64;
+        let hires = vm.save_state();
+
+        assert_eq!(vm.load_state(&blank), Err(StateError::Invalid("display mode")));
+        assert_eq!(vm.load_state(&hires), Err(StateError::Invalid("display mode")));
+    }
+
+    #[test]
+    fn generator_and_seed_are_restored() {
+        let mut vm = vm(Platform::Chip8);
+
+        vm.set_rng(Box::new(VipRng::default()));
+        vm.set_seed(1234);
+        vm.rng.next_byte();
+
+        let state = vm.save_state();
+        let mut other = VM::default();
+
+        other.load_state(&state).unwrap();
+
+        assert_eq!(other.rng.kind(), RngKind::Vip);
+        assert_eq!(other.rng.next_byte(), vm.rng.next_byte());
+
+        other.reset();
+        vm.reset();
+
+        assert_eq!(other.seed(), 1234);
+        assert_eq!(other.rng.next_byte(), vm.rng.next_byte());
+    }
+
+    #[test]
+    fn zero_speeds_are_rejected() {
+        let mut vm = vm(Platform::Chip8);
+
+        vm.speed = 0;
+        let stopped = vm.save_state();
+
+        vm.speed = 700;
+        vm.cycles_per_frame = 0;
+        let idle = vm.save_state();
+
+        assert_eq!(vm.load_state(&stopped), Err(StateError::Invalid("speed")));
+        assert_eq!(vm.load_state(&idle), Err(StateError::Invalid("cycles per frame")));
+    }
+
+    #[test]
+    fn traps_are_restored() {
+        let mut vm = load_rom(vec![0x60, 0x10, 0xF0, 0x29], Platform::Chip8).unwrap();
+
+        vm.step().unwrap();
+        assert!(vm.step().is_err());
+
+        let trap = vm.trap();
+        let state = vm.save_state();
+
+        vm.reset();
+        vm.load_state(&state).unwrap();
+
+        assert_eq!(vm.trap(), trap);
+    }
+}
@@ -0,0 +1,16 @@
+use crate::vm::platform::Platform;
+use crate::vm::vm::{load_rom, VM};
+
+/// Draws a sprite at a random position, sets the delay timer and reads
+/// it back, forever, so video, the timers and the random number
+/// generator all change from frame to frame.
+pub const PROGRAM: [u8; 21] = [
+    0xC0, 0x3F, 0xC1, 0x1F, 0xA2, 0x10, 0xD0, 0x15,
+    0x62, 0x03, 0xF2, 0x15, 0xF3, 0x07, 0x12, 0x00,
+    0xF0, 0x90, 0x90, 0x90, 0xF0,
+];
+
+/// Loads `PROGRAM` on a platform.
+pub fn program(platform: Platform) -> VM {
+    load_rom(PROGRAM.to_vec(), platform).unwrap()
+}
@@ -0,0 +1,106 @@
+use crate::vm::vm::Registers;
+
+/// Nanoseconds in a single 60 Hz timer tick.
+pub const TICK: i64 = 1_000_000_000 / 60;
+
+/// What the delay and sound timers count down against.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// The timers tick once every speed/60 executed cycles. Two runs of
+    /// the same program with the same input always tick at the same
+    /// instructions, regardless of how fast the host is.
+    #[default]
+    Virtual,
+
+    /// The timers tick 60 times per second of clock time.
+    RealTime,
+}
+
+/// The 60 Hz timer subsystem. It keeps count of how many ticks have been
+/// applied to DT and ST, and catches them up to however many ticks should
+/// have happened by now.
+#[derive(Debug, Clone)]
+pub struct Timer {
+    /// What the timers count against.
+    pub(crate) mode: TimerMode,
+
+    /// Number of 60 Hz ticks applied since the timer was reset.
+    pub(crate) ticks: i64,
+
+    /// The cycle at which the speed last changed. Virtual ticks are
+    /// counted from here, so a new speed only affects ticks after it.
+    pub(crate) base_cycle: i64,
+
+    /// The virtual tick reached at `base_cycle`.
+    pub(crate) base_tick: i64,
+}
+
+impl Timer {
+    pub fn new(mode: TimerMode) -> Timer {
+        Timer { mode, ticks: 0, base_cycle: 0, base_tick: 0 }
+    }
+
+    /// Returns what the timers count against.
+    pub fn mode(&self) -> TimerMode {
+        self.mode
+    }
+
+    /// Changes what the timers count against. Ticks already applied are
+    /// kept, so the timers won't jump if the new mode is behind.
+    pub fn set_mode(&mut self, mode: TimerMode) {
+        self.mode = mode;
+    }
+
+    /// Returns the number of ticks applied since the timer was reset.
+    pub fn ticks(&self) -> i64 {
+        self.ticks
+    }
+
+    /// Restarts the count of ticks at 0.
+    pub fn reset(&mut self) {
+        self.ticks = 0;
+        self.base_cycle = 0;
+        self.base_tick = 0;
+    }
+
+    /// Returns the tick that should have been reached, either after a
+    /// number of cycles at a given speed, or after some elapsed time.
+    pub fn due(&self, cycles: i64, speed: i64, elapsed: i64) -> i64 {
+        match self.mode {
+            TimerMode::Virtual => self.virtual_due(cycles, speed),
+            TimerMode::RealTime => elapsed / TICK,
+        }
+    }
+
+    /// Call before the speed changes from `speed`. Virtual ticks up to
+    /// `cycles` are counted at the old speed and the rest at the new one,
+    /// so the timers carry on from where they are instead of jumping.
+    pub fn rebase(&mut self, cycles: i64, speed: i64) {
+        self.base_tick = self.virtual_due(cycles, speed);
+        self.base_cycle = cycles;
+    }
+
+    fn virtual_due(&self, cycles: i64, speed: i64) -> i64 {
+        self.base_tick + (cycles - self.base_cycle) * 60 / speed.max(1)
+    }
+
+    /// Decrements DT and ST once for each tick between the last update
+    /// and tick `due`.
+    pub fn update(&mut self, due: i64, regs: &mut Registers) {
+        if due <= self.ticks {
+            return;
+        }
+
+        let n = (due - self.ticks).min(255) as u8;
+
+        regs.dt = regs.dt.saturating_sub(n);
+        regs.st = regs.st.saturating_sub(n);
+        self.ticks = due;
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Timer::new(TimerMode::default())
+    }
+}
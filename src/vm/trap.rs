@@ -0,0 +1,67 @@
+use crate::vm::error::ChipperError;
+
+/// A fault caused by the program rather than the emulator: something the
+/// original hardware would have done *something* with, even if it was
+/// not what the author intended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// The opcode doesn't decode to any instruction.
+    UnknownOpcode { opcode: u16, addr: usize },
+
+    /// A 2NNN was executed with every stack cell in use.
+    StackOverflow { addr: usize },
+
+    /// A 00EE was executed with an empty stack.
+    StackUnderflow { addr: usize },
+
+    /// An instruction read or wrote memory past the end of memory.
+    MemoryOutOfRange { access: usize, addr: usize },
+
+    /// FX29 or FX30 was asked for a digit that has no font sprite.
+    InvalidFontDigit { digit: u8, addr: usize },
+}
+
+/// What the VM does when the program traps.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TrapPolicy {
+    /// Stop executing. The trap is returned as an error and every step
+    /// after it returns the same error until the VM is reset.
+    #[default]
+    Halt,
+
+    /// Treat the faulting instruction as a no-op and carry on.
+    Ignore,
+
+    /// Do what real hardware did. The stack pointer wraps around the
+    /// stack cells, overwriting the oldest return address on overflow
+    /// and popping the top cell on underflow. Unknown opcodes fell into
+    /// 1802 machine code, which can't be emulated, so they are skipped,
+    /// as are memory accesses past the end and missing font digits,
+    /// which read whatever followed in the real machine's memory.
+    Wrap,
+}
+
+impl Trap {
+    /// The address of the instruction that trapped.
+    pub fn addr(&self) -> usize {
+        match *self {
+            Trap::UnknownOpcode { addr, .. } => addr,
+            Trap::StackOverflow { addr } => addr,
+            Trap::StackUnderflow { addr } => addr,
+            Trap::MemoryOutOfRange { addr, .. } => addr,
+            Trap::InvalidFontDigit { addr, .. } => addr,
+        }
+    }
+}
+
+impl From<Trap> for ChipperError {
+    fn from(trap: Trap) -> Self {
+        match trap {
+            Trap::UnknownOpcode { opcode, addr } => ChipperError::UnknownOpcode { opcode, addr },
+            Trap::StackOverflow { addr } => ChipperError::StackOverflow { addr },
+            Trap::StackUnderflow { addr } => ChipperError::StackUnderflow { addr },
+            Trap::MemoryOutOfRange { access, addr } => ChipperError::MemoryOutOfRange { access, addr },
+            Trap::InvalidFontDigit { digit, addr } => ChipperError::InvalidFontDigit { digit, addr },
+        }
+    }
+}
@@ -1,113 +1,1030 @@
-use std::error::Error;
+use std::ops::Range;
+use crate::vm::clock::{Clock, SystemClock};
+use crate::vm::error::ChipperError;
+use crate::vm::instruction::{DecodeError, Instruction};
+use crate::vm::io::Frame;
+use crate::vm::platform::Platform;
+use crate::vm::quirks::{IndexIncrement, Quirks};
+use crate::vm::rng::{Rng, XorShiftRng, DEFAULT_SEED};
 use crate::vm::rom::EMULATOR_ROM;
+use crate::vm::timer::{Timer, TimerMode, TICK};
+use crate::vm::trap::{Trap, TrapPolicy};
 
 #[derive(Debug)]
-struct VM {
+pub struct VM {
     /// ROM memory for CHIP-8. This holds the reserved 512 bytes as
     /// well as the program memory. It is a pristine state upon being
     /// loaded that Memory can be reset back to.
-    rom: [uint8; 0x1000],
+    pub(crate) rom: Vec<u8>,
 
     /// The ROM size.
-    rom_size: int32,
+    pub(crate) rom_size: usize,
 
     /// Memory addressable by CHIP-8. The first 512 bytes are reserved
-    /// for the font sprites, any RCA 1802 code, and the stack.
-    memory: [uint8; 0x1000],
+    /// for the font sprites, any RCA 1802 code, and the stack. This is
+    /// 4 KiB, or 64 KiB for XO-CHIP.
+    pub(crate) memory: Vec<u8>,
 
     /// Video memory for CHIP-8 (64x32 bits). Each bit represents a
     /// single pixel. It is stored MSB first. For example, pixel <0,0>
     /// is bit 0x80 of byte 0. 4x the video memory is used for the
     /// CHIP-48, which is 128x64 resolution. There are 4 extra lines
-    /// to prevent overflows when scrolling.
-    video: [uint8; 0x440],
+    /// to prevent overflows when scrolling. XO-CHIP adds a second
+    /// bitplane, so there is one buffer per plane.
+    pub(crate) video: [[u8; 0x440]; 2],
+
+    /// Bitmask of the planes that drawing, clearing and scrolling affect.
+    /// This is always 1 unless XO-CHIP selects other planes with FN01.
+    pub(crate) plane: u8,
+
+    /// The XO-CHIP audio pattern buffer. Each bit is a single sample
+    /// that is played while the sound timer is active.
+    pub(crate) pattern: [u8; 16],
+
+    /// The XO-CHIP playback pitch of the audio pattern buffer. The
+    /// sample rate is 4000*2^((pitch-64)/48) Hz.
+    pub(crate) audio_pitch: u8,
 
     /// The stack was in a reserved section of memory on the 1802.
     /// Originally it was only 12-cells deep, but later implementations
     /// went as high as 16-cells.
-    stack: [uint; 16],
+    pub(crate) stack: [usize; 16],
 
     /// The stack pointer.
-    sp: uint,
+    pub(crate) sp: usize,
 
-    /// The program counter, which always begins at 0x200.
-    pc: uint,
+    /// The program counter, which begins at the load address of the
+    /// platform (0x200 for all but the ETI-660).
+    pub(crate) pc: usize,
 
     /// The VM registers.
-    regs: Registers,
+    pub(crate) regs: Registers,
+
+    /// Clock is the source of time for real-time emulation.
+    pub(crate) clock: Box<dyn Clock>,
 
-    /// Clock is the time (in ns) when emulation begins.
-    clock: int64,
+    /// Start is the time (in ns) on the clock when emulation begins.
+    pub(crate) start: i64,
+
+    /// Timer counts down DT and ST at 60 Hz.
+    pub(crate) timer: Timer,
 
     /// Cycles is how many clock cycles have been processed. It is assumed
     /// one clock cycle per instruction.
-    cycles: int64,
+    pub(crate) cycles: i64,
 
     /// Speed is how many cycles (instructions) should execute per second.
-    /// By default this is 700. The RCA CDP1802 ran at 1.76 MHz, with each
-    /// instruction taking 16-24 clock cycles, which is a bit over 70,000
-    /// instructions per second.
-    speed: int64,
+    /// The default depends on the platform, 700 for the VIP. The RCA
+    /// CDP1802 ran at 1.76 MHz, with each instruction taking 16-24 clock
+    /// cycles, which is a bit over 70,000 instructions per second.
+    pub(crate) speed: i64,
 
     /// Keys hold the current state for the 16-key pad keys.
-    keys: [bool; 16],
+    pub(crate) keys: [bool; 16],
 
     /// Number of bytes per scan line. This is 8 in low mode and 16 when high.
-    pitch: int32
+    pub(crate) pitch: usize,
+
+    /// Number of scan lines in the current display mode.
+    pub(crate) height: usize,
+
+    /// The number of stack cells the program may use. This is 12 on the
+    /// VIP and 16 on later interpreters.
+    pub(crate) stack_depth: usize,
+
+    /// The generator used by CXNN.
+    pub(crate) rng: Box<dyn Rng>,
+
+    /// The seed the generator starts from on reset.
+    pub(crate) seed: u32,
+
+    /// Set once the program executes 00FD (SCHIP exit).
+    pub(crate) exited: bool,
+
+    /// What happens when the program traps.
+    pub(crate) trap_policy: TrapPolicy,
+
+    /// The trap that halted the VM, if any.
+    pub(crate) trap: Option<Trap>,
+
+    /// The machine being emulated.
+    pub(crate) platform: Platform,
+
+    /// The behaviors the executor follows where interpreters disagree.
+    pub(crate) quirks: Quirks,
+
+    /// The 60 Hz timer tick in which a sprite was last drawn. Used to
+    /// wait for the vertical blank when the display wait quirk is enabled.
+    pub(crate) draw_frame: i64,
+
+    /// How many instructions `run_frame` executes per 60 Hz frame.
+    pub(crate) cycles_per_frame: i64,
+
+    /// Set whenever video memory or the display mode changes.
+    pub(crate) dirty: bool,
+
+    /// Set while FX0A is waiting for a key to be pressed.
+    pub(crate) waiting: bool,
+}
+
+/// What happened while running a single frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameResult {
+    /// Number of instructions executed.
+    pub cycles: i64,
+
+    /// True if video memory or the display mode changed since the last
+    /// frame, or the VM was reset or had a state loaded.
+    pub video_changed: bool,
+
+    /// True if the sound timer is active at the end of the frame.
+    pub sound: bool,
+
+    /// True if the program is blocked on FX0A waiting for a key.
+    pub waiting_for_key: bool,
+
+    /// True if the program has exited.
+    pub exited: bool,
 }
 
 #[derive(Debug)]
-struct Registers {
+pub struct Registers {
     /// I is the address register.
-    i: uint,
+    pub i: usize,
 
     /// V are the 16 virtual registers.
-    v: [uint8; 16],
+    pub v: [u8; 16],
 
     /// R are the 8, HP-RPL user flags.
-    r: [uint8; 8],
+    pub r: [u8; 8],
 
-    /// DT is the delay timer register. It is set to a time (in ns) in the
-    /// future and compared against the current time.
-    dt: int64,
+    /// DT is the delay timer register. It counts down at 60 Hz until it
+    /// reaches 0.
+    pub dt: u8,
 
-    /// ST is the sound timer register. It is set to a time (in ns) in the
-    /// future and compared against the current time.
-    st: int64
+    /// ST is the sound timer register. It counts down at 60 Hz and the
+    /// buzzer sounds for as long as it is not 0.
+    pub st: u8
 }
 
-pub fn load_rom(program: Vec<uint8>) -> Result<VM, Error> {
+pub fn load_rom(program: Vec<u8>, platform: Platform) -> Result<VM, ChipperError> {
+    if program.is_empty() {
+        return Err(ChipperError::EmptyRom);
+    }
+
     // Check if the program fits within memory
-    if program.len() > 0x800 {
-        return Err("The program is too large to fit into memory")
+    if program.len() > platform.max_program_size() {
+        return Err(ChipperError::RomTooLarge {
+            size: program.len(),
+            max: platform.max_program_size(),
+            platform,
+        });
     }
 
-    let mut vm = VM::new();
+    let mut vm = VM::new(platform);
+    let base = platform.load_address();
 
     vm.rom_size = program.len();
     vm.rom[..0x200].clone_from_slice(&EMULATOR_ROM);
-    vm.rom[0x200..].clone_from_slice(&program);
+    vm.rom[base..base + program.len()].clone_from_slice(&program);
+    vm.reset();
 
     Ok(vm)
 }
 
 impl VM {
-    pub fn new() -> VM {
+    pub fn new(platform: Platform) -> VM {
+        let (width, height) = platform.resolution();
+
         VM {
-            rom: [0; 0x1000],
+            rom: vec![0; platform.memory_size()],
             rom_size: 0,
-            memory: [0; 0x1000],
-            video: [0; 0x440],
+            memory: vec![0; platform.memory_size()],
+            video: [[0; 0x440]; 2],
+            plane: 1,
+            pattern: [0; 16],
+            audio_pitch: 64,
             stack: [0; 16],
             sp: 0,
-            pc: 0x200,
+            pc: platform.load_address(),
             regs: Registers::new(),
-            clock: (),
-            cycles: (),
-            speed: 700,
+            clock: Box::new(SystemClock),
+            start: 0,
+            timer: Timer::default(),
+            cycles: 0,
+            speed: platform.speed(),
             keys: [false; 16],
-            pitch: 8
+            pitch: width / 8,
+            height,
+            stack_depth: platform.stack_depth(),
+            rng: Box::new(XorShiftRng::default()),
+            seed: DEFAULT_SEED,
+            exited: false,
+            trap_policy: TrapPolicy::default(),
+            trap: None,
+            platform,
+            quirks: platform.quirks(),
+            draw_frame: -1,
+            cycles_per_frame: (platform.speed() / 60).max(1),
+            dirty: true,
+            waiting: false,
+        }
+    }
+
+    /// Restores memory from the ROM and resets all registers, video
+    /// memory and the clock so the program runs again from the start.
+    /// Only the timers follow the clock; the random number generator
+    /// starts from the same seed every time, the one last given to
+    /// `set_seed`.
+    pub fn reset(&mut self) {
+        let base = self.platform.load_address();
+        let size = base + self.rom_size;
+        let (width, height) = self.platform.resolution();
+
+        self.memory.iter_mut().for_each(|b| *b = 0);
+        self.memory[..size].copy_from_slice(&self.rom[..size]);
+        self.video = [[0; 0x440]; 2];
+        self.plane = 1;
+        self.pattern = [0; 16];
+        self.audio_pitch = 64;
+        self.stack = [0; 16];
+        self.sp = 0;
+        self.pc = base;
+        self.regs = Registers::new();
+        self.start = self.clock.now();
+        self.timer.reset();
+        self.cycles = 0;
+        self.keys = [false; 16];
+        self.pitch = width / 8;
+        self.height = height;
+        self.rng.seed(self.seed);
+        self.exited = false;
+        self.trap = None;
+        self.draw_frame = -1;
+        self.dirty = true;
+        self.waiting = false;
+    }
+
+    /// Returns the number of instructions that should have executed by
+    /// the current time, given the speed of the VM.
+    pub fn cycles_due(&self) -> i64 {
+        self.elapsed() * self.speed / 1_000_000_000
+    }
+
+    /// Returns the time (in ns) on the clock since emulation began.
+    pub fn elapsed(&self) -> i64 {
+        self.clock.now() - self.start
+    }
+
+    /// Replaces the clock and restarts emulation time from it. Use a
+    /// `ManualClock` to control time from tests or a virtual frontend.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+        self.start = self.clock.now() - self.timer.ticks() * TICK;
+    }
+
+    /// Returns what the delay and sound timers count down against.
+    pub fn timer_mode(&self) -> TimerMode {
+        self.timer.mode()
+    }
+
+    /// Changes what the delay and sound timers count down against.
+    pub fn set_timer_mode(&mut self, mode: TimerMode) {
+        self.timer.set_mode(mode);
+    }
+
+    /// Returns the number of clock cycles (instructions) processed.
+    pub fn cycles(&self) -> i64 {
+        self.cycles
+    }
+
+    /// Returns how many instructions execute per second.
+    pub fn speed(&self) -> i64 {
+        self.speed
+    }
+
+    /// Changes how many instructions execute per second. With a virtual
+    /// timer, the timers keep the ticks they've counted so far and tick
+    /// at the new rate from here on.
+    pub fn set_speed(&mut self, speed: i64) {
+        self.timer.rebase(self.cycles, self.speed);
+        self.speed = speed.max(1);
+    }
+
+    /// Catches the delay and sound timers up to the current tick.
+    fn update_timers(&mut self) {
+        let due = self.timer.due(self.cycles, self.speed, self.elapsed());
+
+        self.timer.update(due, &mut self.regs);
+    }
+
+    /// Fetches the opcode at the program counter, decodes it and executes
+    /// it. Each call is a single clock cycle. Once the program has exited
+    /// this does nothing, and once it has been halted by a trap this
+    /// returns the trap again.
+    pub fn step(&mut self) -> Result<(), ChipperError> {
+        if let Some(trap) = self.trap {
+            return Err(trap.into());
+        }
+
+        if self.exited {
+            return Ok(());
+        }
+
+        self.update_timers();
+        self.cycle()
+    }
+
+    /// Runs a single 60 Hz frame: executes the configured number of
+    /// instructions and then ticks the delay and sound timers once. The
+    /// frame ends early if the program exits or traps.
+    ///
+    /// Timers are only ticked here, so when driving the VM with frames
+    /// the timer mode and the clock don't matter.
+    pub fn run_frame(&mut self) -> Result<FrameResult, ChipperError> {
+        let start = self.cycles;
+
+        if let Some(trap) = self.trap {
+            return Err(trap.into());
+        }
+
+        for _ in 0..self.cycles_per_frame {
+            if self.exited {
+                break;
+            }
+
+            self.cycle()?;
+        }
+
+        let due = self.timer.ticks() + 1;
+        self.timer.update(due, &mut self.regs);
+
+        // Changes made by a reset or a loaded state since the last frame
+        // count as well, so they get presented.
+        let video_changed = std::mem::take(&mut self.dirty);
+
+        Ok(FrameResult {
+            cycles: self.cycles - start,
+            video_changed,
+            sound: self.sound(),
+            waiting_for_key: self.waiting,
+            exited: self.exited,
+        })
+    }
+
+    /// Returns how many instructions `run_frame` executes per frame.
+    pub fn cycles_per_frame(&self) -> i64 {
+        self.cycles_per_frame
+    }
+
+    /// Changes how many instructions `run_frame` executes per frame.
+    pub fn set_cycles_per_frame(&mut self, cycles: i64) {
+        self.cycles_per_frame = cycles.max(1);
+    }
+
+    /// Fetches, decodes and executes the instruction at the program
+    /// counter without updating the timers.
+    fn cycle(&mut self) -> Result<(), ChipperError> {
+        let addr = self.pc;
+        let inst = match self.instruction(addr) {
+            Ok(inst) => inst,
+            Err(err) => {
+                self.pc = (self.pc + 2) & self.mask();
+                self.cycles += 1;
+
+                return self.raise(Trap::UnknownOpcode { opcode: err.opcode, addr });
+            },
+        };
+
+        self.pc = (self.pc + inst.size()) & self.mask();
+        self.cycles += 1;
+
+        self.execute_at(inst, addr)
+    }
+
+    /// Decodes the instruction at an address. On XO-CHIP this includes
+    /// the second word of a long load. Instructions the platform doesn't
+    /// have are unknown opcodes.
+    pub fn instruction(&self, addr: usize) -> Result<Instruction, DecodeError> {
+        let op = self.word(addr);
+
+        let inst = if self.platform == Platform::XoChip {
+            Instruction::decode_long(op, self.word(addr + 2))?
+        } else {
+            Instruction::decode(op)?
+        };
+
+        if self.platform.supports(inst) {
+            Ok(inst)
+        } else {
+            Err(DecodeError { opcode: op })
+        }
+    }
+
+    /// Executes a single, decoded instruction. Any fault is reported at
+    /// the current program counter, and an instruction the platform
+    /// doesn't have is an unknown opcode.
+    pub fn execute(&mut self, inst: Instruction) -> Result<(), ChipperError> {
+        let addr = self.pc;
+
+        if !self.platform.supports(inst) {
+            return self.raise(Trap::UnknownOpcode { opcode: inst.encode(), addr });
+        }
+
+        self.execute_at(inst, addr)
+    }
+
+    /// Executes an instruction that was fetched from addr. Any trap it
+    /// hits is handled by the trap policy.
+    fn execute_at(&mut self, inst: Instruction, addr: usize) -> Result<(), ChipperError> {
+        match self.apply(inst, addr) {
+            Ok(()) => Ok(()),
+            Err(trap) => self.raise(trap),
+        }
+    }
+
+    /// Applies the effects of an instruction fetched from addr, stopping
+    /// at the first trap.
+    fn apply(&mut self, inst: Instruction, addr: usize) -> Result<(), Trap> {
+        let mask = self.mask();
+        let v = &mut self.regs.v;
+
+        match inst {
+            Instruction::Sys(_) => (), // Ignored by modern interpreters.
+            Instruction::Cls => self.cls(),
+            Instruction::Ret => self.ret(addr)?,
+            Instruction::ScrollDown(n) => self.scroll_down(n as usize),
+            Instruction::ScrollUp(n) => self.scroll_up(n as usize),
+            Instruction::ScrollRight => self.scroll_right(),
+            Instruction::ScrollLeft => self.scroll_left(),
+            Instruction::Exit => self.exited = true,
+            Instruction::LowRes => self.set_resolution(false),
+            Instruction::HighRes => self.set_resolution(true),
+            Instruction::Jump(addr) => self.pc = addr as usize,
+            Instruction::Call(target) => self.call(target as usize, addr)?,
+            Instruction::SkipEqImm { x, nn } => if v[x as usize] == nn { self.skip() },
+            Instruction::SkipNeImm { x, nn } => if v[x as usize] != nn { self.skip() },
+            Instruction::SkipEq { x, y } => if v[x as usize] == v[y as usize] { self.skip() },
+            Instruction::SaveRange { x, y } => self.save_range(x as usize, y as usize, addr)?,
+            Instruction::LoadRange { x, y } => self.load_range(x as usize, y as usize, addr)?,
+            Instruction::LoadImm { x, nn } => v[x as usize] = nn,
+            Instruction::AddImm { x, nn } => v[x as usize] = v[x as usize].wrapping_add(nn),
+            Instruction::Load { x, y } => v[x as usize] = v[y as usize],
+            Instruction::Or { x, y } => {
+                v[x as usize] |= v[y as usize];
+                if self.quirks.vf_reset { v[0xF] = 0 }
+            },
+            Instruction::And { x, y } => {
+                v[x as usize] &= v[y as usize];
+                if self.quirks.vf_reset { v[0xF] = 0 }
+            },
+            Instruction::Xor { x, y } => {
+                v[x as usize] ^= v[y as usize];
+                if self.quirks.vf_reset { v[0xF] = 0 }
+            },
+            Instruction::Add { x, y } => {
+                let (sum, carry) = v[x as usize].overflowing_add(v[y as usize]);
+                v[x as usize] = sum;
+                v[0xF] = carry as u8;
+            },
+            Instruction::Sub { x, y } => {
+                let (diff, borrow) = v[x as usize].overflowing_sub(v[y as usize]);
+                v[x as usize] = diff;
+                v[0xF] = !borrow as u8;
+            },
+            Instruction::Shr { x, y } => {
+                let src = if self.quirks.shift_vy { v[y as usize] } else { v[x as usize] };
+                v[x as usize] = src >> 1;
+                v[0xF] = src & 0x01;
+            },
+            Instruction::SubN { x, y } => {
+                let (diff, borrow) = v[y as usize].overflowing_sub(v[x as usize]);
+                v[x as usize] = diff;
+                v[0xF] = !borrow as u8;
+            },
+            Instruction::Shl { x, y } => {
+                let src = if self.quirks.shift_vy { v[y as usize] } else { v[x as usize] };
+                v[x as usize] = src << 1;
+                v[0xF] = src >> 7;
+            },
+            Instruction::SkipNe { x, y } => if v[x as usize] != v[y as usize] { self.skip() },
+            Instruction::LoadI(nnn) => self.regs.i = nnn as usize,
+            Instruction::JumpV0(nnn) => {
+                let r = if self.quirks.jump_vx { (nnn >> 8) as usize & 0xF } else { 0 };
+                self.pc = (nnn as usize + v[r] as usize) & 0xFFF;
+            },
+            Instruction::Rand { x, nn } => self.regs.v[x as usize] = self.rng.next_byte() & nn,
+            Instruction::Draw { x, y, n } => self.draw(x as usize, y as usize, n, addr)?,
+            Instruction::SkipKey { x } => if self.keys[v[x as usize] as usize & 0xF] { self.skip() },
+            Instruction::SkipNotKey { x } => if !self.keys[v[x as usize] as usize & 0xF] { self.skip() },
+            Instruction::LoadILong(nnnn) => self.regs.i = nnnn as usize & mask,
+            Instruction::Plane(n) => self.plane = n & 0x3,
+            Instruction::Audio => {
+                let range = self.range(self.regs.i, 16, addr)?;
+                self.pattern.copy_from_slice(&self.memory[range]);
+            },
+            Instruction::Pitch { x } => self.audio_pitch = v[x as usize],
+            Instruction::LoadDelay { x } => v[x as usize] = self.regs.dt,
+            Instruction::WaitKey { x } => self.wait_key(x as usize),
+            Instruction::SetDelay { x } => self.regs.dt = v[x as usize],
+            Instruction::SetSound { x } => self.regs.st = v[x as usize],
+            Instruction::AddI { x } => self.regs.i = (self.regs.i + v[x as usize] as usize) & mask,
+            Instruction::Font { x } => match v[x as usize] {
+                digit @ 0x0..=0xF => self.regs.i = digit as usize * 5,
+                digit => return Err(Trap::InvalidFontDigit { digit, addr }),
+            },
+            Instruction::BigFont { x } => match v[x as usize] {
+                digit @ 0x0..=0x9 => self.regs.i = 80 + digit as usize * 10,
+                digit => return Err(Trap::InvalidFontDigit { digit, addr }),
+            },
+            Instruction::Bcd { x } => {
+                let n = self.regs.v[x as usize];
+                let range = self.range(self.regs.i, 3, addr)?;
+
+                self.memory[range].copy_from_slice(&[n / 100, n / 10 % 10, n % 10]);
+            },
+            Instruction::Store { x } => {
+                let n = x as usize + 1;
+                let range = self.range(self.regs.i, n, addr)?;
+
+                self.memory[range].copy_from_slice(&self.regs.v[..n]);
+                self.increment_i(x as usize);
+            },
+            Instruction::Restore { x } => {
+                let n = x as usize + 1;
+                let range = self.range(self.regs.i, n, addr)?;
+
+                self.regs.v[..n].copy_from_slice(&self.memory[range]);
+                self.increment_i(x as usize);
+            },
+            Instruction::StoreFlags { x } => {
+                let n = x.min(7) as usize + 1;
+                self.regs.r[..n].copy_from_slice(&v[..n]);
+            },
+            Instruction::LoadFlags { x } => {
+                let n = x.min(7) as usize + 1;
+                v[..n].copy_from_slice(&self.regs.r[..n]);
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Returns the VM registers.
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    /// Returns the VM registers, to change them.
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    /// Returns all of addressable memory.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Returns all of addressable memory, to change it.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Returns the program counter.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Moves the program counter, wrapping it within memory.
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc & self.mask();
+    }
+
+    /// Returns the return addresses on the stack, oldest first.
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[..self.sp]
+    }
+
+    /// Returns video memory of the first bitplane along with the number
+    /// of bytes per scan line.
+    pub fn video(&self) -> (&[u8], usize) {
+        (&self.video[0], self.pitch)
+    }
+
+    /// Returns video memory of bitplane 0 or 1.
+    pub fn plane(&self, n: usize) -> &[u8] {
+        &self.video[n & 1]
+    }
+
+    /// Returns the XO-CHIP audio pattern buffer and its playback pitch.
+    pub fn audio_pattern(&self) -> (&[u8; 16], u8) {
+        (&self.pattern, self.audio_pitch)
+    }
+
+    /// Returns a snapshot of video memory for a display.
+    pub fn frame(&self) -> Frame<'_> {
+        Frame {
+            planes: [&self.video[0], &self.video[1]],
+            pitch: self.pitch,
+            width: self.pitch * 8,
+            height: self.height,
+        }
+    }
+
+    /// Returns which keys on the 16-key pad are held down.
+    pub fn keys(&self) -> [bool; 16] {
+        self.keys
+    }
+
+    /// Sets whether a key on the 16-key pad is held down.
+    pub fn set_key(&mut self, key: usize, down: bool) {
+        self.keys[key & 0xF] = down;
+    }
+
+    /// Sets the state of all 16 keys at once.
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        self.keys = keys;
+    }
+
+    /// Returns the program that was loaded, without the reserved bytes.
+    pub fn program(&self) -> &[u8] {
+        let base = self.platform.load_address();
+
+        &self.rom[base..base + self.rom_size]
+    }
+
+    /// Returns the state of the generator used by CXNN.
+    pub fn seed(&self) -> u32 {
+        self.rng.state()
+    }
+
+    /// Seeds the generator used by CXNN, now and whenever the VM is
+    /// reset.
+    pub fn set_seed(&mut self, seed: u32) {
+        self.seed = seed;
+        self.rng.seed(seed);
+    }
+
+    /// Replaces the generator used by CXNN, e.g. with a `VipRng` to get
+    /// the same random stream as the original interpreter. The new
+    /// generator is seeded from the current state of the old one.
+    pub fn set_rng(&mut self, mut rng: Box<dyn Rng>) {
+        rng.seed(self.rng.state());
+        self.rng = rng;
+    }
+
+    /// Returns the trap that halted the VM, if any.
+    pub fn trap(&self) -> Option<Trap> {
+        self.trap
+    }
+
+    /// Returns what happens when the program traps.
+    pub fn trap_policy(&self) -> TrapPolicy {
+        self.trap_policy
+    }
+
+    /// Changes what happens when the program traps.
+    pub fn set_trap_policy(&mut self, policy: TrapPolicy) {
+        self.trap_policy = policy;
+    }
+
+    /// Returns the number of stack cells the program may use.
+    pub fn stack_depth(&self) -> usize {
+        self.stack_depth
+    }
+
+    /// Changes the number of stack cells the program may use, usually
+    /// 12 for the VIP or 16 for later interpreters. The depth is clamped
+    /// to between 1 and 16 cells.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack_depth = depth.max(1).min(self.stack.len());
+        self.sp = self.sp.min(self.stack_depth);
+    }
+
+    /// Returns the platform being emulated.
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    /// Returns the display resolution (width, height) in pixels.
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.pitch * 8, self.height)
+    }
+
+    /// Returns the quirks the executor follows.
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    /// Changes the quirks the executor follows.
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// True once the program has executed 00FD.
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// True while the sound timer is still counting down.
+    pub fn sound(&self) -> bool {
+        self.regs.st > 0
+    }
+
+    /// Mask applied to every address so it wraps within memory.
+    fn mask(&self) -> usize {
+        self.memory.len() - 1
+    }
+
+    /// Returns the range of n bytes of memory starting at start, or an
+    /// trap for the instruction at addr if it runs past the end.
+    fn range(&self, start: usize, n: usize, addr: usize) -> Result<Range<usize>, Trap> {
+        if start + n > self.memory.len() {
+            return Err(Trap::MemoryOutOfRange { access: start + n - 1, addr });
         }
+
+        Ok(start..start + n)
+    }
+
+    /// Reads the big-endian word at an address.
+    fn word(&self, addr: usize) -> u16 {
+        let mask = self.mask();
+
+        (self.memory[addr & mask] as u16) << 8 | self.memory[(addr + 1) & mask] as u16
+    }
+
+    /// Skips the next instruction. On XO-CHIP this steps over both words
+    /// of a long load.
+    fn skip(&mut self) {
+        let size = if self.platform == Platform::XoChip && self.word(self.pc) == 0xF000 { 4 } else { 2 };
+
+        self.pc = (self.pc + size) & self.mask();
+    }
+
+    /// Returns the indexes of the bitplanes currently selected.
+    fn planes(&self) -> impl Iterator<Item = usize> {
+        let plane = self.plane;
+
+        (0..2).filter(move |n| plane & (1 << n) != 0)
+    }
+
+    /// Clears video memory of the selected planes.
+    fn cls(&mut self) {
+        for n in self.planes() {
+            self.video[n] = [0; 0x440];
+        }
+
+        self.dirty = true;
+    }
+
+    /// Modifies I after FX55 or FX65 according to the quirks.
+    fn increment_i(&mut self, x: usize) {
+        let n = match self.quirks.index_increment {
+            IndexIncrement::Unchanged => 0,
+            IndexIncrement::ByX => x,
+            IndexIncrement::ByXPlusOne => x + 1,
+        };
+
+        self.regs.i = (self.regs.i + n) & self.mask();
+    }
+
+    /// Stores VX through VY in memory at I. When X > Y the registers are
+    /// stored in reverse order. I is not modified.
+    fn save_range(&mut self, x: usize, y: usize, addr: usize) -> Result<(), Trap> {
+        let range = self.range(self.regs.i, x.max(y) - x.min(y) + 1, addr)?;
+
+        for (k, r) in range.zip(register_range(x, y)) {
+            self.memory[k] = self.regs.v[r];
+        }
+
+        Ok(())
+    }
+
+    /// Loads VX through VY from memory at I. When X > Y the registers are
+    /// loaded in reverse order. I is not modified.
+    fn load_range(&mut self, x: usize, y: usize, addr: usize) -> Result<(), Trap> {
+        let range = self.range(self.regs.i, x.max(y) - x.min(y) + 1, addr)?;
+
+        for (k, r) in range.zip(register_range(x, y)) {
+            self.regs.v[r] = self.memory[k];
+        }
+
+        Ok(())
+    }
+
+    /// Handles a trap according to the trap policy. When halting, the
+    /// program counter is left pointing at the instruction that trapped.
+    /// Otherwise the instruction is skipped.
+    fn raise(&mut self, trap: Trap) -> Result<(), ChipperError> {
+        match self.trap_policy {
+            TrapPolicy::Halt => {
+                self.pc = trap.addr();
+                self.trap = Some(trap);
+
+                Err(trap.into())
+            },
+            TrapPolicy::Ignore | TrapPolicy::Wrap => Ok(()),
+        }
+    }
+
+    /// Pushes the program counter onto the stack and jumps to address.
+    fn call(&mut self, address: usize, addr: usize) -> Result<(), Trap> {
+        if self.sp >= self.stack_depth {
+            match self.trap_policy {
+                TrapPolicy::Wrap => self.sp = 0,
+                _ => return Err(Trap::StackOverflow { addr }),
+            }
+        }
+
+        self.stack[self.sp] = self.pc;
+        self.sp += 1;
+        self.pc = address;
+
+        Ok(())
+    }
+
+    /// Pops the return address off the stack.
+    fn ret(&mut self, addr: usize) -> Result<(), Trap> {
+        if self.sp == 0 {
+            match self.trap_policy {
+                TrapPolicy::Wrap => self.sp = self.stack_depth,
+                _ => return Err(Trap::StackUnderflow { addr }),
+            }
+        }
+
+        self.sp -= 1;
+        self.pc = self.stack[self.sp];
+
+        Ok(())
+    }
+
+    /// Waits for a key to be pressed by re-executing the instruction
+    /// until one is, then stores it in VX.
+    fn wait_key(&mut self, x: usize) {
+        match self.keys.iter().position(|&down| down) {
+            Some(key) => {
+                self.regs.v[x] = key as u8;
+                self.waiting = false;
+            },
+            None => {
+                self.pc = self.pc.wrapping_sub(2) & self.mask();
+                self.waiting = true;
+            },
+        }
+    }
+
+    /// Switches between the low-res and high-res display modes, clearing
+    /// every plane whichever are selected, as Octo does. Platforms
+    /// without a high-res mode ignore this.
+    fn set_resolution(&mut self, hires: bool) {
+        let (width, height) = match self.platform.hires_resolution() {
+            Some(res) if hires => res,
+            Some(_) => self.platform.resolution(),
+            None => return,
+        };
+
+        self.pitch = width / 8;
+        self.height = height;
+        self.video = [[0; 0x440]; 2];
+        self.dirty = true;
+    }
+
+    /// Scrolls the selected planes down N lines, clearing the lines at
+    /// the top.
+    fn scroll_down(&mut self, n: usize) {
+        self.dirty = true;
+
+        let size = self.pitch * self.height;
+        let n = (n * self.pitch).min(size);
+
+        for p in self.planes() {
+            self.video[p].copy_within(..size - n, n);
+            self.video[p][..n].iter_mut().for_each(|b| *b = 0);
+        }
+    }
+
+    /// Scrolls the selected planes up N lines, clearing the lines at the
+    /// bottom.
+    fn scroll_up(&mut self, n: usize) {
+        self.dirty = true;
+
+        let size = self.pitch * self.height;
+        let n = (n * self.pitch).min(size);
+
+        for p in self.planes() {
+            self.video[p].copy_within(n..size, 0);
+            self.video[p][size - n..size].iter_mut().for_each(|b| *b = 0);
+        }
+    }
+
+    /// Scrolls the selected planes right 4 pixels.
+    fn scroll_right(&mut self) {
+        self.dirty = true;
+
+        let size = self.pitch * self.height;
+
+        for p in self.planes() {
+            for line in self.video[p][..size].chunks_mut(self.pitch) {
+                for i in (0..line.len()).rev() {
+                    let carry = if i > 0 { line[i - 1] << 4 } else { 0 };
+                    line[i] = line[i] >> 4 | carry;
+                }
+            }
+        }
+    }
+
+    /// Scrolls the selected planes left 4 pixels.
+    fn scroll_left(&mut self) {
+        self.dirty = true;
+
+        let size = self.pitch * self.height;
+
+        for p in self.planes() {
+            for line in self.video[p][..size].chunks_mut(self.pitch) {
+                for i in 0..line.len() {
+                    let carry = if i + 1 < line.len() { line[i + 1] >> 4 } else { 0 };
+                    line[i] = line[i] << 4 | carry;
+                }
+            }
+        }
+    }
+
+    /// Draws an N-byte sprite from I at <VX,VY>, or a 16x16 sprite of
+    /// 32 bytes when N is 0. Pixels are XOR'ed onto the screen and VF is
+    /// set if any pixel was erased. The sprite origin wraps around the
+    /// screen, and the sprite itself is clipped or wrapped depending on
+    /// the quirks. When several planes are selected, the sprite data for
+    /// each plane follows the previous.
+    ///
+    /// With the display wait quirk only one sprite is drawn per 60 Hz
+    /// frame; a second draw in the same frame is retried until the next.
+    fn draw(&mut self, x: usize, y: usize, n: u8, addr: usize) -> Result<(), Trap> {
+        if self.quirks.display_wait {
+            let frame = self.timer.ticks();
+
+            if frame <= self.draw_frame {
+                self.pc = addr;
+                return Ok(());
+            }
+
+            self.draw_frame = frame;
+        }
+
+        let clip = self.quirks.clip_sprites;
+        let width = self.pitch * 8;
+        let height = self.height;
+        let left = self.regs.v[x] as usize % width;
+        let top = self.regs.v[y] as usize % height;
+        let (rows, cols) = if n == 0 { (16, 16) } else { (n as usize, 8) };
+        let planes = self.planes().collect::<Vec<_>>();
+        let sprite = self.range(self.regs.i, rows * cols / 8 * planes.len(), addr)?;
+        let mut base = sprite.start;
+
+        self.dirty = true;
+        self.regs.v[0xF] = 0;
+
+        for p in planes {
+            for row in 0..rows {
+                let line = if clip { top + row } else { (top + row) % height };
+
+                if line >= height {
+                    break;
+                }
+
+                let bits = if cols == 16 {
+                    self.word(base + row * 2)
+                } else {
+                    (self.memory[base + row] as u16) << 8
+                };
+
+                for col in 0..cols {
+                    let px = if clip { left + col } else { (left + col) % width };
+
+                    if px >= width || bits & (0x8000 >> col) == 0 {
+                        continue;
+                    }
+
+                    let addr = line * self.pitch + px / 8;
+                    let bit = 0x80 >> (px % 8);
+
+                    if self.video[p][addr] & bit != 0 {
+                        self.regs.v[0xF] = 1;
+                    }
+
+                    self.video[p][addr] ^= bit;
+                }
+            }
+
+            base += rows * cols / 8;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new(Platform::default())
     }
 }
 
@@ -121,4 +1038,534 @@ impl Registers {
             st: 0
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Registers::new()
+    }
+}
+
+/// Returns the register indexes from X to Y, in reverse when X > Y.
+fn register_range(x: usize, y: usize) -> Box<dyn Iterator<Item = usize>> {
+    if x <= y { Box::new(x..=y) } else { Box::new((y..=x).rev()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::rng::VipRng;
+    use crate::vm::test::program;
+
+    fn vm() -> VM {
+        program(Platform::Chip8)
+    }
+
+    #[test]
+    fn frames_are_identical_across_runs() {
+        let (mut a, mut b) = (vm(), vm());
+
+        for _ in 0..120 {
+            assert_eq!(a.run_frame().unwrap(), b.run_frame().unwrap());
+            assert_eq!(a.video_hash(), b.video_hash());
+            assert_eq!(a.state_hash(), b.state_hash());
+        }
+    }
+
+    #[test]
+    fn steps_are_identical_across_runs_with_a_virtual_timer() {
+        let (mut a, mut b) = (vm(), vm());
+
+        for _ in 0..5000 {
+            a.step().unwrap();
+            b.step().unwrap();
+
+            assert_eq!(a.registers().v, b.registers().v);
+            assert_eq!(a.registers().dt, b.registers().dt);
+        }
+
+        assert_eq!(a.video_hash(), b.video_hash());
+    }
+
+    /// Returns the bytes CXNN produces from a seed.
+    fn random_bytes(seed: u32) -> Vec<u8> {
+        let mut vm = load_rom(vec![0xC0, 0xFF, 0x12, 0x00], Platform::Chip8).unwrap();
+
+        vm.set_seed(seed);
+
+        (0..32).map(|_| {
+            vm.step().unwrap();
+            vm.step().unwrap();
+            vm.registers().v[0]
+        }).collect()
+    }
+
+    #[test]
+    fn frame_results_report_what_happened() {
+        let rom = vec![0x60, 0x02, 0xF0, 0x18, 0x61, 0x00, 0x61, 0x00, 0x00, 0xE0, 0x00, 0xFD];
+        let mut vm = load_rom(rom, Platform::SuperChip).unwrap();
+        let result = |cycles, video_changed, sound, exited| FrameResult { cycles, video_changed, sound, waiting_for_key: false, exited };
+
+        vm.set_cycles_per_frame(2);
+
+        // A new VM has a screen to present. ST is 2, less the frame's tick.
+        assert_eq!(vm.run_frame().unwrap(), result(2, true, true, false));
+        assert_eq!(vm.run_frame().unwrap(), result(2, false, false, false));
+
+        // CLS changes the screen even though it was already blank.
+        assert_eq!(vm.run_frame().unwrap(), result(2, true, false, true));
+        assert_eq!(vm.run_frame().unwrap(), result(0, false, false, true));
+    }
+
+    #[test]
+    fn virtual_timers_carry_on_across_speed_changes() {
+        let mut vm = load_rom(vec![0x12, 0x00], Platform::Chip8).unwrap();
+
+        vm.set_speed(600);
+        vm.registers_mut().dt = 100;
+
+        for _ in 0..101 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.registers().dt, 90);
+
+        // 100 cycles is a fraction of a tick at the new speed...
+        vm.set_speed(60_000);
+
+        for _ in 0..100 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.registers().dt, 90);
+
+        // ...and a tick each at this one.
+        vm.set_speed(60);
+
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.registers().dt, 86);
+    }
+
+    #[test]
+    fn same_seed_gives_same_random_bytes() {
+        assert_eq!(random_bytes(1234), random_bytes(1234));
+        assert_ne!(random_bytes(1234), random_bytes(5678));
+    }
+
+    #[test]
+    fn reset_keeps_the_seed() {
+        let mut vm = load_rom(vec![0xC0, 0xFF, 0x12, 0x00], Platform::Chip8).unwrap();
+
+        vm.set_seed(1234);
+        vm.step().unwrap();
+        vm.reset();
+
+        assert_eq!(vm.seed(), 1234);
+
+        vm.set_rng(Box::new(VipRng::default()));
+        vm.reset();
+
+        assert_eq!(vm.seed(), 1234);
+    }
+
+    /// Loads a program that sets V0 to 0x10 and asks for its font
+    /// sprite, then sets V1.
+    fn font_fault(policy: TrapPolicy) -> VM {
+        let mut vm = load_rom(vec![0x60, 0x10, 0xF0, 0x29, 0x61, 0x01], Platform::Chip8).unwrap();
+
+        vm.set_trap_policy(policy);
+        vm
+    }
+
+    #[test]
+    fn halt_stops_on_the_faulting_instruction() {
+        let mut vm = font_fault(TrapPolicy::Halt);
+
+        vm.step().unwrap();
+
+        let err = ChipperError::InvalidFontDigit { digit: 0x10, addr: 0x202 };
+
+        assert_eq!(vm.step(), Err(err.clone()));
+        assert_eq!(vm.pc(), 0x202);
+        assert_eq!(vm.trap(), Some(Trap::InvalidFontDigit { digit: 0x10, addr: 0x202 }));
+        assert_eq!(vm.step(), Err(err));
+        assert_eq!(vm.pc(), 0x202);
+    }
+
+    #[test]
+    fn ignore_skips_the_faulting_instruction() {
+        let mut vm = font_fault(TrapPolicy::Ignore);
+
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+
+        assert_eq!(vm.registers().i, 0);
+        assert_eq!(vm.registers().v[1], 1);
+        assert_eq!(vm.trap(), None);
+    }
+
+    #[test]
+    fn memory_faults_are_traps() {
+        // I := 0xFFF, then save V0-VF past the end of memory.
+        let mut vm = load_rom(vec![0xAF, 0xFF, 0xFF, 0x55], Platform::Chip8).unwrap();
+
+        vm.step().unwrap();
+
+        assert_eq!(vm.step(), Err(ChipperError::MemoryOutOfRange { access: 0x100E, addr: 0x202 }));
+        assert_eq!(vm.pc(), 0x202);
+
+        vm.reset();
+        vm.set_trap_policy(TrapPolicy::Ignore);
+        vm.step().unwrap();
+        vm.step().unwrap();
+
+        assert_eq!(vm.pc(), 0x204);
+        assert_eq!(vm.registers().i, 0xFFF);
+    }
+
+    #[test]
+    fn reset_repeats_the_run() {
+        let mut vm = vm();
+        let mut hashes = Vec::new();
+
+        for _ in 0..60 {
+            vm.run_frame().unwrap();
+            hashes.push(vm.video_hash());
+        }
+
+        vm.reset();
+
+        for hash in hashes {
+            vm.run_frame().unwrap();
+            assert_eq!(vm.video_hash(), hash);
+        }
+    }
+
+    #[test]
+    fn platform_controls_the_opcode_set() {
+        let schip: [u16; 9] = [0x00C1, 0x00FB, 0x00FC, 0x00FD, 0x00FE, 0x00FF, 0xF030, 0xF075, 0xF085];
+        let xo_chip: [u16; 7] = [0x00D1, 0x5012, 0x5013, 0xF000, 0xF002, 0xF101, 0xF03A];
+
+        for &op in schip.iter().chain(xo_chip.iter()) {
+            for platform in [Platform::Chip8, Platform::Chip48, Platform::SuperChip] {
+                let supported = platform == Platform::SuperChip && schip.contains(&op);
+                let mut vm = load_rom(op.to_be_bytes().to_vec(), platform).unwrap();
+
+                assert_eq!(vm.step().is_ok(), supported, "{:04X} on {}", op, platform);
+
+                if !supported {
+                    assert_eq!(vm.step(), Err(ChipperError::UnknownOpcode { opcode: op, addr: 0x200 }));
+                }
+            }
+
+            let mut vm = load_rom([op.to_be_bytes(), [0x00, 0x00]].concat(), Platform::XoChip).unwrap();
+
+            assert_eq!(vm.step(), Ok(()), "{:04X} on xo-chip", op);
+        }
+    }
+
+    #[test]
+    fn executing_an_unsupported_instruction_is_an_unknown_opcode() {
+        let mut vm = vm();
+
+        assert_eq!(vm.execute(Instruction::HighRes), Err(ChipperError::UnknownOpcode { opcode: 0x00FF, addr: 0x200 }));
+    }
+
+    /// A SUPER-CHIP VM to run instructions on, with a program that loops.
+    fn schip() -> VM {
+        load_rom(vec![0x12, 0x00], Platform::SuperChip).unwrap()
+    }
+
+    /// True if the pixel at (x, y) is lit on any plane.
+    fn lit(vm: &VM, x: usize, y: usize) -> bool {
+        vm.frame().pixel(x, y) != 0
+    }
+
+    /// Draws the font sprite for 0 at (x, y), whose top row is 4 pixels.
+    fn draw_zero(vm: &mut VM, x: u8, y: u8) {
+        vm.registers_mut().i = 0;
+        vm.registers_mut().v[0] = x;
+        vm.registers_mut().v[1] = y;
+        vm.execute(Instruction::Draw { x: 0, y: 1, n: 5 }).unwrap();
+    }
+
+    #[test]
+    fn resolution_switch_clears_the_screen() {
+        let mut vm = schip();
+
+        vm.execute(Instruction::HighRes).unwrap();
+        assert_eq!(vm.resolution(), (128, 64));
+
+        draw_zero(&mut vm, 100, 60);
+        assert!(lit(&vm, 100, 60));
+
+        vm.execute(Instruction::LowRes).unwrap();
+        assert_eq!(vm.resolution(), (64, 32));
+        assert!((0..32).all(|y| (0..64).all(|x| !lit(&vm, x, y))));
+
+        vm.execute(Instruction::HighRes).unwrap();
+        assert!(!lit(&vm, 100, 60));
+    }
+
+    #[test]
+    fn scrolling_moves_pixels() {
+        let mut vm = schip();
+
+        draw_zero(&mut vm, 8, 0);
+        assert!(lit(&vm, 8, 0) && lit(&vm, 11, 0) && !lit(&vm, 12, 0));
+
+        vm.execute(Instruction::ScrollDown(2)).unwrap();
+        assert!(!lit(&vm, 8, 0) && lit(&vm, 8, 2) && lit(&vm, 8, 6));
+
+        vm.execute(Instruction::ScrollRight).unwrap();
+        assert!(!lit(&vm, 8, 2) && lit(&vm, 12, 2) && lit(&vm, 15, 2));
+
+        vm.execute(Instruction::ScrollLeft).unwrap();
+        vm.execute(Instruction::ScrollLeft).unwrap();
+        assert!(lit(&vm, 4, 2) && lit(&vm, 7, 2) && !lit(&vm, 8, 2));
+    }
+
+    #[test]
+    fn scrolling_clears_what_scrolls_in() {
+        let mut vm = schip();
+
+        draw_zero(&mut vm, 60, 30);
+        vm.execute(Instruction::ScrollRight).unwrap();
+        assert!((0..32).all(|y| (0..64).all(|x| !lit(&vm, x, y))));
+
+        draw_zero(&mut vm, 0, 0);
+        vm.execute(Instruction::ScrollDown(15)).unwrap();
+        vm.execute(Instruction::ScrollDown(15)).unwrap();
+        assert!(lit(&vm, 0, 30) && !lit(&vm, 0, 29));
+
+        vm.execute(Instruction::ScrollDown(15)).unwrap();
+        assert!((0..32).all(|y| (0..64).all(|x| !lit(&vm, x, y))));
+    }
+
+    #[test]
+    fn dxy0_draws_16_by_16_sprites() {
+        let mut vm = schip();
+        let mut sprite = [0; 32];
+
+        sprite[..2].copy_from_slice(&[0x80, 0x01]);
+        sprite[30..].copy_from_slice(&[0xFF, 0xFF]);
+        vm.memory_mut()[0x300..0x320].copy_from_slice(&sprite);
+
+        vm.execute(Instruction::HighRes).unwrap();
+        vm.registers_mut().i = 0x300;
+        vm.registers_mut().v[0] = 0;
+        vm.registers_mut().v[1] = 0;
+        vm.execute(Instruction::Draw { x: 0, y: 1, n: 0 }).unwrap();
+
+        assert!(lit(&vm, 0, 0) && !lit(&vm, 1, 0) && lit(&vm, 15, 0) && !lit(&vm, 16, 0));
+        assert!((0..16).all(|x| lit(&vm, x, 15)) && !lit(&vm, 16, 15));
+        assert!((1..15).all(|y| (0..16).all(|x| !lit(&vm, x, y))));
+        assert_eq!(vm.registers().v[0xF], 0);
+
+        vm.execute(Instruction::Draw { x: 0, y: 1, n: 0 }).unwrap();
+
+        assert!(!lit(&vm, 0, 0) && !lit(&vm, 0, 15));
+        assert_eq!(vm.registers().v[0xF], 1);
+    }
+
+    #[test]
+    fn user_flags_store_and_load_up_to_v7() {
+        let mut vm = schip();
+
+        vm.registers_mut().v = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+        vm.execute(Instruction::StoreFlags { x: 15 }).unwrap();
+        assert_eq!(vm.registers().r, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        vm.registers_mut().v = [0; 16];
+        vm.execute(Instruction::LoadFlags { x: 2 }).unwrap();
+        assert_eq!(vm.registers().v[..4], [1, 2, 3, 0]);
+    }
+
+    /// An XO-CHIP VM to run instructions on, with a program that loops.
+    fn xo_chip() -> VM {
+        load_rom(vec![0x12, 0x00], Platform::XoChip).unwrap()
+    }
+
+    #[test]
+    fn resolution_switch_clears_every_plane() {
+        let mut vm = xo_chip();
+
+        vm.execute(Instruction::Plane(3)).unwrap();
+        draw_zero(&mut vm, 0, 0);
+        vm.execute(Instruction::Plane(1)).unwrap();
+        vm.execute(Instruction::HighRes).unwrap();
+
+        assert!(vm.plane(0).iter().chain(vm.plane(1)).all(|&b| b == 0));
+    }
+
+    #[test]
+    fn planes_select_where_sprites_are_drawn() {
+        let mut vm = xo_chip();
+
+        vm.execute(Instruction::Plane(2)).unwrap();
+        draw_zero(&mut vm, 0, 0);
+        assert_eq!(vm.frame().pixel(0, 0), 2);
+
+        // Both planes take a sprite each, the second following the first:
+        // plane 1 gets the font sprite for 1.
+        vm.execute(Instruction::Plane(3)).unwrap();
+        draw_zero(&mut vm, 8, 0);
+        assert_eq!(vm.frame().pixel(8, 0), 1);
+        assert_eq!(vm.frame().pixel(10, 0), 3);
+        assert_eq!(vm.frame().pixel(9, 1), 2);
+        assert_eq!(vm.frame().pixel(11, 1), 1);
+
+        // Clearing only clears the selected planes.
+        vm.execute(Instruction::Plane(1)).unwrap();
+        vm.execute(Instruction::Cls).unwrap();
+        assert_eq!(vm.frame().pixel(0, 0), 2);
+        assert_eq!(vm.frame().pixel(8, 0), 0);
+        assert_eq!(vm.frame().pixel(10, 0), 2);
+    }
+
+    #[test]
+    fn save_and_load_ranges_leave_i_alone() {
+        let mut vm = xo_chip();
+
+        vm.registers_mut().i = 0x300;
+        vm.registers_mut().v[2..5].copy_from_slice(&[7, 8, 9]);
+        vm.execute(Instruction::SaveRange { x: 2, y: 4 }).unwrap();
+        assert_eq!(vm.memory()[0x300..0x303], [7, 8, 9]);
+
+        // Backwards ranges go in reverse.
+        vm.execute(Instruction::SaveRange { x: 4, y: 2 }).unwrap();
+        assert_eq!(vm.memory()[0x300..0x303], [9, 8, 7]);
+
+        vm.execute(Instruction::LoadRange { x: 10, y: 12 }).unwrap();
+        assert_eq!(vm.registers().v[10..13], [9, 8, 7]);
+        assert_eq!(vm.registers().i, 0x300);
+    }
+
+    #[test]
+    fn long_load_takes_the_next_word() {
+        let mut vm = load_rom(vec![0xF0, 0x00, 0xBE, 0xEF, 0x12, 0x04], Platform::XoChip).unwrap();
+
+        vm.step().unwrap();
+
+        assert_eq!(vm.registers().i, 0xBEEF);
+        assert_eq!(vm.pc(), 0x204);
+    }
+
+    #[test]
+    fn skips_step_over_both_words_of_a_long_load() {
+        let program = vec![
+            0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01,
+            0x31, 0x01, 0xF0, 0x00, 0x12, 0x34, 0x12, 0x0E,
+        ];
+        let mut vm = load_rom(program, Platform::XoChip).unwrap();
+
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 0x206);
+
+        vm.step().unwrap();
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 0x20A);
+
+        // Not skipping runs the long load.
+        vm.step().unwrap();
+        assert_eq!(vm.pc(), 0x20E);
+        assert_eq!(vm.registers().i, 0x1234);
+    }
+
+    /// A CHIP-8 VM with the default quirks changed, to run instructions on.
+    fn with_quirks(changes: &str) -> VM {
+        let mut vm = load_rom(vec![0x12, 0x00], Platform::Chip8).unwrap();
+
+        vm.set_quirks(changes.parse().unwrap());
+        vm
+    }
+
+    #[test]
+    fn shift_quirk_picks_the_source() {
+        for (changes, shr, shl) in [("shift-vy", (0x40, 1), (0x02, 1)), ("-shift-vy", (0x01, 0), (0x04, 0))] {
+            let mut vm = with_quirks(changes);
+
+            vm.registers_mut().v[..2].copy_from_slice(&[0x02, 0x81]);
+            vm.execute(Instruction::Shr { x: 0, y: 1 }).unwrap();
+            assert_eq!((vm.registers().v[0], vm.registers().v[0xF]), shr, "{}", changes);
+
+            vm.registers_mut().v[..2].copy_from_slice(&[0x02, 0x81]);
+            vm.execute(Instruction::Shl { x: 0, y: 1 }).unwrap();
+            assert_eq!((vm.registers().v[0], vm.registers().v[0xF]), shl, "{}", changes);
+        }
+    }
+
+    #[test]
+    fn index_quirk_moves_i_after_store_and_load() {
+        for (changes, i) in [("index=x+1", 0x303), ("index=x", 0x302), ("index=unchanged", 0x300)] {
+            let mut vm = with_quirks(changes);
+
+            vm.registers_mut().i = 0x300;
+            vm.execute(Instruction::Store { x: 2 }).unwrap();
+            assert_eq!(vm.registers().i, i, "{}", changes);
+
+            vm.registers_mut().i = 0x300;
+            vm.execute(Instruction::Restore { x: 2 }).unwrap();
+            assert_eq!(vm.registers().i, i, "{}", changes);
+        }
+    }
+
+    #[test]
+    fn jump_quirk_picks_the_offset_register() {
+        for (changes, pc) in [("jump-vx", 0x244), ("-jump-vx", 0x235)] {
+            let mut vm = with_quirks(changes);
+
+            vm.registers_mut().v[0] = 0x01;
+            vm.registers_mut().v[2] = 0x10;
+            vm.execute(Instruction::JumpV0(0x234)).unwrap();
+            assert_eq!(vm.pc(), pc, "{}", changes);
+        }
+    }
+
+    #[test]
+    fn vf_reset_quirk_clears_vf_after_logic() {
+        for (changes, vf) in [("vf-reset", 0), ("-vf-reset", 7)] {
+            for inst in [Instruction::Or { x: 0, y: 1 }, Instruction::And { x: 0, y: 1 }, Instruction::Xor { x: 0, y: 1 }] {
+                let mut vm = with_quirks(changes);
+
+                vm.registers_mut().v[0xF] = 7;
+                vm.execute(inst).unwrap();
+                assert_eq!(vm.registers().v[0xF], vf, "{:?} with {}", inst, changes);
+            }
+        }
+    }
+
+    #[test]
+    fn clip_quirk_clips_or_wraps_sprites() {
+        for (changes, wraps) in [("clip", false), ("-clip", true)] {
+            let mut vm = with_quirks(changes);
+
+            draw_zero(&mut vm, 62, 30);
+
+            assert!(lit(&vm, 62, 30) && lit(&vm, 62, 31), "{}", changes);
+            assert_eq!(lit(&vm, 1, 30), wraps, "{}", changes);
+            assert_eq!(lit(&vm, 62, 0), wraps, "{}", changes);
+        }
+    }
+
+    #[test]
+    fn display_wait_quirk_draws_once_a_frame() {
+        for (changes, waits) in [("display-wait", true), ("-display-wait", false)] {
+            let mut vm = with_quirks(changes);
+
+            draw_zero(&mut vm, 0, 0);
+            draw_zero(&mut vm, 0, 0);
+
+            // A second draw in the same frame is retried, leaving the first.
+            assert_eq!(lit(&vm, 0, 0), waits, "{}", changes);
+
+            vm.run_frame().unwrap();
+            draw_zero(&mut vm, 8, 0);
+            assert!(lit(&vm, 8, 0), "{}", changes);
+        }
+    }
+}